@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::flac::FlacFileWriter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioFileFormat {
+    Wav,
+    Flac,
+}
+
+impl AudioFileFormat {
+    pub fn from_path(file_path: &str) -> Option<Self> {
+        let extension = Path::new(file_path).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "wav" => Some(AudioFileFormat::Wav),
+            "flac" => Some(AudioFileFormat::Flac),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFileFormat::Wav => "wav",
+            AudioFileFormat::Flac => "flac",
+        }
+    }
+}
+
+enum Writer {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Flac(FlacFileWriter),
+}
+
+// Writes interleaved integer samples (already scaled to `bits_per_sample`) to a WAV or FLAC file
+pub struct AudioFileWriter {
+    writer: Writer,
+}
+
+impl AudioFileWriter {
+    pub fn create(
+        file_path: &str,
+        format: AudioFileFormat,
+        sample_rate: u32,
+        channels: u32,
+        bits_per_sample: u32,
+    ) -> std::io::Result<Self> {
+        let writer = match format {
+            AudioFileFormat::Wav => {
+                let spec = hound::WavSpec {
+                    channels: channels as u16,
+                    sample_rate,
+                    bits_per_sample: bits_per_sample as u16,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                Writer::Wav(hound::WavWriter::create(file_path, spec).map_err(to_io_error)?)
+            }
+            AudioFileFormat::Flac => {
+                Writer::Flac(FlacFileWriter::create(file_path, sample_rate, channels, bits_per_sample)?)
+            }
+        };
+
+        Ok(Self { writer })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i32]) -> std::io::Result<()> {
+        match &mut self.writer {
+            Writer::Wav(writer) => {
+                for sample in samples {
+                    writer.write_sample(*sample).map_err(to_io_error)?;
+                }
+                Ok(())
+            }
+            Writer::Flac(writer) => writer.write_samples(samples),
+        }
+    }
+
+    pub fn finalize(self) -> std::io::Result<()> {
+        match self.writer {
+            Writer::Wav(writer) => writer.finalize().map_err(to_io_error),
+            Writer::Flac(writer) => writer.finalize(),
+        }
+    }
+}
+
+fn to_io_error(err: hound::Error) -> std::io::Error {
+    match err {
+        hound::Error::IoError(err) => err,
+        other => std::io::Error::other(other.to_string()),
+    }
+}
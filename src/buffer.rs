@@ -2,12 +2,15 @@
 use dasp_ring_buffer::Bounded;
 use std::{collections::HashMap, sync::{Arc, Mutex}};
 
-pub fn init_buffer_map() -> Arc<Mutex<HashMap<i32, Bounded<Vec<f32>>>>> {
-    let track_buffers: Arc<Mutex<HashMap<i32, Bounded<Vec<f32>>>>> = Arc::new(Mutex::new(HashMap::new()));
+// Samples each track has decoded, keyed by track, waiting to be mixed
+pub type BufferMap = Arc<Mutex<HashMap<i32, Bounded<Vec<f32>>>>>;
+
+pub fn init_buffer_map() -> BufferMap {
+    let track_buffers: BufferMap = Arc::new(Mutex::new(HashMap::new()));
     track_buffers
 }
 
-pub fn buffer_remaining_space(track_buffers: &Arc<Mutex<HashMap<i32, Bounded<Vec<f32>>>>>, track_key: i32) -> usize {
+pub fn buffer_remaining_space(track_buffers: &BufferMap, track_key: i32) -> usize {
     let track_buffers = track_buffers.lock().unwrap();
     let remaining_space: usize;
     match track_buffers.get(&track_key) {
@@ -0,0 +1,211 @@
+use symphonia::core::audio::Channels;
+
+// Gain of a channel split equally between two others
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+const LEFTS: Channels = Channels::FRONT_LEFT
+    .union(Channels::FRONT_LEFT_CENTRE)
+    .union(Channels::FRONT_LEFT_WIDE)
+    .union(Channels::FRONT_LEFT_HIGH)
+    .union(Channels::TOP_FRONT_LEFT);
+const RIGHTS: Channels = Channels::FRONT_RIGHT
+    .union(Channels::FRONT_RIGHT_CENTRE)
+    .union(Channels::FRONT_RIGHT_WIDE)
+    .union(Channels::FRONT_RIGHT_HIGH)
+    .union(Channels::TOP_FRONT_RIGHT);
+const SURROUND_LEFTS: Channels = Channels::SIDE_LEFT
+    .union(Channels::REAR_LEFT)
+    .union(Channels::REAR_LEFT_CENTRE)
+    .union(Channels::TOP_REAR_LEFT);
+const SURROUND_RIGHTS: Channels = Channels::SIDE_RIGHT
+    .union(Channels::REAR_RIGHT)
+    .union(Channels::REAR_RIGHT_CENTRE)
+    .union(Channels::TOP_REAR_RIGHT);
+const REAR_CENTRES: Channels = Channels::REAR_CENTRE.union(Channels::TOP_REAR_CENTRE);
+const LFES: Channels = Channels::LFE1.union(Channels::LFE2);
+
+/// The usual channels for a count of them, as FLAC and WAV files assume:
+/// mono, stereo, 3.0, quad, 5.0, 5.1, 6.1 and 7.1. Other counts take the
+/// first channels of the WAV channel mask.
+pub fn default_layout(count: u16) -> Channels {
+    let front = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+    let rear = Channels::REAR_LEFT | Channels::REAR_RIGHT;
+    let side = Channels::SIDE_LEFT | Channels::SIDE_RIGHT;
+
+    match count {
+        1 => Channels::FRONT_LEFT,
+        2 => front,
+        3 => front | Channels::FRONT_CENTRE,
+        4 => front | rear,
+        5 => front | Channels::FRONT_CENTRE | rear,
+        6 => front | Channels::FRONT_CENTRE | Channels::LFE1 | rear,
+        7 => front | Channels::FRONT_CENTRE | Channels::LFE1 | Channels::REAR_CENTRE | side,
+        8 => front | Channels::FRONT_CENTRE | Channels::LFE1 | rear | side,
+        count => Channels::from_bits_truncate(((1u64 << count.min(32)) - 1) as u32),
+    }
+}
+
+// Whether the channel is on the left, front or surround
+pub(crate) fn is_left(channel: Channels) -> bool {
+    LEFTS.union(SURROUND_LEFTS).contains(channel)
+}
+
+// Whether the channel is on the right, front or surround
+pub(crate) fn is_right(channel: Channels) -> bool {
+    RIGHTS.union(SURROUND_RIGHTS).contains(channel)
+}
+
+/// Mixes interleaved frames from one set of channels into another.
+///
+/// Channels both sets have are passed straight through. Of the rest:
+/// - a mono source plays at full level on the front left and right, or on
+///   the centre if there is one;
+/// - the centre is split between the front left and right at -3 dB;
+/// - the front left and right are averaged into a centre, for mono outputs;
+/// - surrounds move to the other surround on the same side, side or rear,
+///   or else into the front on that side at -3 dB;
+/// - the rear centre is split between the left and right surrounds at -3 dB;
+/// - the low frequency channel is dropped.
+///
+/// Channels not named above take the place of the nearest one that is, so
+/// a wide or high front left is treated as the front left.
+#[derive(Debug, Clone)]
+pub(crate) struct ChannelMatrix {
+    from: Channels,
+    to: Channels,
+    inputs: usize,
+    outputs: usize,
+    // Gain from each input channel, one row per output channel
+    gains: Vec<f32>,
+}
+
+impl ChannelMatrix {
+    pub fn new(from: Channels, to: Channels) -> Self {
+        // A lone channel is mono, whatever position it is given
+        let routing_to = if to.count() == 1 { Channels::FRONT_CENTRE } else { to };
+        let outputs: Vec<Channels> = routing_to.iter().collect();
+        let inputs = from.count();
+
+        let mut gains = vec![0.0; outputs.len() * inputs];
+        for (input, channel) in from.iter().enumerate() {
+            let routes = if inputs == 1 { route_mono(routing_to) } else { route(channel, routing_to) };
+
+            for (channel, gain) in routes {
+                let output = outputs.iter().position(|output| *output == channel).unwrap();
+                gains[output * inputs + input] += gain;
+            }
+        }
+
+        Self { from, to, inputs, outputs: outputs.len(), gains }
+    }
+
+    pub fn from(&self) -> Channels {
+        self.from
+    }
+
+    pub fn to(&self) -> Channels {
+        self.to
+    }
+
+    /// Whether frames pass through unchanged.
+    pub fn is_identity(&self) -> bool {
+        self.inputs == self.outputs
+            && self.gains.chunks_exact(self.inputs).enumerate().all(|(output, row)| {
+                row.iter().enumerate().all(|(input, gain)| *gain == if input == output { 1.0 } else { 0.0 })
+            })
+    }
+
+    /// Mixes the frames in `input` into `output`, which holds as many frames
+    /// of the channels mixed into.
+    pub fn mix_into(&self, input: &[f32], output: &mut [f32]) {
+        for (input, output) in input.chunks_exact(self.inputs).zip(output.chunks_exact_mut(self.outputs)) {
+            for (sample, gains) in output.iter_mut().zip(self.gains.chunks_exact(self.inputs)) {
+                *sample = gains.iter().zip(input).map(|(gain, sample)| gain * sample).sum();
+            }
+        }
+    }
+
+    pub fn mix(&self, input: &[f32]) -> Vec<f32> {
+        let mut output = vec![0.0; input.len() / self.inputs * self.outputs];
+        self.mix_into(input, &mut output);
+        output
+    }
+}
+
+fn route_mono(to: Channels) -> Vec<(Channels, f32)> {
+    if !to.contains(Channels::FRONT_CENTRE) && to.contains(Channels::FRONT_LEFT | Channels::FRONT_RIGHT) {
+        return vec![(Channels::FRONT_LEFT, 1.0), (Channels::FRONT_RIGHT, 1.0)];
+    }
+
+    route(Channels::FRONT_CENTRE, to)
+}
+
+// Output channels, and their gains, that one input channel is mixed into
+fn route(channel: Channels, to: Channels) -> Vec<(Channels, f32)> {
+    if to.contains(channel) {
+        return vec![(channel, 1.0)];
+    }
+
+    if LFES.contains(channel) {
+        return [Channels::LFE1, Channels::LFE2]
+            .into_iter()
+            .find(|lfe| to.contains(*lfe))
+            .map(|lfe| vec![(lfe, 1.0)])
+            .unwrap_or_default();
+    }
+
+    let routes = if LEFTS.contains(channel) {
+        route_front(Channels::FRONT_LEFT, to)
+    } else if RIGHTS.contains(channel) {
+        route_front(Channels::FRONT_RIGHT, to)
+    } else if SURROUND_LEFTS.contains(channel) {
+        route_surround([Channels::SIDE_LEFT, Channels::REAR_LEFT], Channels::FRONT_LEFT, to)
+    } else if SURROUND_RIGHTS.contains(channel) {
+        route_surround([Channels::SIDE_RIGHT, Channels::REAR_RIGHT], Channels::FRONT_RIGHT, to)
+    } else if REAR_CENTRES.contains(channel) {
+        let left = route_surround([Channels::SIDE_LEFT, Channels::REAR_LEFT], Channels::FRONT_LEFT, to);
+        let right = route_surround([Channels::SIDE_RIGHT, Channels::REAR_RIGHT], Channels::FRONT_RIGHT, to);
+        scale(left.into_iter().chain(right).collect(), MINUS_3DB)
+    } else {
+        route_centre(to)
+    };
+
+    // Layouts with none of the channels above share the input among them all
+    if routes.is_empty() {
+        let outputs = to - LFES;
+        return outputs.iter().map(|output| (output, 1.0 / outputs.count() as f32)).collect();
+    }
+
+    routes
+}
+
+fn route_front(channel: Channels, to: Channels) -> Vec<(Channels, f32)> {
+    if to.contains(channel) {
+        vec![(channel, 1.0)]
+    } else if to.contains(Channels::FRONT_CENTRE) {
+        vec![(Channels::FRONT_CENTRE, 0.5)]
+    } else {
+        Vec::new()
+    }
+}
+
+fn route_centre(to: Channels) -> Vec<(Channels, f32)> {
+    if to.contains(Channels::FRONT_CENTRE) {
+        vec![(Channels::FRONT_CENTRE, 1.0)]
+    } else if to.contains(Channels::FRONT_LEFT | Channels::FRONT_RIGHT) {
+        vec![(Channels::FRONT_LEFT, MINUS_3DB), (Channels::FRONT_RIGHT, MINUS_3DB)]
+    } else {
+        Vec::new()
+    }
+}
+
+fn route_surround(surrounds: [Channels; 2], front: Channels, to: Channels) -> Vec<(Channels, f32)> {
+    match surrounds.into_iter().find(|surround| to.contains(*surround)) {
+        Some(surround) => vec![(surround, 1.0)],
+        None => scale(route_front(front, to), MINUS_3DB),
+    }
+}
+
+fn scale(routes: Vec<(Channels, f32)>, gain: f32) -> Vec<(Channels, f32)> {
+    routes.into_iter().map(|(channel, route_gain)| (channel, route_gain * gain)).collect()
+}
@@ -1 +1,20 @@
-pub const SAMPLE_RATE: usize = 44100;
\ No newline at end of file
+pub const PLAY_SETTINGS_FILE_NAME: &str = "play_settings.json";
+pub const PLAY_SETTINGS_MIME_TYPE: &str = "application/json";
+
+// Version written to play_settings.json by `ProtWriter`
+pub const ENCODER_VERSION: f64 = 1.0;
+
+// Crossfade, in seconds, used when play_settings.json does not set one
+pub const DEFAULT_SECTION_CROSSFADE: f64 = 0.05;
+
+// Loudness, in LU, a take may be from the average of its group before it is
+// turned up or down, when play_settings.json does not set it
+pub const DEFAULT_LOUDNESS_WINDOW: f32 = 1.0;
+
+// Most gain, in dB, loudness normalization gives or takes from a take
+pub const MAX_LOUDNESS_COMPENSATION: f32 = 12.0;
+
+// Levels of the reverb and of the mix without it, when play_settings.json
+// does not set them
+pub const DEFAULT_REVERB_WET: f32 = 0.3;
+pub const DEFAULT_REVERB_DRY: f32 = 1.0;
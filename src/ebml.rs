@@ -0,0 +1,112 @@
+// Minimal EBML element writing, enough to produce Matroska files.
+
+pub const EBML_HEADER: u32 = 0x1A45DFA3;
+pub const EBML_VERSION: u32 = 0x4286;
+pub const EBML_READ_VERSION: u32 = 0x42F7;
+pub const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+pub const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+pub const DOC_TYPE: u32 = 0x4282;
+pub const DOC_TYPE_VERSION: u32 = 0x4287;
+pub const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+pub const SEGMENT: u32 = 0x18538067;
+
+pub const INFO: u32 = 0x1549A966;
+pub const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+pub const DURATION: u32 = 0x4489;
+pub const MUXING_APP: u32 = 0x4D80;
+pub const WRITING_APP: u32 = 0x5741;
+
+pub const TRACKS: u32 = 0x1654AE6B;
+pub const TRACK_ENTRY: u32 = 0xAE;
+pub const TRACK_NUMBER: u32 = 0xD7;
+pub const TRACK_UID: u32 = 0x73C5;
+pub const TRACK_TYPE: u32 = 0x83;
+pub const FLAG_LACING: u32 = 0x9C;
+pub const DEFAULT_DURATION: u32 = 0x23E383;
+pub const NAME: u32 = 0x536E;
+pub const CODEC_ID: u32 = 0x86;
+pub const CODEC_PRIVATE: u32 = 0x63A2;
+pub const AUDIO: u32 = 0xE1;
+pub const SAMPLING_FREQUENCY: u32 = 0xB5;
+pub const CHANNELS: u32 = 0x9F;
+pub const BIT_DEPTH: u32 = 0x6264;
+
+pub const ATTACHMENTS: u32 = 0x1941A469;
+pub const ATTACHED_FILE: u32 = 0x61A7;
+pub const FILE_NAME: u32 = 0x466E;
+pub const FILE_MIME_TYPE: u32 = 0x4660;
+pub const FILE_DATA: u32 = 0x465C;
+pub const FILE_UID: u32 = 0x46AE;
+
+pub const TAGS: u32 = 0x1254C367;
+pub const TAG: u32 = 0x7373;
+pub const TARGETS: u32 = 0x63C0;
+pub const TARGET_TYPE_VALUE: u32 = 0x68CA;
+pub const TAG_TRACK_UID: u32 = 0x63C5;
+pub const SIMPLE_TAG: u32 = 0x67C8;
+pub const TAG_NAME: u32 = 0x45A3;
+pub const TAG_STRING: u32 = 0x4487;
+
+pub const CLUSTER: u32 = 0x1F43B675;
+pub const TIMESTAMP: u32 = 0xE7;
+pub const SIMPLE_BLOCK: u32 = 0xA3;
+
+// Width of size fields that are patched in once the element has been written
+pub const PLACEHOLDER_SIZE_LENGTH: usize = 8;
+
+pub fn write_id(buffer: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let first = bytes.iter().position(|byte| *byte != 0).unwrap_or(3);
+    buffer.extend_from_slice(&bytes[first..]);
+}
+
+pub fn write_size(buffer: &mut Vec<u8>, size: u64) {
+    // Sizes with every value bit set are reserved for "unknown"
+    let mut length = 1;
+    while length < 8 && size >= (1u64 << (7 * length)) - 1 {
+        length += 1;
+    }
+
+    write_size_with_length(buffer, size, length);
+}
+
+pub fn write_size_with_length(buffer: &mut Vec<u8>, size: u64, length: usize) {
+    let marked = size | (1u64 << (7 * length));
+    buffer.extend_from_slice(&marked.to_be_bytes()[8 - length..]);
+}
+
+pub fn element(buffer: &mut Vec<u8>, id: u32, data: &[u8]) {
+    write_id(buffer, id);
+    write_size(buffer, data.len() as u64);
+    buffer.extend_from_slice(data);
+}
+
+pub fn uint_element(buffer: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let first = bytes.iter().position(|byte| *byte != 0).unwrap_or(7);
+    element(buffer, id, &bytes[first..]);
+}
+
+pub fn float_element(buffer: &mut Vec<u8>, id: u32, value: f64) {
+    element(buffer, id, &value.to_be_bytes());
+}
+
+pub fn string_element(buffer: &mut Vec<u8>, id: u32, value: &str) {
+    element(buffer, id, value.as_bytes());
+}
+
+pub fn master_element(buffer: &mut Vec<u8>, id: u32, children: impl FnOnce(&mut Vec<u8>)) {
+    let mut data = Vec::new();
+    children(&mut data);
+    element(buffer, id, &data);
+}
+
+pub fn simple_block(buffer: &mut Vec<u8>, track_number: u64, relative_timestamp: i16, data: &[u8]) {
+    let mut block = Vec::with_capacity(data.len() + 4);
+    write_size(&mut block, track_number);
+    block.extend_from_slice(&relative_timestamp.to_be_bytes());
+    block.push(0x80); // Keyframe, no lacing
+    block.extend_from_slice(data);
+    element(buffer, SIMPLE_BLOCK, &block);
+}
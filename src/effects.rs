@@ -1,73 +1,416 @@
-use std::io::BufReader;
-use rustfft::{FftPlanner, num_complex::Complex};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Cursor;
+use std::sync::Arc;
 
-use rodio::Decoder;
+use rodio::{Decoder, Source};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
 
-pub fn apply_convolution_reverb(input_signal: Vec<f32>) -> Vec<f32> {
-    // Load your impulse response (IR) file
-    // TODO: This should be done once, not every time the effect is applied
-    let ir_signal = load_impulse_response("/Users/innocentsmith/Dev/tauri/proteus-author/dev-assets/Impulse Responses/IR.wav");
+use crate::limiter::Limiter;
+use crate::resample::{ResampleQuality, Resampler};
 
-    // Perform the convolution here
-    // This is a placeholder for actual convolution logic
-    let convolved_signal = fft_convolution(&input_signal, &ir_signal);
+// Frames in each partition of the impulse response, and in each block of
+// input held back before it is convolved. Longer partitions take fewer,
+// larger FFTs.
+const PARTITION_FRAMES: usize = 512;
 
-    convolved_signal
+#[derive(Debug)]
+pub enum ImpulseResponseError {
+    Io(std::io::Error),
+    Decode(String),
+    Empty,
+}
+
+impl fmt::Display for ImpulseResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImpulseResponseError::Io(err) => write!(f, "could not read impulse response: {}", err),
+            ImpulseResponseError::Decode(err) => write!(f, "could not decode impulse response: {}", err),
+            ImpulseResponseError::Empty => write!(f, "impulse response is silent"),
+        }
+    }
+}
+
+impl std::error::Error for ImpulseResponseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImpulseResponseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ImpulseResponseError {
+    fn from(err: std::io::Error) -> Self {
+        ImpulseResponseError::Io(err)
+    }
+}
+
+/// Recording of a space answering a single click, which a reverb gives to
+/// everything played through it.
+#[derive(Clone)]
+pub struct ImpulseResponse {
+    sample_rate: u32,
+    // One vector per channel
+    samples: Vec<Vec<f32>>,
+}
 
-    // input_signal
+impl fmt::Debug for ImpulseResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImpulseResponse")
+            .field("sample_rate", &self.sample_rate)
+            .field("channels", &self.channels())
+            .field("frames", &self.frames())
+            .finish()
+    }
 }
 
-pub fn load_impulse_response(file_path: &str) -> Vec<f32> {
-    let file = BufReader::new(std::fs::File::open(file_path).unwrap());
-    let mut source = Decoder::new(file).unwrap();
+impl ImpulseResponse {
+    /// Loads an impulse response from a WAV or FLAC file.
+    pub fn load(file_path: &str) -> Result<Self, ImpulseResponseError> {
+        Self::from_bytes(std::fs::read(file_path)?)
+    }
+
+    /// Reads an impulse response from the contents of a WAV or FLAC file,
+    /// such as an attachment of a .prot file.
+    ///
+    /// The response is scaled to unit energy on its loudest channel, so how
+    /// loud it was recorded does not change how loud the reverb is.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, ImpulseResponseError> {
+        let decoder = Decoder::new(Cursor::new(data)).map_err(|err| ImpulseResponseError::Decode(err.to_string()))?;
+        let sample_rate = decoder.sample_rate();
+        let channels = decoder.channels().max(1) as usize;
+
+        let mut samples = vec![Vec::new(); channels];
+        for (index, sample) in decoder.convert_samples::<f32>().enumerate() {
+            samples[index % channels].push(sample);
+        }
+
+        let energy = samples
+            .iter()
+            .map(|channel| channel.iter().map(|sample| sample * sample).sum::<f32>())
+            .fold(0.0, f32::max);
+        if energy == 0.0 {
+            return Err(ImpulseResponseError::Empty);
+        }
+
+        let scale = energy.sqrt().recip();
+        for sample in samples.iter_mut().flatten() {
+            *sample *= scale;
+        }
+
+        Ok(Self { sample_rate, samples })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples[0].len()
+    }
+
+    // The response at another sample rate, one vector per channel
+    fn resampled(&self, sample_rate: u32) -> Vec<Vec<f32>> {
+        if sample_rate == self.sample_rate {
+            return self.samples.clone();
+        }
+
+        let channels = self.channels();
+        let interleaved: Vec<f32> =
+            (0..self.frames()).flat_map(|frame| self.samples.iter().map(move |channel| channel[frame])).collect();
 
-    // println!("Sample rate: {:?}", source.sample_rate());
-    // Load the WAV file and return its samples as a Vec<f32>
-    // This function needs to be implemented
+        let mut resampler = Resampler::new(self.sample_rate, sample_rate, channels, ResampleQuality::High);
+        resampler.push(&interleaved);
+        resampler.finish();
 
-    let mut samples: Vec<f32> = Vec::new();
+        let mut samples = vec![Vec::new(); channels];
+        for (index, sample) in resampler.take().into_iter().enumerate() {
+            samples[index % channels].push(sample);
+        }
 
-    while let Some(sample) = source.next() {
-        println!("{:?}", sample);
-        samples.push(sample as f32);
+        samples
     }
+}
 
-    samples
+/// Convolution reverb over the whole mix.
+#[derive(Debug, Clone)]
+pub struct Reverb {
+    pub impulse_response: Arc<ImpulseResponse>,
+    /// Level of the reverberated mix, from 0 to 1.
+    pub wet: f32,
+    /// Level of the mix as it was, from 0 to 1.
+    pub dry: f32,
 }
 
-pub fn convolution(_input_signal: &[f32], _ir_signal: &[f32]) -> Vec<f32> {
-    // Implement the convolution algorithm
-    // This could be direct convolution or FFT-based convolution
+// Convolves interleaved frames with an impulse response a partition at a
+// time: each block of input is transformed once, and its spectrum is kept
+// to be multiplied with every partition of the response as later blocks
+// come in (uniformly partitioned overlap-save).
+//
+// Channels of the input take the response's channels in turn, so a mono
+// response is heard on every channel. Input is held back a block at a
+// time, but each frame comes out in line with the frame that went in.
+pub(crate) struct Convolver {
+    channels: usize,
+    wet: f32,
+    dry: f32,
+    fft: Arc<dyn Fft<f32>>,
+    inverse_fft: Arc<dyn Fft<f32>>,
+    // Spectrum of every partition of the response, per channel
+    partitions: Vec<Vec<Vec<Complex<f32>>>>,
+    // Spectra of the latest blocks of input, newest first, per channel
+    history: Vec<VecDeque<Vec<Complex<f32>>>>,
+    // The last block of input and the one coming in, per channel
+    window: Vec<Vec<f32>>,
+    // Frames of the block coming in
+    filled: usize,
+    frames_in: u64,
+    // Frames of real input, as opposed to the silence that flushes the end
+    frames_expected: Option<u64>,
+    output: VecDeque<f32>,
+    frames_out: u64,
+}
 
-    Vec::new()
+impl fmt::Debug for Convolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Convolver")
+            .field("channels", &self.channels)
+            .field("wet", &self.wet)
+            .field("dry", &self.dry)
+            .field("partitions", &self.partitions[0].len())
+            .field("frames_in", &self.frames_in)
+            .field("frames_out", &self.frames_out)
+            .finish_non_exhaustive()
+    }
 }
 
-fn fft_convolution(input_signal: &[f32], ir_signal: &[f32]) -> Vec<f32> {
-    let mut planner = FftPlanner::new();
-    let fft = planner.plan_fft_forward(input_signal.len() + ir_signal.len() - 1);
-    let ifft = planner.plan_fft_inverse(input_signal.len() + ir_signal.len() - 1);
+impl Convolver {
+    pub fn new(reverb: &Reverb, channels: usize, sample_rate: u32) -> Self {
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(PARTITION_FRAMES * 2);
+        let inverse_fft = planner.plan_fft_inverse(PARTITION_FRAMES * 2);
+
+        let response = reverb.impulse_response.resampled(sample_rate);
+        let partitions = (0..channels)
+            .map(|channel| {
+                response[channel % response.len()]
+                    .chunks(PARTITION_FRAMES)
+                    .map(|chunk| {
+                        let mut spectrum = vec![Complex::new(0.0, 0.0); PARTITION_FRAMES * 2];
+                        for (bin, sample) in spectrum.iter_mut().zip(chunk) {
+                            *bin = Complex::new(*sample, 0.0);
+                        }
+                        fft.process(&mut spectrum);
+                        spectrum
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            channels,
+            wet: reverb.wet,
+            dry: reverb.dry,
+            fft,
+            inverse_fft,
+            partitions,
+            history: vec![VecDeque::new(); channels],
+            window: vec![vec![0.0; PARTITION_FRAMES * 2]; channels],
+            filled: 0,
+            frames_in: 0,
+            frames_expected: None,
+            output: VecDeque::new(),
+            frames_out: 0,
+        }
+    }
+
+    /// Adds the reverb to interleaved `samples`. Frames are held back until
+    /// a partition of them has come in.
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (window, sample) in self.window.iter_mut().zip(frame) {
+                window[PARTITION_FRAMES + self.filled] = *sample;
+            }
+            self.filled += 1;
+            self.frames_in += 1;
+
+            if self.filled == PARTITION_FRAMES {
+                self.process();
+            }
+        }
+    }
 
-    let mut input_fft = input_signal.iter().map(|&f| Complex::new(f, 0.0)).collect::<Vec<_>>();
-    let mut ir_fft = ir_signal.iter().map(|&f| Complex::new(f, 0.0)).collect::<Vec<_>>();
+    /// Gives out the frames held back, once there is no more input. The
+    /// reverb's tail past the end of the input is cut off.
+    pub fn finish(&mut self) {
+        if self.frames_expected.is_some() {
+            return;
+        }
+        let frames = self.frames_in;
+        self.frames_expected = Some(frames);
 
-    // Zero-padding to the same length
-    input_fft.resize(input_fft.len() + ir_signal.len() - 1, Complex::new(0.0, 0.0));
-    ir_fft.resize(ir_fft.len() + input_signal.len() - 1, Complex::new(0.0, 0.0));
+        let silence = vec![0.0; self.channels];
+        while self.frames_out < frames {
+            self.push(&silence);
+        }
+    }
 
-    // Perform FFT
-    fft.process(&mut input_fft);
-    fft.process(&mut ir_fft);
+    /// Whether the input has ended and every sample has been taken.
+    pub fn is_finished(&self) -> bool {
+        self.frames_expected.is_some_and(|frames| self.frames_out >= frames) && self.output.is_empty()
+    }
+
+    /// Moves samples with the reverb into `buffer`. Returns how many were
+    /// moved.
+    pub fn pop(&mut self, buffer: &mut [f32]) -> usize {
+        let count = buffer.len().min(self.output.len());
+        for (out, sample) in buffer.iter_mut().zip(self.output.drain(..count)) {
+            *out = sample;
+        }
+
+        count
+    }
+
+    /// Takes every sample with the reverb.
+    pub fn take(&mut self) -> Vec<f32> {
+        self.output.drain(..).collect()
+    }
 
-    // Multiply in frequency domain
-    for (input, ir) in input_fft.iter_mut().zip(ir_fft.iter()) {
-        *input = *input * *ir;
+    /// Samples with the reverb ready to be taken.
+    pub fn len(&self) -> usize {
+        self.output.len()
     }
 
-    // Perform inverse FFT
-    ifft.process(&mut input_fft);
+    // Convolves the block that has just come in
+    fn process(&mut self) {
+        let length = (PARTITION_FRAMES * 2) as f32;
+        let mut wet = vec![[0.0; PARTITION_FRAMES]; self.channels];
 
-    // Normalize and extract real part
-    input_fft.clone().into_iter().map(|c| c.re / input_fft.len() as f32).collect()
+        for (channel, wet) in wet.iter_mut().enumerate() {
+            let partitions = &self.partitions[channel];
+            let history = &mut self.history[channel];
+
+            // The oldest spectrum is no longer needed, and its room is reused
+            let mut spectrum = match history.len() >= partitions.len() {
+                true => history.pop_back().unwrap(),
+                false => vec![Complex::new(0.0, 0.0); PARTITION_FRAMES * 2],
+            };
+            for (bin, sample) in spectrum.iter_mut().zip(&self.window[channel]) {
+                *bin = Complex::new(*sample, 0.0);
+            }
+            self.fft.process(&mut spectrum);
+            history.push_front(spectrum);
+
+            let mut sum = vec![Complex::new(0.0, 0.0); PARTITION_FRAMES * 2];
+            for (input, partition) in history.iter().zip(partitions) {
+                for ((bin, input), response) in sum.iter_mut().zip(input).zip(partition) {
+                    *bin += input * response;
+                }
+            }
+            self.inverse_fft.process(&mut sum);
+
+            // The first half wraps around, the second is the block's output
+            for (sample, bin) in wet.iter_mut().zip(&sum[PARTITION_FRAMES..]) {
+                *sample = bin.re / length;
+            }
+        }
+
+        for frame in 0..PARTITION_FRAMES {
+            if self.frames_expected.is_some_and(|frames| self.frames_out >= frames) {
+                break;
+            }
+
+            for (window, wet) in self.window.iter().zip(&wet) {
+                let dry = window[PARTITION_FRAMES + frame];
+                self.output.push_back(dry * self.dry + wet[frame] * self.wet);
+            }
+            self.frames_out += 1;
+        }
+
+        for window in self.window.iter_mut() {
+            window.copy_within(PARTITION_FRAMES.., 0);
+        }
+        self.filled = 0;
+    }
+}
+
+// What the mix goes through once it is mixed and remixed to the output's
+// channels: the reverb, then the limiter. Samples are interleaved, and come
+// out of the last of them.
+#[derive(Debug)]
+pub(crate) struct MasterBus {
+    reverb: Option<Convolver>,
+    limiter: Option<Limiter>,
+}
+
+impl MasterBus {
+    /// `None` when there is nothing on the bus.
+    pub fn new(reverb: Option<Convolver>, limiter: Option<Limiter>) -> Option<Self> {
+        if reverb.is_none() && limiter.is_none() {
+            return None;
+        }
+
+        Some(Self { reverb, limiter })
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        match (self.reverb.as_mut(), self.limiter.as_mut()) {
+            (Some(reverb), Some(limiter)) => {
+                reverb.push(samples);
+                limiter.push(&reverb.take());
+            }
+            (Some(reverb), None) => reverb.push(samples),
+            (None, Some(limiter)) => limiter.push(samples),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(reverb) = self.reverb.as_mut() {
+            reverb.finish();
+            if let Some(limiter) = self.limiter.as_mut() {
+                limiter.push(&reverb.take());
+            }
+        }
+
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.finish();
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.reverb.as_ref().is_none_or(|reverb| reverb.is_finished())
+            && self.limiter.as_ref().is_none_or(|limiter| limiter.is_finished())
+    }
+
+    pub fn pop(&mut self, buffer: &mut [f32]) -> usize {
+        match (self.reverb.as_mut(), self.limiter.as_mut()) {
+            (_, Some(limiter)) => limiter.pop(buffer),
+            (Some(reverb), None) => reverb.pop(buffer),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<f32> {
+        match (self.reverb.as_mut(), self.limiter.as_mut()) {
+            (_, Some(limiter)) => limiter.take(),
+            (Some(reverb), None) => reverb.take(),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match (self.reverb.as_ref(), self.limiter.as_ref()) {
+            (_, Some(limiter)) => limiter.len(),
+            (Some(reverb), None) => reverb.len(),
+            (None, None) => unreachable!(),
+        }
+    }
 }
 
 pub fn simple_reverb(samples: Vec<f32>, delay_samples: usize, decay: f32) -> Vec<f32> {
@@ -79,4 +422,94 @@ pub fn simple_reverb(samples: Vec<f32>, delay_samples: usize, decay: f32) -> Vec
         processed.push(current_sample);
     }
     processed
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    // Values in -1 to 1 from a fixed seed
+    fn noise(count: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state as i32) as f32 / i32::MAX as f32
+            })
+            .collect()
+    }
+
+    // A decaying response, spread over a few partitions and a part of one
+    fn reverb(channels: usize, wet: f32, dry: f32) -> Reverb {
+        let frames = PARTITION_FRAMES * 3 + 100;
+        let samples = (0..channels)
+            .map(|channel| {
+                noise(frames, channel as u32 + 1)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(frame, sample)| sample * (-(frame as f32) / 400.0).exp())
+                    .collect()
+            })
+            .collect();
+
+        Reverb {
+            impulse_response: Arc::new(ImpulseResponse { sample_rate: SAMPLE_RATE, samples }),
+            wet,
+            dry,
+        }
+    }
+
+    // Convolves interleaved `samples` one frame at a time, cut to their length
+    fn convolve_directly(samples: &[f32], channels: usize, reverb: &Reverb) -> Vec<f32> {
+        let response = &reverb.impulse_response.samples;
+        let frames = samples.len() / channels;
+
+        let mut output = Vec::with_capacity(samples.len());
+        for frame in 0..frames {
+            for channel in 0..channels {
+                let response = &response[channel % response.len()];
+                let wet: f32 = (0..=frame.min(response.len() - 1))
+                    .map(|delay| response[delay] * samples[(frame - delay) * channels + channel])
+                    .sum();
+                output.push(samples[frame * channels + channel] * reverb.dry + wet * reverb.wet);
+            }
+        }
+
+        output
+    }
+
+    #[test]
+    fn convolver_matches_direct_convolution() {
+        for (response_channels, channels) in [(1, 1), (1, 2), (2, 2), (2, 3)] {
+            let reverb = reverb(response_channels, 0.4, 0.8);
+            // Not a whole number of partitions
+            let samples = noise((PARTITION_FRAMES * 5 + 77) * channels, 7);
+
+            let mut convolver = Convolver::new(&reverb, channels, SAMPLE_RATE);
+            let mut output = Vec::new();
+            for chunk in samples.chunks(channels * 300) {
+                convolver.push(chunk);
+                output.extend(convolver.take());
+            }
+            convolver.finish();
+            output.extend(convolver.take());
+            assert!(convolver.is_finished());
+
+            let expected = convolve_directly(&samples, channels, &reverb);
+            assert_eq!(output.len(), expected.len());
+            for (index, (sample, expected)) in output.iter().zip(&expected).enumerate() {
+                assert!(
+                    (sample - expected).abs() < 1e-4,
+                    "sample {} is {}, expected {} ({} response channels, {} channels)",
+                    index,
+                    sample,
+                    expected,
+                    response_channels,
+                    channels
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+// A small FLAC encoder. Each channel is coded independently with the best
+// fixed predictor (orders 0-4) and a single rice partition, falling back to
+// verbatim or constant subframes when those are smaller.
+
+pub const FLAC_BLOCK_SIZE: usize = 4096;
+pub const FLAC_MAX_BLOCK_SIZE: usize = 65535;
+
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_RICE_PARAMETER: u32 = 14;
+
+#[derive(Debug, Clone)]
+pub struct FlacEncoder {
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+    block_size: usize,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+}
+
+impl FlacEncoder {
+    pub fn new(sample_rate: u32, channels: u32, bits_per_sample: u32) -> Self {
+        assert!((1..=8).contains(&channels), "FLAC supports 1 to 8 channels");
+        assert!(
+            sample_size_code(bits_per_sample).is_some(),
+            "FLAC encoder does not support {} bits per sample",
+            bits_per_sample
+        );
+
+        Self {
+            sample_rate,
+            channels,
+            bits_per_sample,
+            block_size: FLAC_BLOCK_SIZE,
+            frame_number: 0,
+            total_samples: 0,
+            min_frame_size: 0,
+            max_frame_size: 0,
+        }
+    }
+
+    // Frames in every block but the last, FLAC_BLOCK_SIZE unless set
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        assert!((16..=FLAC_MAX_BLOCK_SIZE).contains(&block_size), "FLAC blocks hold 16 to 65535 frames");
+        self.block_size = block_size;
+        self
+    }
+
+    // Encodes interleaved samples (at most one block of frames) into one FLAC frame
+    pub fn encode_frame(&mut self, samples: &[i32]) -> Vec<u8> {
+        let channels = self.channels as usize;
+        let block_size = samples.len() / channels;
+        assert!(block_size > 0 && block_size <= self.block_size);
+
+        let mut writer = BitWriter::new();
+
+        // ===== Frame header ===== //
+        writer.write(0x3FFE, 14); // Sync code
+        writer.write(0, 1); // Reserved
+        writer.write(0, 1); // Fixed block size stream
+        writer.write(0b0111, 4); // Block size is stored as a 16 bit value after the frame number
+        writer.write(0b0000, 4); // Sample rate is taken from STREAMINFO
+        writer.write(self.channels as u64 - 1, 4); // Independent channels
+        writer.write(sample_size_code(self.bits_per_sample).unwrap(), 3);
+        writer.write(0, 1); // Reserved
+        write_utf8_number(&mut writer, self.frame_number);
+        writer.write(block_size as u64 - 1, 16);
+        let crc = crc8(writer.bytes());
+        writer.write(crc as u64, 8);
+
+        // ===== Subframes ===== //
+        let mut channel_samples: Vec<i64> = Vec::with_capacity(block_size);
+        for channel in 0..channels {
+            channel_samples.clear();
+            channel_samples.extend(samples.iter().skip(channel).step_by(channels).map(|s| *s as i64));
+            self.write_subframe(&mut writer, &channel_samples);
+        }
+
+        // ===== Frame footer ===== //
+        writer.align();
+        let crc = crc16(writer.bytes());
+        writer.write(crc as u64, 16);
+
+        let frame = writer.into_bytes();
+
+        let frame_size = frame.len() as u32;
+        if self.min_frame_size == 0 || frame_size < self.min_frame_size {
+            self.min_frame_size = frame_size;
+        }
+        if frame_size > self.max_frame_size {
+            self.max_frame_size = frame_size;
+        }
+
+        self.frame_number += 1;
+        self.total_samples += block_size as u64;
+
+        frame
+    }
+
+    fn write_subframe(&self, writer: &mut BitWriter, samples: &[i64]) {
+        let bps = self.bits_per_sample;
+
+        if samples.iter().all(|sample| *sample == samples[0]) {
+            writer.write(0, 1);
+            writer.write(0b000000, 6); // Constant
+            writer.write(0, 1);
+            writer.write_signed(samples[0], bps);
+            return;
+        }
+
+        let verbatim_bits = samples.len() as u64 * bps as u64;
+
+        // Find the fixed predictor with the smallest residual
+        let mut best: Option<(usize, u32, u64, Vec<i64>)> = None;
+        for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+            let residual = fixed_residual(samples, order);
+            let (parameter, residual_bits) = best_rice_parameter(&residual);
+            let bits = order as u64 * bps as u64 + 2 + 4 + 4 + residual_bits;
+
+            let is_better = match &best {
+                Some((_, _, best_bits, _)) => bits < *best_bits,
+                None => true,
+            };
+
+            if is_better {
+                best = Some((order, parameter, bits, residual));
+            }
+        }
+
+        match best {
+            Some((order, parameter, bits, residual)) if bits < verbatim_bits => {
+                writer.write(0, 1);
+                writer.write(0b001000 | order as u64, 6); // Fixed
+                writer.write(0, 1);
+                for sample in &samples[..order] {
+                    writer.write_signed(*sample, bps);
+                }
+
+                writer.write(0b00, 2); // Rice coding with 4 bit parameters
+                writer.write(0, 4); // Partition order 0
+                writer.write(parameter as u64, 4);
+                for value in residual {
+                    writer.write_rice(value, parameter);
+                }
+            }
+            _ => {
+                writer.write(0, 1);
+                writer.write(0b000001, 6); // Verbatim
+                writer.write(0, 1);
+                for sample in samples {
+                    writer.write_signed(*sample, bps);
+                }
+            }
+        }
+    }
+
+    pub fn stream_info(&self) -> [u8; 34] {
+        let mut writer = BitWriter::new();
+        writer.write(self.block_size as u64, 16);
+        writer.write(self.block_size as u64, 16);
+        writer.write(self.min_frame_size as u64, 24);
+        writer.write(self.max_frame_size as u64, 24);
+        writer.write(self.sample_rate as u64, 20);
+        writer.write(self.channels as u64 - 1, 3);
+        writer.write(self.bits_per_sample as u64 - 1, 5);
+        writer.write(self.total_samples, 36);
+        // MD5 signature is left unset
+        writer.write(0, 64);
+        writer.write(0, 64);
+
+        let mut stream_info = [0u8; 34];
+        stream_info.copy_from_slice(writer.bytes());
+        stream_info
+    }
+
+    // The "fLaC" marker followed by a single STREAMINFO metadata block
+    pub fn header(&self, total_samples: u64) -> Vec<u8> {
+        let mut stream_info = self.stream_info();
+
+        // Total samples occupy the low 4 bits of byte 13 and bytes 14 to 17
+        stream_info[13] = (stream_info[13] & 0xF0) | ((total_samples >> 32) & 0x0F) as u8;
+        stream_info[14..18].copy_from_slice(&(total_samples as u32).to_be_bytes());
+
+        let mut header = Vec::with_capacity(42);
+        header.extend_from_slice(b"fLaC");
+        header.push(0x80); // Last metadata block, type STREAMINFO
+        header.extend_from_slice(&(stream_info.len() as u32).to_be_bytes()[1..]);
+        header.extend_from_slice(&stream_info);
+        header
+    }
+}
+
+// Writes a .flac file, filling in STREAMINFO once every sample is known
+pub struct FlacFileWriter {
+    file: BufWriter<File>,
+    encoder: FlacEncoder,
+    pending: Vec<i32>,
+}
+
+impl FlacFileWriter {
+    pub fn create(file_path: &str, sample_rate: u32, channels: u32, bits_per_sample: u32) -> std::io::Result<Self> {
+        if !(1..=8).contains(&channels) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("FLAC supports 1 to 8 channels, not {}", channels),
+            ));
+        }
+
+        let encoder = FlacEncoder::new(sample_rate, channels, bits_per_sample);
+        let mut file = BufWriter::new(File::create(file_path)?);
+        file.write_all(&encoder.header(0))?;
+
+        Ok(Self {
+            file,
+            encoder,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i32]) -> std::io::Result<()> {
+        self.pending.extend_from_slice(samples);
+
+        let block_length = FLAC_BLOCK_SIZE * self.encoder.channels as usize;
+        while self.pending.len() >= block_length {
+            let frame = self.encoder.encode_frame(&self.pending[..block_length]);
+            self.file.write_all(&frame)?;
+            self.pending.drain(..block_length);
+        }
+
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> std::io::Result<()> {
+        let channels = self.encoder.channels as usize;
+        let remaining = self.pending.len() - self.pending.len() % channels;
+        if remaining > 0 {
+            let frame = self.encoder.encode_frame(&self.pending[..remaining]);
+            self.file.write_all(&frame)?;
+        }
+
+        // STREAMINFO starts after the "fLaC" marker and the metadata block header
+        self.file.seek(SeekFrom::Start(8))?;
+        self.file.write_all(&self.encoder.stream_info())?;
+        self.file.flush()
+    }
+}
+
+fn sample_size_code(bits_per_sample: u32) -> Option<u64> {
+    match bits_per_sample {
+        8 => Some(0b001),
+        12 => Some(0b010),
+        16 => Some(0b100),
+        20 => Some(0b101),
+        24 => Some(0b110),
+        _ => None,
+    }
+}
+
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let prediction = match order {
+                0 => 0,
+                1 => samples[i - 1],
+                2 => 2 * samples[i - 1] - samples[i - 2],
+                3 => 3 * samples[i - 1] - 3 * samples[i - 2] + samples[i - 3],
+                _ => 4 * samples[i - 1] - 6 * samples[i - 2] + 4 * samples[i - 3] - samples[i - 4],
+            };
+            samples[i] - prediction
+        })
+        .collect()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn best_rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let folded: Vec<u64> = residual.iter().map(|value| zigzag(*value)).collect();
+
+    let mut best = (0, u64::MAX);
+    for parameter in 0..=MAX_RICE_PARAMETER {
+        let bits: u64 = folded
+            .iter()
+            .map(|value| (value >> parameter) + 1 + parameter as u64)
+            .sum();
+
+        if bits < best.1 {
+            best = (parameter, bits);
+        }
+    }
+
+    best
+}
+
+fn write_utf8_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+
+    // Number of continuation bytes needed to hold the value
+    let continuation_bytes = match value {
+        0..=0x7FF => 1,
+        0x800..=0xFFFF => 2,
+        0x1_0000..=0x1F_FFFF => 3,
+        0x20_0000..=0x3FF_FFFF => 4,
+        0x400_0000..=0x7FFF_FFFF => 5,
+        _ => 6,
+    };
+
+    let leading_ones = continuation_bytes + 1;
+    let first_byte_bits = 7 - leading_ones;
+    let prefix = (0xFFu64 << (8 - leading_ones)) & 0xFF;
+    writer.write(prefix | (value >> (6 * continuation_bytes)) & ((1 << first_byte_bits) - 1), 8);
+
+    for index in (0..continuation_bytes).rev() {
+        writer.write(0x80 | ((value >> (6 * index)) & 0x3F), 8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            accumulator: 0,
+            bit_count: 0,
+        }
+    }
+
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+
+        let mask = if bits == 64 { u64::MAX } else { (1u64 << bits) - 1 };
+        self.accumulator = (self.accumulator << bits) | (value & mask);
+        self.bit_count += bits;
+
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            self.bytes.push((self.accumulator >> self.bit_count) as u8);
+        }
+        self.accumulator &= (1u64 << self.bit_count) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_rice(&mut self, value: i64, parameter: u32) {
+        let folded = zigzag(value);
+        let mut quotient = folded >> parameter;
+
+        while quotient >= 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+        self.write(1, quotient as u32 + 1);
+
+        if parameter > 0 {
+            self.write(folded, parameter);
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.write(0, 8 - self.bit_count);
+        }
+    }
+
+    // Only complete bytes, so callers must align first when that matters
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
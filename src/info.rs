@@ -1,7 +1,7 @@
 use std::{path::Path, fs::File, collections::HashMap};
 
 use symphonia::core::{
-    audio::Layout, codecs::CodecParameters, formats::{FormatOptions, Track}, io::{
+    audio::Channels, codecs::{CodecParameters, DecoderOptions}, formats::{FormatOptions, Track}, io::{
         MediaSource, MediaSourceStream, ReadOnlySource
     }, meta::MetadataOptions, probe::{
         Hint,
@@ -114,25 +114,30 @@ fn get_durations(file_path: &str) -> HashMap<u32, f64> {
 #[derive(Debug)]
 pub struct TrackInfo {
     pub sample_rate: u32,
-    pub channel_layout: Layout,
+    pub channel_layout: Channels,
     pub bits_per_sample: u32,
 }
 
-fn are_layouts_equal(layout1: Layout, layout2: Layout) -> bool {
-    match (layout1, layout2) {
-        (Layout::Mono, Layout::Mono) => true,
-        (Layout::Stereo, Layout::Stereo) => true,
-        (Layout::TwoPointOne, Layout::TwoPointOne) => true,
-        (Layout::FivePointOne, Layout::FivePointOne) => true,
-        // Add other cases if needed
-        _ => false,
+fn get_track_channels(track: &Track) -> Channels {
+    let codec_params = &track.codec_params;
+    if let Some(channels) = codec_params.channels {
+        return channels;
     }
+
+    // The decoder knows more than the container, e.g. Matroska only gives a
+    // layout for some channel counts where FLAC has them all in STREAMINFO
+    let decoder = symphonia::default::get_codecs().make(codec_params, &DecoderOptions::default());
+    decoder
+        .ok()
+        .and_then(|decoder| decoder.codec_params().channels)
+        .or(codec_params.channel_layout.map(|layout| layout.into_channels()))
+        .unwrap_or(Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
 }
 
 fn get_track_info(track: &Track) -> TrackInfo {
     let codec_params = &track.codec_params;
     let sample_rate = codec_params.sample_rate.unwrap();
-    let channel_layout = codec_params.channel_layout.unwrap();
+    let channel_layout = get_track_channels(track);
     let bits_per_sample = codec_params.bits_per_sample.unwrap();
     
     TrackInfo {
@@ -142,21 +147,24 @@ fn get_track_info(track: &Track) -> TrackInfo {
     }
 }
 
+// Tracks at a lower sample rate are resampled to the highest rate as they are
+// decoded, so the rates need not match. Likewise tracks are mixed into every
+// channel any of them has, with mono tracks spread over the others rather
+// than given a channel of their own. Every track is decoded to floats, so
+// bit depths need not match either, and the deepest is kept.
 fn reduce_track_infos(track_infos: Vec<TrackInfo>) -> TrackInfo {
     let info = track_infos.into_iter().fold(None, |acc: Option<TrackInfo>, track_info| {
         match acc {
-            Some(acc) => {
-                if acc.sample_rate != track_info.sample_rate {
-                    panic!("Sample rates do not match");
-                }
+            Some(mut acc) => {
+                acc.sample_rate = acc.sample_rate.max(track_info.sample_rate);
 
-                if !are_layouts_equal(acc.channel_layout, track_info.channel_layout) {
-                    panic!("Channel layouts do not match");
+                if acc.channel_layout.count() == 1 {
+                    acc.channel_layout = track_info.channel_layout;
+                } else if track_info.channel_layout.count() > 1 {
+                    acc.channel_layout |= track_info.channel_layout;
                 }
 
-                if acc.bits_per_sample != track_info.bits_per_sample {
-                    panic!("Bits per sample do not match");
-                }
+                acc.bits_per_sample = acc.bits_per_sample.max(track_info.bits_per_sample);
 
                 Some(acc)
             },
@@ -191,20 +199,13 @@ fn gather_track_info_from_file_paths(file_paths: Vec<String>) -> TrackInfo {
     reduce_track_infos(track_infos)
 }
 
-fn get_channel_count(channel_layout: Layout) -> u32 {
-    match channel_layout {
-        Layout::Mono => 1,
-        Layout::Stereo => 2,
-        Layout::TwoPointOne => 3,
-        Layout::FivePointOne => 6
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct Info {
     pub file_paths: Vec<String>,
     pub duration_map: HashMap<u32, f64>,
     pub channels: u32,
+    /// The channels of the mix, in the order their samples are interleaved.
+    pub channel_layout: Channels,
     pub sample_rate: u32,
     pub bits_per_sample: u32,
 }
@@ -212,12 +213,13 @@ pub struct Info {
 impl Info {
     pub fn new(file_path: String) -> Self {
         let track_info = gather_track_info(&file_path);
-        let channels = get_channel_count(track_info.channel_layout);
+        let channels = track_info.channel_layout.count() as u32;
 
         Self {
             duration_map: get_durations(&file_path),
             file_paths: vec![file_path],
             channels,
+            channel_layout: track_info.channel_layout,
             sample_rate: track_info.sample_rate,
             bits_per_sample: track_info.bits_per_sample,
         }
@@ -233,12 +235,13 @@ impl Info {
         }
 
         let track_info = gather_track_info_from_file_paths(file_paths.clone());
-        let channels = get_channel_count(track_info.channel_layout);
+        let channels = track_info.channel_layout.count() as u32;
 
         Self {
             duration_map,
             file_paths,
             channels,
+            channel_layout: track_info.channel_layout,
             sample_rate: track_info.sample_rate,
             bits_per_sample: track_info.bits_per_sample,
         }
@@ -1,10 +1,25 @@
 pub mod player;
-mod player_engine;
+pub mod player_engine;
 mod tools;
 mod buffer;
 mod track;
+mod constants;
+mod ebml;
+mod flac;
+mod selection;
 pub mod info;
 pub mod effects;
 pub mod prot;
+pub mod play_settings;
+pub mod prot_writer;
+pub mod audio_file;
+pub mod unpack;
 pub mod peaks;
-pub mod timer;
\ No newline at end of file
+pub mod output;
+pub mod render;
+pub mod timer;
+pub mod resample;
+pub mod channels;
+pub mod mixer;
+pub mod loudness;
+pub mod limiter;
\ No newline at end of file
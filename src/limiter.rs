@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Ceiling, in dBTP, the master bus limits the mix to unless told otherwise.
+pub const DEFAULT_CEILING: f32 = -1.0;
+
+// Seconds the limiter looks ahead, so it can turn down before a peak
+const LOOKAHEAD_SECONDS: f64 = 0.005;
+// Seconds the gain takes to recover most of the way after a peak
+const RELEASE_SECONDS: f64 = 0.1;
+
+// True peaks are found between the samples by upsampling 4 times with a
+// windowed sinc, using this many samples either side
+const OVERSAMPLING: usize = 4;
+const INTERPOLATION_TAPS: usize = 6;
+
+// Keeps the mix under a ceiling on its true peak, the peak of the waveform
+// between the samples as well as at them. The gain comes down ahead of each
+// peak over the look-ahead and recovers after it over the release, and the
+// output is clamped to the ceiling so that nothing can get past.
+//
+// Frames are held back by the look-ahead and the interpolation taps, but
+// each comes out in line with the frame that went in. Samples are
+// interleaved.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    channels: usize,
+    ceiling: f32,
+    lookahead: usize,
+    release: f32,
+    // Interpolation filter of each phase between two samples
+    phases: Vec<[f32; INTERPOLATION_TAPS * 2]>,
+    // Frames not yet given out, from `input_start` on
+    input: VecDeque<f32>,
+    input_start: u64,
+    frames_in: u64,
+    // Frames of real input, as opposed to the silence that flushes the end
+    frames_expected: Option<u64>,
+    // Increasing gains needed from upcoming frames, for the lowest over the
+    // look-ahead
+    needed: VecDeque<(u64, f32)>,
+    // The last gains after the release, and their sum
+    gains: VecDeque<f32>,
+    gain_sum: f64,
+    output: VecDeque<f32>,
+    frames_out: u64,
+}
+
+impl Limiter {
+    /// `ceiling` is in dBTP.
+    pub fn new(channels: usize, sample_rate: u32, ceiling: f32) -> Self {
+        let lookahead = ((LOOKAHEAD_SECONDS * sample_rate as f64).round() as usize).max(INTERPOLATION_TAPS * 2);
+        let release = (-1.0 / (RELEASE_SECONDS * sample_rate as f64)).exp() as f32;
+
+        let phases = (1..OVERSAMPLING)
+            .map(|phase| {
+                let offset = phase as f64 / OVERSAMPLING as f64;
+                let mut taps = [0.0; INTERPOLATION_TAPS * 2];
+                for (index, tap) in taps.iter_mut().enumerate() {
+                    // Distance from the point being interpolated to the sample
+                    let x = index as f64 - (INTERPOLATION_TAPS - 1) as f64 - offset;
+                    let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                    let window = 0.5 + 0.5 * (PI * x / INTERPOLATION_TAPS as f64).cos();
+                    *tap = (sinc * window) as f32;
+                }
+                taps
+            })
+            .collect();
+
+        Self {
+            channels,
+            ceiling: 10f32.powf(ceiling / 20.0),
+            lookahead,
+            release,
+            phases,
+            input: VecDeque::new(),
+            input_start: 0,
+            frames_in: 0,
+            frames_expected: None,
+            needed: VecDeque::new(),
+            gains: VecDeque::with_capacity(lookahead),
+            gain_sum: 0.0,
+            output: VecDeque::new(),
+            frames_out: 0,
+        }
+    }
+
+    /// Limits interleaved `samples`. The last frames are held back until
+    /// more come in or the input finishes.
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            self.input.extend(frame);
+            self.frames_in += 1;
+            self.process();
+        }
+    }
+
+    /// Gives out the frames held back, once there is no more input.
+    pub fn finish(&mut self) {
+        if self.frames_expected.is_some() {
+            return;
+        }
+        let frames = self.frames_in;
+        self.frames_expected = Some(frames);
+
+        let silence = vec![0.0; self.channels];
+        while self.frames_out < frames {
+            self.input.extend(&silence);
+            self.frames_in += 1;
+            self.process();
+        }
+    }
+
+    /// Whether the input has ended and every limited sample has been taken.
+    pub fn is_finished(&self) -> bool {
+        self.frames_expected.is_some_and(|frames| self.frames_out >= frames) && self.output.is_empty()
+    }
+
+    /// Moves limited samples into `buffer`. Returns how many were moved.
+    pub fn pop(&mut self, buffer: &mut [f32]) -> usize {
+        let count = buffer.len().min(self.output.len());
+        for (out, sample) in buffer.iter_mut().zip(self.output.drain(..count)) {
+            *out = sample;
+        }
+
+        count
+    }
+
+    /// Takes every limited sample.
+    pub fn take(&mut self) -> Vec<f32> {
+        self.output.drain(..).collect()
+    }
+
+    /// Limited samples ready to be taken.
+    pub fn len(&self) -> usize {
+        self.output.len()
+    }
+
+    // Moves on by the frame just added to the input
+    fn process(&mut self) {
+        // The interpolation needs the taps after a frame to find its peak
+        let Some(frame) = self.frames_in.checked_sub(INTERPOLATION_TAPS as u64 + 1) else {
+            return;
+        };
+
+        let peak = self.true_peak(frame);
+        let needed = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+        while self.needed.back().is_some_and(|(_, gain)| *gain >= needed) {
+            self.needed.pop_back();
+        }
+        self.needed.push_back((frame, needed));
+
+        // The frame the look-ahead has now passed over
+        let Some(frame) = (frame + 1).checked_sub(self.lookahead as u64) else {
+            return;
+        };
+        while self.needed.front().is_some_and(|(index, _)| *index < frame) {
+            self.needed.pop_front();
+        }
+        let needed = self.needed.front().unwrap().1;
+
+        // Down at once, back up over the release
+        let previous = self.gains.back().copied().unwrap_or(1.0);
+        let gain = if needed < previous { needed } else { needed + (previous - needed) * self.release };
+
+        // Before the first frame the gain is what the first look-ahead needs,
+        // so a mix that starts loud is turned down from its first frame
+        if self.gains.is_empty() {
+            self.gains.resize(self.lookahead, gain);
+            self.gain_sum = gain as f64 * self.lookahead as f64;
+        }
+
+        // Averaging over the look-ahead ramps the gain down ahead of a peak,
+        // and never above what any frame in the look-ahead needs
+        self.gain_sum += (gain - self.gains.pop_front().unwrap()) as f64;
+        self.gains.push_back(gain);
+        let gain = (self.gain_sum / self.lookahead as f64).min(1.0) as f32;
+
+        let offset = (frame - self.input_start) as usize * self.channels;
+        if self.frames_expected.is_none_or(|frames| self.frames_out < frames) {
+            for sample in self.input.range(offset..offset + self.channels) {
+                self.output.push_back((sample * gain).clamp(-self.ceiling, self.ceiling));
+            }
+            self.frames_out += 1;
+        }
+
+        // The look-ahead is longer than the interpolation taps, so no earlier
+        // frame is needed again
+        while self.input_start <= frame {
+            self.input.drain(..self.channels);
+            self.input_start += 1;
+        }
+    }
+
+    // Highest peak on any channel from `frame` up to the next frame
+    fn true_peak(&self, frame: u64) -> f32 {
+        let mut peak: f32 = 0.0;
+
+        for channel in 0..self.channels {
+            let sample = |index: u64| match index.checked_sub(self.input_start) {
+                Some(offset) => self.input[offset as usize * self.channels + channel],
+                None => 0.0,
+            };
+
+            peak = peak.max(sample(frame).abs());
+
+            let first = frame as i64 - (INTERPOLATION_TAPS as i64 - 1);
+            for taps in &self.phases {
+                let interpolated: f32 = taps
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| first + *index as i64 >= 0)
+                    .map(|(index, tap)| tap * sample((first + index as i64) as u64))
+                    .sum();
+                peak = peak.max(interpolated.abs());
+            }
+        }
+
+        peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn limit(samples: &[f32], channels: usize, ceiling: f32) -> Vec<f32> {
+        let mut limiter = Limiter::new(channels, SAMPLE_RATE, ceiling);
+        let mut output = Vec::new();
+        // Pushed in uneven chunks, as the mixer would
+        for chunk in samples.chunks(channels * 333) {
+            limiter.push(chunk);
+            output.extend(limiter.take());
+        }
+        limiter.finish();
+        output.extend(limiter.take());
+        assert!(limiter.is_finished());
+        output
+    }
+
+    // True peak of one channel, found with a much longer windowed sinc than
+    // the limiter's and at twice the oversampling
+    fn true_peak(samples: &[f32], channels: usize, channel: usize) -> f32 {
+        const TAPS: i64 = 32;
+        const PHASES: usize = 8;
+        let frames = (samples.len() / channels) as i64;
+        let sample = |frame: i64| match (0..frames).contains(&frame) {
+            true => samples[frame as usize * channels + channel] as f64,
+            false => 0.0,
+        };
+
+        let mut peak: f64 = 0.0;
+        for frame in 0..frames {
+            for phase in 0..PHASES {
+                let offset = phase as f64 / PHASES as f64;
+                let interpolated: f64 = (frame - TAPS + 1..=frame + TAPS)
+                    .map(|index| {
+                        let x = (index - frame) as f64 - offset;
+                        let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                        let window = 0.5 + 0.5 * (PI * x / TAPS as f64).cos();
+                        sinc * window * sample(index)
+                    })
+                    .sum();
+                peak = peak.max(interpolated.abs());
+            }
+        }
+
+        peak as f32
+    }
+
+    fn to_db(level: f32) -> f32 {
+        20.0 * level.log10()
+    }
+
+    #[test]
+    fn quiet_mix_passes_unchanged() {
+        let samples: Vec<f32> = (0..SAMPLE_RATE as usize * 2)
+            .map(|index| 0.5 * (index as f32 * 0.01).sin() * if index % 2 == 0 { 1.0 } else { -0.5 })
+            .collect();
+
+        assert_eq!(limit(&samples, 2, DEFAULT_CEILING), samples);
+    }
+
+    #[test]
+    fn true_peak_stays_under_the_ceiling() {
+        // A sine at a quarter of the sample rate peaks halfway between its
+        // samples, 3 dB over them
+        let sine: Vec<f32> = (0..SAMPLE_RATE as usize / 4)
+            .map(|frame| 1.5 * (PI / 2.0 * frame as f64 + PI / 4.0).sin() as f32)
+            .collect();
+        // Loud noise from a fixed seed on a second channel, smoothed so that,
+        // like music, it has little left near the Nyquist frequency where 4
+        // times oversampling misses the most
+        let mut state: u32 = 1;
+        let mut smoothed = [0.0; 4];
+        let noise: Vec<f32> = (0..sine.len())
+            .map(|frame| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                smoothed[frame % 4] = (state as i32) as f32 / i32::MAX as f32;
+                smoothed.iter().sum::<f32>()
+            })
+            .collect();
+        let samples: Vec<f32> = sine.iter().zip(&noise).flat_map(|(sine, noise)| [*sine, *noise]).collect();
+
+        for ceiling in [DEFAULT_CEILING, -6.0] {
+            let limited = limit(&samples, 2, ceiling);
+            assert_eq!(limited.len(), samples.len());
+
+            for channel in 0..2 {
+                let peak = to_db(true_peak(&limited, 2, channel));
+                assert!(peak <= ceiling + 0.1, "channel {} peaks at {} dBTP over {}", channel, peak, ceiling);
+            }
+
+            // On its own, the sine is only turned down as far as it needs
+            let limited = limit(&sine, 1, ceiling);
+            let peak = to_db(true_peak(&limited, 1, 0));
+            assert!(peak <= ceiling + 0.1 && peak > ceiling - 0.5, "the sine peaks at {} dBTP", peak);
+        }
+    }
+
+    #[test]
+    fn gain_recovers_after_a_peak() {
+        let mut samples = vec![0.25; SAMPLE_RATE as usize * 2];
+        samples[SAMPLE_RATE as usize / 2] = 4.0;
+
+        let limited = limit(&samples, 1, DEFAULT_CEILING);
+        let ceiling = 10f32.powf(DEFAULT_CEILING / 20.0);
+        assert!(limited.iter().all(|sample| sample.abs() <= ceiling));
+        // Ducked around the peak, back up a second later
+        assert!(limited[SAMPLE_RATE as usize / 2 - 10] < 0.1);
+        assert!((limited.last().unwrap() - 0.25).abs() < 0.001);
+    }
+}
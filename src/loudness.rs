@@ -0,0 +1,132 @@
+use std::f64::consts::PI;
+
+use symphonia::core::audio::Channels;
+
+use crate::peaks::decode_channels;
+
+// Gating blocks are 400 ms long and start every 100 ms
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+const SUB_BLOCK_SECONDS: f64 = 0.1;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+// Surround channels count for more, as they are heard from the side
+const SURROUND_WEIGHT: f64 = 1.41;
+const SURROUNDS: Channels = Channels::SIDE_LEFT
+    .union(Channels::SIDE_RIGHT)
+    .union(Channels::REAR_LEFT)
+    .union(Channels::REAR_RIGHT);
+const LFES: Channels = Channels::LFE1.union(Channels::LFE2);
+
+/// Integrated loudness in LUFS, as EBU R128 measures it, of `samples` with
+/// one vector per channel of `channels`. Returns `None` when the audio is
+/// silent or shorter than a gating block.
+pub fn integrated_loudness(samples: &[Vec<f32>], channels: Channels, sample_rate: u32) -> Option<f32> {
+    let sub_block_length = (sample_rate as f64 * SUB_BLOCK_SECONDS).round() as usize;
+
+    // Energy of every 100 ms of the weighted audio, summed over the channels
+    let mut sub_blocks: Vec<f64> = Vec::new();
+    for (samples, channel) in samples.iter().zip(channels.iter()) {
+        let weight = if LFES.contains(channel) {
+            continue;
+        } else if SURROUNDS.contains(channel) {
+            SURROUND_WEIGHT
+        } else {
+            1.0
+        };
+
+        let mut filter = KWeighting::new(sample_rate);
+        let sub_block_count = samples.len() / sub_block_length;
+        sub_blocks.resize(sub_blocks.len().max(sub_block_count), 0.0);
+
+        for (sub_block, chunk) in sub_blocks.iter_mut().zip(samples.chunks_exact(sub_block_length)) {
+            let energy: f64 = chunk
+                .iter()
+                .map(|sample| {
+                    let filtered = filter.process(*sample as f64);
+                    filtered * filtered
+                })
+                .sum();
+            *sub_block += weight * energy;
+        }
+    }
+
+    let block_length = (sub_block_length * SUB_BLOCKS_PER_BLOCK) as f64;
+    let blocks: Vec<f64> = sub_blocks
+        .windows(SUB_BLOCKS_PER_BLOCK)
+        .map(|window| window.iter().sum::<f64>() / block_length)
+        .collect();
+
+    let loudness = |energy: f64| -0.691 + 10.0 * energy.log10();
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+    let audible: Vec<f64> = blocks.into_iter().filter(|block| loudness(*block) > ABSOLUTE_GATE).collect();
+    if audible.is_empty() {
+        return None;
+    }
+
+    let relative_gate = loudness(mean(&audible)) + RELATIVE_GATE;
+    let gated: Vec<f64> = audible.into_iter().filter(|block| loudness(*block) > relative_gate).collect();
+
+    Some(loudness(mean(&gated)) as f32)
+}
+
+/// Integrated loudness in LUFS of one take: a track of the file, or the
+/// first track when `track_id` is `None`.
+pub fn measure_loudness(file_path: &str, track_id: Option<u32>) -> Option<f32> {
+    let decoded = decode_channels(file_path, track_id);
+    integrated_loudness(&decoded.samples, decoded.channels, decoded.sample_rate)
+}
+
+// Two stage filter modelling how loud the head hears each frequency: a
+// shelf lifting the highs and a high pass cutting the lows
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f64;
+
+        let k = (PI * 1681.974450955533 / sample_rate).tan();
+        let q = 0.7071752369554196;
+        let vh = 10f64.powf(3.999843853973347 / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let k = (PI * 38.13547087602444 / sample_rate).tan();
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+        Self { stages: [shelf, high_pass] }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.stages.iter_mut().fold(sample, |sample, stage| stage.process(sample))
+    }
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, state: [0.0; 2] }
+    }
+
+    // Transposed direct form II
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
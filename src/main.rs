@@ -1,10 +1,15 @@
-use clap::{Arg, ArgMatches};
-use log::error;
-use proteus_audio::{info, player, prot};
+use std::sync::Arc;
+
+use clap::{Arg, ArgAction, ArgMatches};
+use proteus_audio::effects::ImpulseResponse;
+use proteus_audio::play_settings::SectionSettings;
+use proteus_audio::resample::ResampleQuality;
+use proteus_audio::{audio_file, info, player, prot, prot_writer, render, unpack};
 use serde_json::Number;
-use symphonia::core::errors::Result;
 use rand::Rng;
 
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
 fn main() {
     let args = clap::Command::new("Prot Play")
         .version("1.0")
@@ -62,6 +67,13 @@ fn main() {
                 .long("no-gapless")
                 .help("Disable gapless decoding and playback"),
         )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .value_parser(clap::value_parser!(u64))
+                .help("Seed the track selection so that it can be replayed"),
+        )
         .arg(Arg::new("debug").short('d').help("Show debug output"))
         .arg(
             Arg::new("INPUT")
@@ -69,13 +81,235 @@ fn main() {
                 .required(true)
                 .index(1),
         )
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            clap::Command::new("pack")
+                .about("Write a .prot file from groups of source audio files")
+                .arg(
+                    Arg::new("group")
+                        .long("group")
+                        .short('G')
+                        .value_name("FILES")
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .required(true)
+                        .help("The takes of one track group. Repeat for each group"),
+                )
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .short('n')
+                        .value_name("NAME")
+                        .action(ArgAction::Append)
+                        .help("A name for each track group, in the same order as --group"),
+                )
+                .arg(
+                    Arg::new("weights")
+                        .long("weights")
+                        .short('w')
+                        .value_name("WEIGHTS")
+                        .action(ArgAction::Append)
+                        .help("Comma separated take weights for each track group, e.g. 3,1,1, in the same order as --group"),
+                )
+                .arg(
+                    Arg::new("presence")
+                        .long("presence")
+                        .value_name("CHANCE")
+                        .value_parser(clap::value_parser!(f32))
+                        .action(ArgAction::Append)
+                        .help("The chance, from 0 to 1, that each track group plays, in the same order as --group"),
+                )
+                .arg(
+                    Arg::new("section")
+                        .long("section")
+                        .value_name("START[:NAME]")
+                        .action(ArgAction::Append)
+                        .help("Start a new section, with its own choice of takes, at the given time in seconds"),
+                )
+                .arg(
+                    Arg::new("crossfade")
+                        .long("crossfade")
+                        .value_name("SECONDS")
+                        .value_parser(clap::value_parser!(f64))
+                        .help("The crossfade where a track group switches takes between sections"),
+                )
+                .arg(
+                    Arg::new("measure-loudness")
+                        .long("measure-loudness")
+                        .action(ArgAction::SetTrue)
+                        .help("Measure the loudness of every take, for playback to normalize"),
+                )
+                .arg(
+                    Arg::new("loudness-window")
+                        .long("loudness-window")
+                        .value_name("LU")
+                        .value_parser(clap::value_parser!(f32))
+                        .help("How far, in LU, a take's loudness may be from its group's, or a combination's from the usual, before it is normalized"),
+                )
+                .arg(
+                    Arg::new("impulse-response")
+                        .long("impulse-response")
+                        .value_name("FILE")
+                        .help("WAV or FLAC impulse response to attach, for a reverb over the mix"),
+                )
+                .arg(
+                    Arg::new("wet")
+                        .long("wet")
+                        .value_name("LEVEL")
+                        .value_parser(clap::value_parser!(f32))
+                        .requires("impulse-response")
+                        .help("Level of the reverb, from 0 to 1"),
+                )
+                .arg(
+                    Arg::new("dry")
+                        .long("dry")
+                        .value_name("LEVEL")
+                        .value_parser(clap::value_parser!(f32))
+                        .requires("impulse-response")
+                        .help("Level of the mix without the reverb, from 0 to 1"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("FILE")
+                        .required(true)
+                        .help("The .prot file to write"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("unpack")
+                .about("Write every take in a .prot file to its own audio file")
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("DIR")
+                        .required(true)
+                        .help("The directory to write the stems and manifest.json to"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .short('f')
+                        .value_name("FORMAT")
+                        .value_parser(["wav", "flac"])
+                        .default_value("wav")
+                        .help("The audio format of the stems"),
+                )
+                .arg(
+                    Arg::new("INPUT")
+                        .help("The .prot file to unpack")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("render")
+                .about("Mix one selection of takes to a WAV or FLAC file")
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .value_name("SEED")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Seed the track selection, as printed during playback"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("FILE")
+                        .required(true)
+                        .help("The .wav or .flac file to write"),
+                )
+                .args(render_args())
+                .arg(
+                    Arg::new("INPUT")
+                        .help("The .prot file to render")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("batch")
+                .about("Mix several different selections of takes to WAV or FLAC files")
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .short('c')
+                        .value_name("COUNT")
+                        .value_parser(clap::value_parser!(usize))
+                        .required(true)
+                        .help("How many different mixes to write"),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .value_name("SEED")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Seed the batch so that it can be written again"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("DIR")
+                        .required(true)
+                        .help("The directory to write the mixes and their index to"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .short('f')
+                        .value_name("FORMAT")
+                        .value_parser(["wav", "flac"])
+                        .default_value("wav")
+                        .help("The audio format of the mixes"),
+                )
+                .args(render_args())
+                .arg(
+                    Arg::new("INPUT")
+                        .help("The .prot file to render")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("combinations")
+                .about("Count the combinations of takes in a .prot file and list its track groups")
+                .arg(
+                    Arg::new("list")
+                        .long("list")
+                        .short('l')
+                        .value_name("LIMIT")
+                        .value_parser(clap::value_parser!(usize))
+                        .num_args(1)
+                        .help("Also print the combination ids, up to LIMIT of them"),
+                )
+                .arg(
+                    Arg::new("INPUT")
+                        .help("The .prot file to inspect")
+                        .required(true)
+                        .index(1),
+                ),
+        )
         .get_matches();
 
+    let result = match args.subcommand() {
+        Some(("pack", sub_args)) => pack(sub_args),
+        Some(("unpack", sub_args)) => unpack(sub_args),
+        Some(("render", sub_args)) => render(sub_args),
+        Some(("batch", sub_args)) => batch(sub_args),
+        Some(("combinations", sub_args)) => combinations(sub_args),
+        _ => run(&args),
+    };
+
     // For any error, return an exit code -1. Otherwise return the exit code provided.
-    let code = match run(&args) {
+    let code = match result {
         Ok(code) => code,
         Err(err) => {
-            error!("{}", err.to_string().to_lowercase());
+            eprintln!("Error: {}", err);
             -1
         }
     };
@@ -83,6 +317,32 @@ fn main() {
     std::process::exit(code)
 }
 
+fn parse_weights(weights: &str) -> Result<Vec<f32>> {
+    weights
+        .split(',')
+        .map(|weight| {
+            weight
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| format!("\"{}\" is not a valid weight", weight).into())
+        })
+        .collect()
+}
+
+fn parse_section(section: &str) -> Result<SectionSettings> {
+    let (start, name) = match section.split_once(':') {
+        Some((start, name)) => (start, Some(name.to_string())),
+        None => (section, None),
+    };
+
+    let start = start
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("\"{}\" is not a valid section start", start))?;
+
+    Ok(SectionSettings { start, name })
+}
+
 fn format_time(time: f64) -> String {
     // Seconds rounded up
     let seconds = (time / 1000.0).ceil() as u32;
@@ -94,6 +354,221 @@ fn format_time(time: f64) -> String {
     format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
 }
 
+fn pack(args: &ArgMatches) -> Result<i32> {
+    let file_paths: Vec<Vec<String>> = args
+        .get_occurrences::<String>("group")
+        .unwrap()
+        .map(|group| group.cloned().collect())
+        .collect();
+    let output = args.get_one::<String>("output").unwrap();
+
+    let mut writer = prot_writer::ProtWriter::new(&file_paths);
+
+    if let Some(names) = args.get_many::<String>("name") {
+        writer.set_names(names.cloned().collect());
+    }
+
+    if let Some(weights) = args.get_many::<String>("weights") {
+        let weights = weights
+            .map(|weights| parse_weights(weights).map(Some))
+            .collect::<Result<Vec<Option<Vec<f32>>>>>()?;
+        writer.set_weights(weights);
+    }
+
+    if let Some(presence) = args.get_many::<f32>("presence") {
+        writer.set_presence(presence.map(|presence| Some(*presence)).collect());
+    }
+
+    if let Some(sections) = args.get_many::<String>("section") {
+        let sections = sections
+            .map(|section| parse_section(section))
+            .collect::<Result<Vec<SectionSettings>>>()?;
+        writer.set_sections(sections);
+    }
+
+    writer.set_crossfade(args.get_one::<f64>("crossfade").copied());
+    writer.set_measure_loudness(args.get_flag("measure-loudness"));
+    writer.set_loudness_window(args.get_one::<f32>("loudness-window").copied());
+    writer.set_reverb(
+        args.get_one::<String>("impulse-response").cloned(),
+        args.get_one::<f32>("wet").copied(),
+        args.get_one::<f32>("dry").copied(),
+    );
+
+    writer.write(output)?;
+
+    let file_count = writer.get_file_paths_dictionary().len();
+    println!("Wrote {} tracks in {} groups to {}", file_count, file_paths.len(), output);
+
+    Ok(0)
+}
+
+fn unpack(args: &ArgMatches) -> Result<i32> {
+    let file_path = args.get_one::<String>("INPUT").unwrap();
+    let output = args.get_one::<String>("output").unwrap();
+    let format = match args.get_one::<String>("format").unwrap().as_str() {
+        "flac" => audio_file::AudioFileFormat::Flac,
+        _ => audio_file::AudioFileFormat::Wav,
+    };
+
+    let stems = unpack::unpack(file_path, output, format)?;
+
+    for stem in &stems {
+        println!("{} (track {}) -> {}", stem.key, stem.track_id, stem.file_path);
+    }
+
+    Ok(0)
+}
+
+fn render(args: &ArgMatches) -> Result<i32> {
+    let file_path = args.get_one::<String>("INPUT").unwrap();
+    let output = args.get_one::<String>("output").unwrap();
+    let seed = args.get_one::<u64>("seed").copied();
+    let format = audio_file::AudioFileFormat::from_path(output)
+        .ok_or_else(|| format!("\"{}\" is not a .wav or .flac file", output))?;
+
+    let render = render::render(file_path, seed, output, &get_render_options(args, format)?)?;
+
+    if let Some(seed) = render.seed {
+        println!("Seed: {}", seed);
+    }
+    println!("Combination: {}", render.combination);
+    println!("Tracks: {:?}", render.ids);
+    println!("Wrote {} to {}", format_time(render.duration * 1000.0), render.file_path);
+
+    Ok(0)
+}
+
+fn batch(args: &ArgMatches) -> Result<i32> {
+    let file_path = args.get_one::<String>("INPUT").unwrap();
+    let output = args.get_one::<String>("output").unwrap();
+    let count = *args.get_one::<usize>("count").unwrap();
+    let seed = args.get_one::<u64>("seed").copied();
+    let format = match args.get_one::<String>("format").unwrap().as_str() {
+        "flac" => audio_file::AudioFileFormat::Flac,
+        _ => audio_file::AudioFileFormat::Wav,
+    };
+
+    let renders = render::render_batch(file_path, count, seed, output, &get_render_options(args, format)?)?;
+
+    for render in &renders {
+        println!("{} {:?} -> {}", render.combination, render.ids, render.file_path);
+    }
+
+    Ok(0)
+}
+
+// Arguments the render and batch subcommands share, read by get_render_options
+fn render_args() -> Vec<Arg> {
+    vec![
+        Arg::new("sample-rate")
+            .long("sample-rate")
+            .short('r')
+            .value_name("RATE")
+            .value_parser(clap::value_parser!(u32).range(8_000..=384_000))
+            .help("Resample the mix to RATE Hz instead of the file's own rate"),
+        Arg::new("channels")
+            .long("channels")
+            .value_name("COUNT")
+            .value_parser(clap::value_parser!(u16).range(1..=8))
+            .help("Remix the mix to COUNT channels (2 for stereo, 6 for 5.1, 8 for 7.1) instead of the file's own"),
+        Arg::new("resample-quality")
+            .long("resample-quality")
+            .value_name("QUALITY")
+            .value_parser(["fast", "balanced", "high"])
+            .default_value("high")
+            .help("How carefully to resample tracks and mixes that are not at the file's rate"),
+        Arg::new("normalize-loudness")
+            .long("normalize-loudness")
+            .action(ArgAction::SetTrue)
+            .help("Bring every take in line with the loudness of the other takes in its group, and every combination within the loudness window"),
+        Arg::new("ceiling")
+            .long("ceiling")
+            .value_name("DBTP")
+            .value_parser(clap::value_parser!(f32))
+            .allow_negative_numbers(true)
+            .default_value("-1")
+            .help("True peak, in dBTP, the master bus limiter keeps the mix under"),
+        Arg::new("no-limiter")
+            .long("no-limiter")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("ceiling")
+            .help("Leave the mix unlimited, so that it can clip"),
+        Arg::new("impulse-response")
+            .long("impulse-response")
+            .value_name("FILE")
+            .help("WAV or FLAC impulse response to reverb the mix with, in place of the file's"),
+        Arg::new("wet")
+            .long("wet")
+            .value_name("LEVEL")
+            .value_parser(clap::value_parser!(f32))
+            .help("Level of the reverb, from 0 to 1"),
+        Arg::new("dry")
+            .long("dry")
+            .value_name("LEVEL")
+            .value_parser(clap::value_parser!(f32))
+            .help("Level of the mix without the reverb, from 0 to 1"),
+        Arg::new("no-reverb")
+            .long("no-reverb")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["impulse-response", "wet", "dry"])
+            .help("Leave out the reverb the file sets"),
+    ]
+}
+
+fn get_render_options(args: &ArgMatches, format: audio_file::AudioFileFormat) -> Result<render::RenderOptions> {
+    let resample_quality = match args.get_one::<String>("resample-quality").unwrap().as_str() {
+        "fast" => ResampleQuality::Fast,
+        "balanced" => ResampleQuality::Balanced,
+        _ => ResampleQuality::High,
+    };
+
+    let impulse_response = match args.get_one::<String>("impulse-response") {
+        Some(file_path) => Some(Arc::new(
+            ImpulseResponse::load(file_path).map_err(|err| format!("{}: {}", file_path, err))?,
+        )),
+        None => None,
+    };
+
+    Ok(render::RenderOptions {
+        sample_rate: args.get_one::<u32>("sample-rate").copied(),
+        channels: args.get_one::<u16>("channels").copied(),
+        resample_quality,
+        normalize_loudness: args.get_flag("normalize-loudness"),
+        limiter_ceiling: match args.get_flag("no-limiter") {
+            true => None,
+            false => args.get_one::<f32>("ceiling").copied(),
+        },
+        reverb: !args.get_flag("no-reverb"),
+        impulse_response,
+        reverb_wet: args.get_one::<f32>("wet").copied(),
+        reverb_dry: args.get_one::<f32>("dry").copied(),
+        ..render::RenderOptions::new(format)
+    })
+}
+
+fn combinations(args: &ArgMatches) -> Result<i32> {
+    let file_path = args.get_one::<String>("INPUT").unwrap();
+    let prot = prot::Prot::try_new(file_path)?;
+
+    println!("Combinations: {}", prot.count_combinations());
+
+    for (key, name, takes) in prot.get_group_list() {
+        match name {
+            Some(name) => println!("Group {} ({}): {}", key, name, takes.join(", ")),
+            None => println!("Group {}: {}", key, takes.join(", ")),
+        }
+    }
+
+    if let Some(limit) = args.get_one::<usize>("list") {
+        for combination in prot.combinations().take(*limit) {
+            println!("{}", combination);
+        }
+    }
+
+    Ok(0)
+}
+
 fn run(args: &ArgMatches) -> Result<i32> {
     let file_path = args.get_one::<String>("INPUT").unwrap().clone();
     let gain = args.get_one::<String>("GAIN").unwrap().parse::<f32>().unwrap().clone();
@@ -103,8 +578,14 @@ fn run(args: &ArgMatches) -> Result<i32> {
         panic!("File is not a .prot file");
     }
 
-    let mut player = player::Player::new(&file_path);
-    
+    let seed = args.get_one::<u64>("seed").copied();
+
+    let mut player = player::Player::try_new_with_seed(&file_path, seed)?;
+    if let Some(seed) = player.get_seed() {
+        println!("Seed: {}", seed);
+    }
+    println!("Combination: {}", player.get_combination());
+
     let info = info::Info::new(file_path);
     println!("Files: {:?}", info.file_paths);
     println!("Duration: {:?}", info.duration_map);
@@ -0,0 +1,245 @@
+use std::f32::consts::FRAC_PI_2;
+
+use symphonia::core::audio::Channels;
+
+use crate::channels::{is_left, is_right};
+use crate::play_settings::{AutomationPoint, AutomationSettings, TrackSettingsV1};
+
+// Seconds a change to a group's mix takes to ramp in, so it does not click
+const RAMP_SECONDS: f64 = 0.03;
+
+/// Level, stereo position, mute and solo of one track group in the mix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroupMix {
+    /// Gain in dB.
+    pub gain: f32,
+    /// Stereo position, from -1 (left) through 0 (centre) to 1 (right).
+    /// Panning turns the other side down, until it is silent at either end.
+    pub pan: f32,
+    pub mute: bool,
+    /// While any group is soloed, only the soloed groups are heard.
+    pub solo: bool,
+}
+
+impl Default for GroupMix {
+    fn default() -> Self {
+        Self { gain: 0.0, pan: 0.0, mute: false, solo: false }
+    }
+}
+
+impl GroupMix {
+    /// The group's mix as set in play settings, with anything missing left
+    /// at its default.
+    pub fn from_settings(track: &TrackSettingsV1) -> Self {
+        Self {
+            gain: track.gain.unwrap_or(0.0),
+            pan: track.pan.unwrap_or(0.0),
+            mute: track.mute.unwrap_or(false),
+            solo: track.solo.unwrap_or(false),
+        }
+    }
+
+    // Level of the group as a factor, silent when muted or when other groups
+    // are soloed. `soloing` is whether any group is soloed.
+    fn level(&self, soloing: bool) -> f32 {
+        if self.mute || (soloing && !self.solo) {
+            0.0
+        } else {
+            10f32.powf(self.gain / 20.0)
+        }
+    }
+}
+
+// Gains of the left and right sides for a stereo position
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let pan = pan.clamp(-1.0, 1.0);
+    let left = if pan > 0.0 { (pan * FRAC_PI_2).cos() } else { 1.0 };
+    let right = if pan < 0.0 { (pan * FRAC_PI_2).cos() } else { 1.0 };
+    (left, right)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    Right,
+    Centre,
+}
+
+// A value moving in a straight line to a new one over a number of frames
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    // At the start of the next frame to be mixed
+    value: f32,
+    target: f32,
+    step: f32,
+    // Frames left in the ramp
+    remaining: u64,
+}
+
+impl Ramp {
+    fn new(value: f32) -> Self {
+        Self { value, target: value, step: 0.0, remaining: 0 }
+    }
+
+    fn at(&self, frame: usize) -> f32 {
+        if (frame as u64) < self.remaining {
+            self.value + self.step * (frame + 1) as f32
+        } else {
+            self.target
+        }
+    }
+
+    // A ramp under way starts again from wherever it has got to
+    fn start(&mut self, target: f32, length: u64) {
+        self.step = (target - self.value) / length as f32;
+        self.target = target;
+        self.remaining = length;
+    }
+
+    fn advance(&mut self, frames: usize) {
+        if frames as u64 >= self.remaining {
+            self.value = self.target;
+            self.remaining = 0;
+        } else {
+            self.value += self.step * frames as f32;
+            self.remaining -= frames as u64;
+        }
+    }
+}
+
+// Value at points in time, in a straight line between them and held before
+// the first point and after the last
+#[derive(Debug, Clone)]
+struct Envelope {
+    points: Vec<AutomationPoint>,
+}
+
+impl Envelope {
+    fn value_at(&self, time: f64) -> Option<f32> {
+        let next = self.points.partition_point(|point| point.time <= time);
+        let previous = next.checked_sub(1).map(|index| self.points[index]);
+
+        match (previous, self.points.get(next).copied()) {
+            (Some(previous), Some(next)) => {
+                let progress = ((time - previous.time) / (next.time - previous.time)) as f32;
+                Some(previous.value + (next.value - previous.value) * progress)
+            }
+            (Some(point), None) | (None, Some(point)) => Some(point.value),
+            (None, None) => None,
+        }
+    }
+}
+
+// One group's mix, with changes ramping in from where it was
+#[derive(Debug, Clone)]
+struct GroupState {
+    mix: GroupMix,
+    level: Ramp,
+    pan: Ramp,
+    volume_envelope: Envelope,
+    pan_envelope: Envelope,
+}
+
+/// Applies every track group's `GroupMix` and automation as the groups are
+/// mixed.
+#[derive(Debug, Clone)]
+pub(crate) struct GroupMixer {
+    sides: Vec<Side>,
+    ramp_length: u64,
+    groups: Vec<GroupState>,
+}
+
+impl GroupMixer {
+    pub fn new(mixes: Vec<GroupMix>, automation: Vec<AutomationSettings>, layout: Channels, sample_rate: u32) -> Self {
+        let soloing = mixes.iter().any(|mix| mix.solo);
+        let groups = mixes
+            .into_iter()
+            .zip(automation.into_iter().chain(std::iter::repeat_with(AutomationSettings::default)))
+            .map(|(mix, automation)| GroupState {
+                mix,
+                level: Ramp::new(mix.level(soloing)),
+                pan: Ramp::new(mix.pan),
+                volume_envelope: Envelope { points: automation.volume },
+                pan_envelope: Envelope { points: automation.pan },
+            })
+            .collect();
+
+        let sides = layout
+            .iter()
+            .map(|channel| {
+                if is_left(channel) {
+                    Side::Left
+                } else if is_right(channel) {
+                    Side::Right
+                } else {
+                    Side::Centre
+                }
+            })
+            .collect();
+
+        Self {
+            sides,
+            ramp_length: ((RAMP_SECONDS * sample_rate as f64).round() as u64).max(1),
+            groups,
+        }
+    }
+
+    pub fn get(&self, key: i32) -> Option<GroupMix> {
+        self.groups.get(key as usize).map(|group| group.mix)
+    }
+
+    /// Sets the mix of one group. When `ramp` is set the group moves to the
+    /// new mix over a short ramp, otherwise it jumps to it.
+    pub fn set(&mut self, key: i32, mix: GroupMix, ramp: bool) {
+        let Some(group) = self.groups.get_mut(key as usize) else {
+            return;
+        };
+        group.mix = mix;
+
+        // Soloing one group changes the level of every other group
+        let soloing = self.groups.iter().any(|group| group.mix.solo);
+        for group in self.groups.iter_mut() {
+            let level = group.mix.level(soloing);
+            if ramp {
+                group.level.start(level, self.ramp_length);
+                group.pan.start(group.mix.pan, self.ramp_length);
+            } else {
+                group.level = Ramp::new(level);
+                group.pan = Ramp::new(group.mix.pan);
+            }
+        }
+    }
+
+    /// Fills `gains` with the gain of each channel for the group `key`,
+    /// `frame` frames into the chunk being mixed and at `time` seconds into
+    /// the song.
+    pub fn frame_gains(&self, key: i32, frame: usize, time: f64, gains: &mut [f32]) {
+        let Some(group) = self.groups.get(key as usize) else {
+            gains.fill(1.0);
+            return;
+        };
+
+        let mut level = group.level.at(frame);
+        if let Some(volume) = group.volume_envelope.value_at(time) {
+            level *= 10f32.powf(volume / 20.0);
+        }
+        let pan = group.pan.at(frame) + group.pan_envelope.value_at(time).unwrap_or(0.0);
+        let (left, right) = pan_gains(pan);
+
+        for (gain, side) in gains.iter_mut().zip(&self.sides) {
+            *gain = match side {
+                Side::Left => level * left,
+                Side::Right => level * right,
+                Side::Centre => level,
+            };
+        }
+    }
+
+    /// Moves every ramp on by the frames just mixed.
+    pub fn advance(&mut self, frames: usize) {
+        for group in self.groups.iter_mut() {
+            group.level.advance(frames);
+            group.pan.advance(frames);
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::warn;
+use rodio::buffer::SamplesBuffer;
+use rodio::cpal::{self, traits::HostTrait};
+use rodio::{DeviceTrait, OutputStream, Sink};
+
+/// Receives the mixed audio of a `Player`. The player appends chunks of
+/// interleaved samples and waits for them to be played through, so an
+/// output only needs to report how many chunks it still holds.
+pub trait AudioOutput: Send {
+    /// Queues a chunk of interleaved samples after the ones before it.
+    fn append(&mut self, samples: Vec<f32>, channels: u16, sample_rate: u32);
+
+    /// Number of chunks appended and not yet played through.
+    fn len(&mut self) -> usize;
+
+    fn is_empty(&mut self) -> bool {
+        self.len() == 0
+    }
+
+    fn play(&mut self);
+
+    fn pause(&mut self);
+
+    fn is_paused(&self) -> bool;
+
+    fn volume(&self) -> f32;
+
+    fn set_volume(&mut self, volume: f32);
+
+    /// Drops every chunk that has not been played.
+    fn clear(&mut self);
+
+    /// Sample rate the output plays at, if it needs one. The player resamples
+    /// its mix to this rate. `None` takes the file's own rate.
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Channels the output plays, if it needs a given number of them. The
+    /// player remixes its mix to them. `None` takes the file's own channels.
+    fn channels(&self) -> Option<u16> {
+        None
+    }
+}
+
+/// Where a `Player` sends its mixed audio.
+#[derive(Clone, Default)]
+pub enum OutputBackend {
+    /// The default audio device, through rodio. When there is none, the
+    /// audio plays to nothing in real time, as with `Null`.
+    #[default]
+    Device,
+    /// No audio device. The audio is thrown away as it is played, `speed`
+    /// times faster than real time.
+    Null { speed: f64 },
+    /// Like `Null`, but every mixed sample is kept in `capture`.
+    Capture { capture: Capture, speed: f64 },
+    /// The host pulls the audio from its own callback. See `HostOutput`.
+    Host(HostOutput),
+    /// Any other output. Called every time playback starts or seeks.
+    Custom(Arc<dyn Fn() -> Box<dyn AudioOutput> + Send + Sync>),
+}
+
+impl OutputBackend {
+    // Opens the output. The device stream is returned separately as it
+    // cannot leave the thread that opened it, and stops when dropped.
+    pub(crate) fn open(&self) -> (Box<dyn AudioOutput>, Option<OutputStream>) {
+        match self {
+            OutputBackend::Device => match RodioOutput::open() {
+                Ok((output, stream)) => (Box::new(output), Some(stream)),
+                Err(err) => {
+                    warn!("could not open the audio device, playing without it: {}", err);
+                    (Box::new(VirtualOutput::new(1.0, None)), None)
+                }
+            },
+            OutputBackend::Null { speed } => (Box::new(VirtualOutput::new(*speed, None)), None),
+            OutputBackend::Capture { capture, speed } => {
+                (Box::new(VirtualOutput::new(*speed, Some(capture.clone()))), None)
+            }
+            OutputBackend::Host(output) => (Box::new(output.clone()), None),
+            OutputBackend::Custom(open) => (open(), None),
+        }
+    }
+}
+
+// Stands in until a playback thread opens the real output
+pub(crate) fn placeholder_output() -> Box<dyn AudioOutput> {
+    Box::new(VirtualOutput::new(1.0, None))
+}
+
+/// Mixed audio recorded by `OutputBackend::Capture`, as interleaved samples
+/// in the file's channels, before the player's volume is applied.
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    samples: Arc<Mutex<Vec<f32>>>,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every sample captured so far.
+    pub fn get_samples(&self) -> Vec<f32> {
+        self.samples.lock().unwrap().clone()
+    }
+
+    /// Removes and returns the samples captured so far.
+    pub fn take_samples(&self) -> Vec<f32> {
+        std::mem::take(&mut *self.samples.lock().unwrap())
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn extend(&self, samples: &[f32]) {
+        self.samples.lock().unwrap().extend_from_slice(samples);
+    }
+}
+
+struct RodioOutput {
+    sink: Sink,
+    // Rate and channels of the device, which rodio would otherwise convert to
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+}
+
+impl RodioOutput {
+    // Opens the default audio device
+    fn open() -> Result<(Self, OutputStream), String> {
+        let (stream, stream_handle) = OutputStream::try_default().map_err(|err| err.to_string())?;
+        let sink = Sink::try_new(&stream_handle).map_err(|err| err.to_string())?;
+        let config = cpal::default_host()
+            .default_output_device()
+            .and_then(|device| device.default_output_config().ok());
+        let output = RodioOutput {
+            sink,
+            sample_rate: config.as_ref().map(|config| config.sample_rate().0),
+            channels: config.as_ref().map(|config| config.channels()),
+        };
+
+        Ok((output, stream))
+    }
+}
+
+impl AudioOutput for RodioOutput {
+    fn append(&mut self, samples: Vec<f32>, channels: u16, sample_rate: u32) {
+        self.sink.append(SamplesBuffer::new(channels, sample_rate, samples));
+    }
+
+    fn len(&mut self) -> usize {
+        self.sink.len()
+    }
+
+    fn play(&mut self) {
+        self.sink.play();
+    }
+
+    fn pause(&mut self) {
+        self.sink.pause();
+    }
+
+    fn is_paused(&self) -> bool {
+        self.sink.is_paused()
+    }
+
+    fn volume(&self) -> f32 {
+        self.sink.volume()
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.sink.set_volume(volume);
+    }
+
+    fn clear(&mut self) {
+        self.sink.clear();
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> Option<u16> {
+        self.channels
+    }
+}
+
+// Plays chunks against the clock instead of a device
+pub(crate) struct VirtualOutput {
+    // Length in seconds of every chunk still to be played
+    queue: VecDeque<f64>,
+    // Seconds already played of the first chunk
+    played: f64,
+    last_update: Instant,
+    paused: bool,
+    volume: f32,
+    speed: f64,
+    capture: Option<Capture>,
+}
+
+impl VirtualOutput {
+    fn new(speed: f64, capture: Option<Capture>) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            played: 0.0,
+            last_update: Instant::now(),
+            paused: false,
+            volume: 1.0,
+            speed,
+            capture,
+        }
+    }
+
+    // Plays through the queue for the time that has passed
+    fn update(&mut self) {
+        let elapsed = self.last_update.elapsed().as_secs_f64();
+        self.last_update = Instant::now();
+
+        if self.paused {
+            return;
+        }
+
+        self.played += elapsed * self.speed;
+        while let Some(length) = self.queue.front() {
+            if self.played < *length {
+                break;
+            }
+
+            self.played -= length;
+            self.queue.pop_front();
+        }
+
+        if self.queue.is_empty() {
+            self.played = 0.0;
+        }
+    }
+}
+
+impl AudioOutput for VirtualOutput {
+    fn append(&mut self, samples: Vec<f32>, channels: u16, sample_rate: u32) {
+        self.update();
+
+        if let Some(capture) = &self.capture {
+            capture.extend(&samples);
+        }
+
+        self.queue.push_back(samples.len() as f64 / channels as f64 / sample_rate as f64);
+    }
+
+    fn len(&mut self) -> usize {
+        self.update();
+        self.queue.len()
+    }
+
+    fn play(&mut self) {
+        self.update();
+        self.paused = false;
+    }
+
+    fn pause(&mut self) {
+        self.update();
+        self.paused = true;
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+        self.played = 0.0;
+    }
+}
+
+/// An output for hosts that run their own audio callback. Pass a clone to the
+/// player as `OutputBackend::Host` and call `fill` from the callback to take
+/// the mixed frames as they are needed.
+#[derive(Debug, Clone, Default)]
+pub struct HostOutput {
+    queue: Arc<Mutex<HostQueue>>,
+}
+
+#[derive(Debug)]
+struct HostQueue {
+    chunks: VecDeque<Vec<f32>>,
+    // Samples already taken from the first chunk
+    offset: usize,
+    paused: bool,
+    volume: f32,
+    channels: u16,
+    sample_rate: u32,
+    // Rate and channels the host asked for
+    requested_sample_rate: Option<u32>,
+    requested_channels: Option<u16>,
+}
+
+impl Default for HostQueue {
+    fn default() -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            offset: 0,
+            paused: false,
+            volume: 1.0,
+            channels: 2,
+            sample_rate: 44_100,
+            requested_sample_rate: None,
+            requested_channels: None,
+        }
+    }
+}
+
+impl HostOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An output whose samples are resampled to `sample_rate`, for hosts
+    /// that run at a fixed rate.
+    pub fn with_sample_rate(sample_rate: u32) -> Self {
+        Self::with_format(Some(sample_rate), None)
+    }
+
+    /// An output whose samples are resampled to `sample_rate` and remixed to
+    /// `channels`, where given, for hosts that run with a fixed format.
+    pub fn with_format(sample_rate: Option<u32>, channels: Option<u16>) -> Self {
+        let output = Self::default();
+        let mut queue = output.queue.lock().unwrap();
+        queue.requested_sample_rate = sample_rate;
+        queue.requested_channels = channels;
+        drop(queue);
+
+        output
+    }
+
+    /// Fills `buffer` with interleaved samples at the player's volume. Where
+    /// nothing is queued, or playback is paused, the buffer is filled with
+    /// silence. Returns how many samples came from the player.
+    pub fn fill(&self, buffer: &mut [f32]) -> usize {
+        let mut queue = self.queue.lock().unwrap();
+        let mut written = 0;
+
+        while written < buffer.len() && !queue.paused {
+            let offset = queue.offset;
+            let volume = queue.volume;
+            let chunk = match queue.chunks.front() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+
+            let count = (chunk.len() - offset).min(buffer.len() - written);
+            for (out, sample) in buffer[written..written + count].iter_mut().zip(&chunk[offset..]) {
+                *out = sample * volume;
+            }
+            written += count;
+
+            if offset + count == chunk.len() {
+                queue.chunks.pop_front();
+                queue.offset = 0;
+            } else {
+                queue.offset += count;
+            }
+        }
+
+        buffer[written..].fill(0.0);
+
+        written
+    }
+
+    /// Channels of the samples given by `fill`.
+    pub fn channels(&self) -> u16 {
+        self.queue.lock().unwrap().channels
+    }
+
+    /// Sample rate of the samples given by `fill`.
+    pub fn sample_rate(&self) -> u32 {
+        self.queue.lock().unwrap().sample_rate
+    }
+}
+
+impl AudioOutput for HostOutput {
+    fn append(&mut self, samples: Vec<f32>, channels: u16, sample_rate: u32) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.channels = channels;
+        queue.sample_rate = sample_rate;
+        queue.chunks.push_back(samples);
+    }
+
+    fn len(&mut self) -> usize {
+        self.queue.lock().unwrap().chunks.len()
+    }
+
+    fn play(&mut self) {
+        self.queue.lock().unwrap().paused = false;
+    }
+
+    fn pause(&mut self) {
+        self.queue.lock().unwrap().paused = true;
+    }
+
+    fn is_paused(&self) -> bool {
+        self.queue.lock().unwrap().paused
+    }
+
+    fn volume(&self) -> f32 {
+        self.queue.lock().unwrap().volume
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.queue.lock().unwrap().volume = volume;
+    }
+
+    fn clear(&mut self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.chunks.clear();
+        queue.offset = 0;
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.queue.lock().unwrap().requested_sample_rate
+    }
+
+    fn channels(&self) -> Option<u16> {
+        self.queue.lock().unwrap().requested_channels
+    }
+}
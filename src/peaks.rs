@@ -1,38 +1,9 @@
-use std::sync::mpsc;
-use std::thread;
-
 use log::warn;
-use symphonia::core::audio::{AudioBufferRef, Signal, Channels};
+use symphonia::core::audio::Channels;
 use symphonia::core::errors::Error;
 
 use crate::tools::*;
-
-fn convert_signed_24bit_to_f32(sample: i32) -> f32 {
-    // Assuming the 24-bit sample is the least significant bits of a 32-bit integer
-    // Shift to get rid of padding/sign-extension if necessary
-    let shifted_sample = sample << 8 >> 8; // Adjust this based on your data's format
-
-    // Normalize to -1.0 to 1.0 range
-    let normalized_sample = shifted_sample as f32 / 2f32.powi(23);
-
-    normalized_sample
-}
-
-fn convert_unsigned_24bit_to_f32(sample: u32) -> f32 {
-    let shifted_sample = sample as i32 - 2i32.pow(23);
-    let normalized_sample = shifted_sample as f32 / 2f32.powi(23);
-    normalized_sample
-}
-
-fn convert_signed_16bit_to_f32(sample: i16) -> f32 {
-    sample as f32 / 2f32.powi(15)
-}
-
-fn convert_unsigned_16bit_to_f32(sample: u16) -> f32 {
-    let shifted_sample = sample as i16 - 2i16.pow(15);
-    let normalized_sample = shifted_sample as f32 / 2f32.powi(15);
-    normalized_sample
-}
+use crate::track::process_channel;
 
 fn find_peaks(samples: &[f32], window_size: usize) -> Vec<(f32, f32)> {
     samples
@@ -45,147 +16,78 @@ fn find_peaks(samples: &[f32], window_size: usize) -> Vec<(f32, f32)> {
         .collect()
 }
 
-pub fn get_peaks(file_path: &str, limited: bool) -> Vec<Vec<(f32, f32)>> {
-    let (mut decoder, mut format) = open_file(file_path);
-    // let (sender, receiver) = mpsc::sync_channel::<Peak>(1);
-    let (sender, receiver) = mpsc::sync_channel::<(usize, Vec<f32>)>(1);
-
-    let sample_rate = format
-        .tracks()
-        .first()
-        .unwrap()
-        .codec_params
-        .sample_rate
-        .unwrap();
-
-    let channels = if limited {
-        1
-    } else {
-        let channels_option = &format.tracks().first().unwrap().codec_params.channels.unwrap_or(Channels::FRONT_CENTRE);
-        channels_option.iter().count()
-    };
-
-    // let channels = match format.tracks().first().unwrap().codec_params.channel_layout.unwrap_or(Layout::Mono) {
-    //     Layout::Mono => 1,
-    //     Layout::Stereo => 2,
-    //     Layout::TwoPointOne => 3,
-    //     Layout::FivePointOne => 6,
-    // };
-
-    thread::spawn(move || {
-        // If not explicitly specified, use the first audio track.
-        let track_id = format.tracks().first().unwrap().id;
-
-        // let track_id = track_id.unwrap_or_else(|| {
-        //     format.tracks().iter().find(|track| track.codec_params. == symphonia::core::media::Type::Audio)
-        //         .expect("no audio track found").id
-        // });
-
-        // Get the selected track using the track ID.
-        // let track = format.tracks().iter().find(|track| track.id == track_id).expect("no track found");
-
-        // TODO: Use actual time?
-        // let actual_time = track.codec_params.time_base.unwrap().calc_time(seek_success.unwrap().actual_ts);
-
-        let _result: Result<bool, Error> = loop {
-            // Get the next packet from the format reader.
-            let packet = match format.next_packet() {
-                Ok(packet) => packet,
-                Err(err) => break Err(err),
-            };
-
-            if packet.track_id() != track_id {
-                continue;
-            }
-
-            let process_channel = |decoded, channel| {
-                match decoded {
-                    AudioBufferRef::U16(buf) => buf
-                            .chan(channel)
-                            .to_vec()
-                            .into_iter()
-                            .map(|s| convert_unsigned_16bit_to_f32(s))
-                            .collect(),
+/// Samples of one decoded track, one vector per channel.
+pub(crate) struct DecodedChannels {
+    pub sample_rate: u32,
+    pub channels: Channels,
+    pub samples: Vec<Vec<f32>>,
+}
 
-                    AudioBufferRef::S16(buf) => buf
-                            .chan(channel)
-                            .to_vec()
-                            .into_iter()
-                            .map(|s| convert_signed_16bit_to_f32(s))
-                            .collect(),
+/// Decodes a whole track, the first one in the file when `track_id` is
+/// `None`.
+pub(crate) fn decode_channels(file_path: &str, track_id: Option<u32>) -> DecodedChannels {
+    let mut format = get_reader(file_path);
+    let track_id = track_id.unwrap_or_else(|| format.tracks().first().unwrap().id);
+    let mut decoder = get_track_decoder(format.as_ref(), track_id).expect("unsupported codec");
 
-                    AudioBufferRef::U24(buf) => buf
-                            .chan(channel)
-                            .to_vec()
-                            .into_iter()
-                            .map(|s| convert_unsigned_24bit_to_f32(s.0))
-                            .collect(),
+    let codec_params = &format.tracks().iter().find(|track| track.id == track_id).unwrap().codec_params;
+    let sample_rate = codec_params.sample_rate.unwrap();
+    let mut channels = codec_params.channels.unwrap_or(Channels::FRONT_CENTRE);
+    let mut samples: Vec<Vec<f32>> = Vec::new();
 
-                    AudioBufferRef::S24(buf) => buf
-                            .chan(channel)
-                            .to_vec()
-                            .into_iter()
-                            .map(|s| convert_signed_24bit_to_f32(s.0))
-                            .collect(),
+    let result: Result<(), Error> = loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(err) => break Err(err),
+        };
 
-                    AudioBufferRef::F32(buf) => buf.chan(0).to_vec().into_iter().collect(),
-                    _ => {
-                        // Repeat for the different sample formats.
-                        unimplemented!();
-                        return Vec::new();
-                    }
-                }
-            };
+        if packet.track_id() != track_id {
+            continue;
+        }
 
-            match decoder.decode(&packet) {
-                Ok(decoded) => {
-                    for channel in 0..channels {
-                        // println!("channel: {}", channel);
-                        let samples = process_channel(decoded.clone(), channel);
-                        sender.send((channel, samples)).unwrap();
-                    }
-                }
-                Err(Error::DecodeError(err)) => {
-                    // Decode errors are not fatal. Print the error message and try to decode the next
-                    // packet as usual.
-                    warn!("decode error: {}", err);
+        match decoder.decode(&packet) {
+            Ok(decoded) => {
+                // The decoder knows the channels where the container may not
+                channels = decoded.spec().channels;
+                samples.resize(decoded.spec().channels.count(), Vec::new());
+                for (channel, channel_samples) in samples.iter_mut().enumerate() {
+                    channel_samples.extend(process_channel(decoded.clone(), channel));
                 }
-                Err(err) => break Err(err),
             }
-        };
-
-        // If an error occurred, print the error message.
-        if let Err(err) = _result {
-            warn!("error: {}", err);
+            Err(Error::DecodeError(err)) => {
+                // Decode errors are not fatal. Print the error message and try to decode the next
+                // packet as usual.
+                warn!("decode error: {}", err);
+            }
+            Err(err) => break Err(err),
         }
+    };
 
-        // Mark the track as finished
-        // mark_track_as_finished(&mut finished_tracks.clone(), track_key);
-    });
-
-    let mut samples: Vec<Vec<f32>> = Vec::new();
-    // let mut peaks: Vec<(f64, f32)> = Vec::new();
-
-    for peak in receiver {
-        // if peak.channel != 0 {
-        //     continue;
-        // }
-
-        if samples.len() <= peak.0 {
-            samples.push(peak.1);
-        } else {
-            samples[peak.0].extend(peak.1);
-        };
-
-        // peaks.push((peak.time, peak.sample));
+    match result {
+        Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {}
+        Err(err) => warn!("error: {}", err),
+        Ok(()) => {}
     }
 
-    let mut peaks = Vec::new();
+    DecodedChannels { sample_rate, channels, samples }
+}
 
-    for channel in samples {
-        let channel_peaks = find_peaks(&channel, sample_rate as usize / 100);
-        peaks.push(channel_peaks);
-    }
+pub fn get_peaks(file_path: &str, limited: bool) -> Vec<Vec<(f32, f32)>> {
+    let format = get_reader(file_path);
+    let channels = if limited {
+        1
+    } else {
+        let channels_option = &format.tracks().first().unwrap().codec_params.channels.unwrap_or(Channels::FRONT_CENTRE);
+        channels_option.iter().count()
+    };
+    drop(format);
+
+    let decoded = decode_channels(file_path, None);
 
-    peaks
+    decoded
+        .samples
+        .iter()
+        .take(channels)
+        .map(|channel| find_peaks(channel, decoded.sample_rate as usize / 100))
+        .collect()
 }
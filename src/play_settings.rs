@@ -0,0 +1,604 @@
+use std::fmt;
+
+use matroska::Matroska;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::constants::*;
+use crate::selection::is_satisfiable;
+
+#[derive(Debug)]
+pub enum PlaySettingsError {
+    Io(std::io::Error),
+    Container(String),
+    MissingAttachment,
+    UnknownAttachment(String),
+    InvalidAttachment { name: String, reason: String },
+    Json(serde_json::Error),
+    MissingField(&'static str),
+    InvalidField { field: &'static str, reason: String },
+    InvalidTrack { index: usize, reason: String },
+    UnknownTrackId { index: usize, id: u32 },
+    InvalidWeights { index: usize, reason: String },
+    InvalidRule { index: usize, reason: String },
+    Unsatisfiable,
+}
+
+impl fmt::Display for PlaySettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaySettingsError::Io(err) => write!(f, "could not open file: {}", err),
+            PlaySettingsError::Container(err) => write!(f, "could not read container: {}", err),
+            PlaySettingsError::MissingAttachment => {
+                write!(f, "file has no {} attachment", PLAY_SETTINGS_FILE_NAME)
+            }
+            PlaySettingsError::UnknownAttachment(name) => write!(
+                f,
+                "{} refers to attachment {}, which is not in the container",
+                PLAY_SETTINGS_FILE_NAME, name
+            ),
+            PlaySettingsError::InvalidAttachment { name, reason } => {
+                write!(f, "attachment {} is invalid: {}", name, reason)
+            }
+            PlaySettingsError::Json(err) => {
+                write!(f, "{} is not valid json: {}", PLAY_SETTINGS_FILE_NAME, err)
+            }
+            PlaySettingsError::MissingField(field) => {
+                write!(f, "{} is missing \"{}\"", PLAY_SETTINGS_FILE_NAME, field)
+            }
+            PlaySettingsError::InvalidField { field, reason } => {
+                write!(f, "{} has an invalid \"{}\": {}", PLAY_SETTINGS_FILE_NAME, field, reason)
+            }
+            PlaySettingsError::InvalidTrack { index, reason } => {
+                write!(f, "track {} in {} is invalid: {}", index, PLAY_SETTINGS_FILE_NAME, reason)
+            }
+            PlaySettingsError::UnknownTrackId { index, id } => write!(
+                f,
+                "track {} in {} refers to id {}, which is not in the container",
+                index, PLAY_SETTINGS_FILE_NAME, id
+            ),
+            PlaySettingsError::InvalidWeights { index, reason } => {
+                write!(f, "track {} has invalid weights: {}", index, reason)
+            }
+            PlaySettingsError::InvalidRule { index, reason } => {
+                write!(f, "rule {} in {} is invalid: {}", index, PLAY_SETTINGS_FILE_NAME, reason)
+            }
+            PlaySettingsError::Unsatisfiable => {
+                write!(f, "no combination of takes satisfies the rules in {}", PLAY_SETTINGS_FILE_NAME)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PlaySettingsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PlaySettingsError::Io(err) => Some(err),
+            PlaySettingsError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PlaySettingsError {
+    fn from(err: std::io::Error) -> Self {
+        PlaySettingsError::Io(err)
+    }
+}
+
+/// Legacy (v0) settings, written before `encoder_version` existed. Each track
+/// group is a contiguous run of container tracks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaySettingsV0 {
+    pub tracks: Vec<TrackSettingsV0>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackSettingsV0 {
+    #[serde(rename = "startingIndex")]
+    pub starting_index: u32,
+    pub length: u32,
+}
+
+/// Current (v1+) settings. Each track group lists the container track ids it
+/// can choose from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlaySettingsV1 {
+    pub tracks: Vec<TrackSettingsV1>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+    /// Song sections, each with its own choice of takes. The whole song is
+    /// one section when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sections: Vec<SectionSettings>,
+    /// Length in seconds of the crossfade where a group switches takes
+    /// between sections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crossfade: Option<f64>,
+    /// Loudness, in LU, a take may be from the average of its group's takes,
+    /// and a combination from every group at its average, before loudness
+    /// normalization turns it up or down.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loudness_window: Option<f32>,
+    /// Convolution reverb over the whole mix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverb: Option<ReverbSettings>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SectionSettings {
+    /// Start of the section in seconds. A section lasts until the next one
+    /// starts, and the first section always starts at the top of the song.
+    pub start: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrackSettingsV1 {
+    pub ids: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Relative likelihood of each id being chosen. Every id is equally
+    /// likely when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weights: Option<Vec<f32>>,
+    /// Chance, from 0 to 1, that the group plays at all. The group always
+    /// plays when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence: Option<f32>,
+    /// Level of the group in the mix, in dB. 0 dB when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gain: Option<f32>,
+    /// Stereo position, from -1 (left) to 1 (right). Centred when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pan: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub solo: Option<bool>,
+    /// Gain in dB of each id, in the same order, on top of the group's
+    /// gain. Every take plays at 0 dB when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub take_gains: Option<Vec<f32>>,
+    /// Volume and pan over the course of the song.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub automation: Option<AutomationSettings>,
+    /// Integrated loudness in LUFS of each id, in the same order, as
+    /// `loudness::measure_loudness` finds it. `None` for silent takes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loudness: Option<Vec<Option<f32>>>,
+}
+
+/// Envelopes a track group follows over the song. Between points the value
+/// moves in a straight line, and it holds before the first point and after
+/// the last.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AutomationSettings {
+    /// Gain in dB, added to the group's gain.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volume: Vec<AutomationPoint>,
+    /// Stereo position from -1 to 1, added to the group's pan.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pan: Vec<AutomationPoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AutomationPoint {
+    /// Time in seconds from the top of the song.
+    pub time: f64,
+    pub value: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReverbSettings {
+    /// File name of the attachment holding the impulse response, a WAV or
+    /// FLAC file.
+    pub impulse_response: String,
+    /// Level of the reverb, from 0 to 1. 0.3 when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wet: Option<f32>,
+    /// Level of the mix without the reverb, from 0 to 1. Full level when
+    /// missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry: Option<f32>,
+}
+
+/// A constraint on which takes may play together. Takes are container track
+/// ids, so a rule applies whichever group the take was chosen in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Rule {
+    /// Whenever `take` plays, at least one of `any_of` plays too.
+    Requires { take: u32, any_of: Vec<u32> },
+    /// No two of `takes` play together.
+    Excludes { takes: Vec<u32> },
+}
+
+#[derive(Debug, Clone)]
+pub enum PlaySettings {
+    V0(PlaySettingsV0),
+    V1 {
+        encoder_version: f64,
+        settings: PlaySettingsV1,
+    },
+}
+
+impl From<PlaySettingsV0> for PlaySettingsV1 {
+    fn from(legacy: PlaySettingsV0) -> Self {
+        // Legacy indexes are zero based while container track ids start at 1
+        let tracks = legacy
+            .tracks
+            .into_iter()
+            .map(|track| {
+                let first_id = track.starting_index + 1;
+                TrackSettingsV1 {
+                    ids: (first_id..(first_id + track.length)).collect(),
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        Self {
+            tracks,
+            ..Default::default()
+        }
+    }
+}
+
+impl PlaySettings {
+    pub fn from_slice(data: &[u8]) -> Result<Self, PlaySettingsError> {
+        let json_data: Value = serde_json::from_slice(data).map_err(PlaySettingsError::Json)?;
+
+        let play_settings = match json_data.get("play_settings") {
+            Some(play_settings) => play_settings.clone(),
+            None => return Err(PlaySettingsError::MissingField("play_settings")),
+        };
+
+        if play_settings.get("tracks").is_none() {
+            return Err(PlaySettingsError::MissingField("play_settings.tracks"));
+        }
+
+        let encoder_version = match json_data.get("encoder_version") {
+            None | Some(Value::Null) => None,
+            Some(Value::Number(version)) => version.as_f64(),
+            Some(Value::String(version)) => match version.parse::<f64>() {
+                Ok(version) => Some(version),
+                Err(_) => {
+                    return Err(PlaySettingsError::InvalidField {
+                        field: "encoder_version",
+                        reason: format!("\"{}\" is not a number", version),
+                    })
+                }
+            },
+            Some(other) => {
+                return Err(PlaySettingsError::InvalidField {
+                    field: "encoder_version",
+                    reason: format!("expected a number, found {}", other),
+                })
+            }
+        };
+
+        match encoder_version {
+            Some(encoder_version) => {
+                parse_rules(&play_settings)?;
+                let settings: PlaySettingsV1 = parse_tracks(play_settings)?;
+                Ok(PlaySettings::V1 { encoder_version, settings })
+            }
+            None => {
+                let settings: PlaySettingsV0 = parse_tracks(play_settings)?;
+                Ok(PlaySettings::V0(settings))
+            }
+        }
+    }
+
+    pub fn from_file(file_path: &str) -> Result<Self, PlaySettingsError> {
+        let file = std::fs::File::open(file_path)?;
+
+        let mka: Matroska = Matroska::open(file)
+            .map_err(|err| PlaySettingsError::Container(format!("{:?}", err)))?;
+
+        let attachment = mka
+            .attachments
+            .iter()
+            .find(|attachment| attachment.name == PLAY_SETTINGS_FILE_NAME)
+            .ok_or(PlaySettingsError::MissingAttachment)?;
+
+        Self::from_slice(&attachment.data)
+    }
+
+    /// Contents of the attachment called `name`, such as the impulse
+    /// response of the reverb.
+    pub fn read_attachment(file_path: &str, name: &str) -> Result<Vec<u8>, PlaySettingsError> {
+        let file = std::fs::File::open(file_path)?;
+
+        let mka: Matroska = Matroska::open(file)
+            .map_err(|err| PlaySettingsError::Container(format!("{:?}", err)))?;
+
+        mka.attachments
+            .into_iter()
+            .find(|attachment| attachment.name == name)
+            .map(|attachment| attachment.data)
+            .ok_or_else(|| PlaySettingsError::UnknownAttachment(name.to_string()))
+    }
+
+    pub fn encoder_version(&self) -> Option<f64> {
+        match self {
+            PlaySettings::V0(_) => None,
+            PlaySettings::V1 { encoder_version, .. } => Some(*encoder_version),
+        }
+    }
+
+    /// Migrates the settings to the current version.
+    pub fn migrate(self) -> PlaySettingsV1 {
+        match self {
+            PlaySettings::V0(legacy) => legacy.into(),
+            PlaySettings::V1 { settings, .. } => settings,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            PlaySettings::V0(legacy) => serde_json::json!({
+                "play_settings": legacy,
+            }),
+            PlaySettings::V1 { encoder_version, settings } => serde_json::json!({
+                "encoder_version": encoder_version,
+                "play_settings": settings,
+            }),
+        }
+    }
+}
+
+impl PlaySettingsV1 {
+    /// Checks that every id refers to a track that exists in the container,
+    /// that weights, where given, line up with the ids and that the rules
+    /// allow at least one combination.
+    pub fn validate(&self, available_ids: &[u32]) -> Result<(), PlaySettingsError> {
+        for (index, track) in self.tracks.iter().enumerate() {
+            if let Some(id) = track.ids.iter().find(|id| !available_ids.contains(id)) {
+                return Err(PlaySettingsError::UnknownTrackId { index, id: *id });
+            }
+
+            if let Some(weights) = &track.weights {
+                check_weights(weights, track.ids.len())
+                    .map_err(|reason| PlaySettingsError::InvalidWeights { index, reason })?;
+            }
+
+            if let Some(presence) = track.presence {
+                if !(0.0..=1.0).contains(&presence) {
+                    return Err(PlaySettingsError::InvalidTrack {
+                        index,
+                        reason: format!("presence must be between 0 and 1, found {}", presence),
+                    });
+                }
+            }
+
+            if let Some(pan) = track.pan {
+                if !(-1.0..=1.0).contains(&pan) {
+                    return Err(PlaySettingsError::InvalidTrack {
+                        index,
+                        reason: format!("pan must be between -1 and 1, found {}", pan),
+                    });
+                }
+            }
+
+            if let Some(take_gains) = &track.take_gains {
+                if take_gains.len() != track.ids.len() {
+                    return Err(PlaySettingsError::InvalidTrack {
+                        index,
+                        reason: format!("expected {} take gains, found {}", track.ids.len(), take_gains.len()),
+                    });
+                }
+            }
+
+            if let Some(loudness) = &track.loudness {
+                if loudness.len() != track.ids.len() {
+                    return Err(PlaySettingsError::InvalidTrack {
+                        index,
+                        reason: format!("expected {} loudness values, found {}", track.ids.len(), loudness.len()),
+                    });
+                }
+            }
+
+            if let Some(automation) = &track.automation {
+                check_automation(automation).map_err(|reason| PlaySettingsError::InvalidTrack { index, reason })?;
+            }
+        }
+
+        let mut previous_start = None;
+        for section in &self.sections {
+            if !section.start.is_finite() || section.start < 0.0 {
+                return Err(PlaySettingsError::InvalidField {
+                    field: "play_settings.sections",
+                    reason: format!("{} is not a valid start time", section.start),
+                });
+            }
+
+            if previous_start.is_some_and(|previous| section.start <= previous) {
+                return Err(PlaySettingsError::InvalidField {
+                    field: "play_settings.sections",
+                    reason: String::from("sections must be in order of start time"),
+                });
+            }
+
+            previous_start = Some(section.start);
+        }
+
+        if let Some(crossfade) = self.crossfade {
+            if !crossfade.is_finite() || crossfade < 0.0 {
+                return Err(PlaySettingsError::InvalidField {
+                    field: "play_settings.crossfade",
+                    reason: format!("{} is not a valid length", crossfade),
+                });
+            }
+        }
+
+        if let Some(loudness_window) = self.loudness_window {
+            if !loudness_window.is_finite() || loudness_window < 0.0 {
+                return Err(PlaySettingsError::InvalidField {
+                    field: "play_settings.loudness_window",
+                    reason: format!("{} is not a valid window", loudness_window),
+                });
+            }
+        }
+
+        if let Some(reverb) = &self.reverb {
+            check_reverb(reverb).map_err(|reason| PlaySettingsError::InvalidField {
+                field: "play_settings.reverb",
+                reason,
+            })?;
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            check_rule(rule, &self.tracks).map_err(|reason| PlaySettingsError::InvalidRule { index, reason })?;
+        }
+
+        if !is_satisfiable(&self.tracks, &self.rules) {
+            return Err(PlaySettingsError::Unsatisfiable);
+        }
+
+        Ok(())
+    }
+
+    /// Start and end, in seconds, of every section. The last section has no
+    /// end.
+    pub fn get_section_bounds(&self) -> Vec<(f64, Option<f64>)> {
+        if self.sections.is_empty() {
+            return vec![(0.0, None)];
+        }
+
+        (0..self.sections.len())
+            .map(|index| {
+                let start = if index == 0 { 0.0 } else { self.sections[index].start };
+                let end = self.sections.get(index + 1).map(|section| section.start);
+                (start, end)
+            })
+            .collect()
+    }
+}
+
+/// Whether the group can be left out of a selection.
+pub fn is_optional(track: &TrackSettingsV1) -> bool {
+    match track.presence {
+        Some(presence) => presence < 1.0,
+        None => false,
+    }
+}
+
+/// Checks that there is one usable weight per take.
+pub fn check_weights(weights: &[f32], take_count: usize) -> Result<(), String> {
+    if weights.len() != take_count {
+        return Err(format!("expected {} weights, found {}", take_count, weights.len()));
+    }
+
+    if let Some(weight) = weights.iter().find(|weight| !weight.is_finite() || **weight < 0.0) {
+        return Err(format!("{} is not a positive number", weight));
+    }
+
+    if take_count > 0 && weights.iter().sum::<f32>() <= 0.0 {
+        return Err(String::from("at least one weight must be above zero"));
+    }
+
+    Ok(())
+}
+
+fn check_automation(automation: &AutomationSettings) -> Result<(), String> {
+    for (name, points) in [("volume", &automation.volume), ("pan", &automation.pan)] {
+        let mut previous_time = None;
+        for point in points {
+            if !point.time.is_finite() || point.time < 0.0 {
+                return Err(format!("{} is not a valid time for {} automation", point.time, name));
+            }
+
+            if previous_time.is_some_and(|previous| point.time < previous) {
+                return Err(format!("{} automation must be in order of time", name));
+            }
+
+            previous_time = Some(point.time);
+        }
+    }
+
+    if let Some(point) = automation.pan.iter().find(|point| !(-1.0..=1.0).contains(&point.value)) {
+        return Err(format!("pan automation must be between -1 and 1, found {}", point.value));
+    }
+
+    Ok(())
+}
+
+fn check_reverb(reverb: &ReverbSettings) -> Result<(), String> {
+    if reverb.impulse_response.is_empty() || reverb.impulse_response == PLAY_SETTINGS_FILE_NAME {
+        return Err(format!("\"{}\" is not an impulse response attachment", reverb.impulse_response));
+    }
+
+    for (name, level) in [("wet", reverb.wet), ("dry", reverb.dry)] {
+        if let Some(level) = level.filter(|level| !(0.0..=1.0).contains(level)) {
+            return Err(format!("{} must be between 0 and 1, found {}", name, level));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_rule(rule: &Rule, tracks: &[TrackSettingsV1]) -> Result<(), String> {
+    let ids = match rule {
+        Rule::Requires { any_of, .. } if any_of.is_empty() => {
+            return Err(String::from("\"any_of\" needs at least one take"))
+        }
+        Rule::Excludes { takes } if takes.len() < 2 => {
+            return Err(String::from("\"takes\" needs at least two takes"))
+        }
+        Rule::Requires { take, any_of } => std::iter::once(take).chain(any_of).collect::<Vec<_>>(),
+        Rule::Excludes { takes } => takes.iter().collect(),
+    };
+
+    match ids.into_iter().find(|id| !tracks.iter().any(|track| track.ids.contains(id))) {
+        Some(id) => Err(format!("id {} is not in any track", id)),
+        None => Ok(()),
+    }
+}
+
+fn parse_rules(play_settings: &Value) -> Result<(), PlaySettingsError> {
+    // Parse each rule on its own so that errors can point at the offending entry
+    match play_settings.get("rules") {
+        None | Some(Value::Null) => Ok(()),
+        Some(Value::Array(rules)) => {
+            for (index, rule) in rules.iter().enumerate() {
+                if let Err(err) = serde_json::from_value::<Rule>(rule.clone()) {
+                    return Err(PlaySettingsError::InvalidRule {
+                        index,
+                        reason: err.to_string(),
+                    });
+                }
+            }
+
+            Ok(())
+        }
+        Some(_) => Err(PlaySettingsError::InvalidField {
+            field: "play_settings.rules",
+            reason: String::from("expected an array"),
+        }),
+    }
+}
+
+fn parse_tracks<T: for<'de> Deserialize<'de>>(play_settings: Value) -> Result<T, PlaySettingsError> {
+    // Parse each track on its own first so that errors can point at the offending entry
+    if let Some(tracks) = play_settings["tracks"].as_array() {
+        for (index, track) in tracks.iter().enumerate() {
+            let single = serde_json::json!({ "tracks": [track] });
+            if let Err(err) = serde_json::from_value::<T>(single) {
+                return Err(PlaySettingsError::InvalidTrack {
+                    index,
+                    reason: err.to_string(),
+                });
+            }
+        }
+    } else {
+        return Err(PlaySettingsError::InvalidField {
+            field: "play_settings.tracks",
+            reason: String::from("expected an array"),
+        });
+    }
+
+    serde_json::from_value(play_settings).map_err(PlaySettingsError::Json)
+}
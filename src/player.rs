@@ -1,14 +1,26 @@
 use rodio::buffer::SamplesBuffer;
-use rodio::{OutputStream, Sink};
+use rodio::Source;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use crate::prot::Prot;
+use crate::constants::{DEFAULT_REVERB_DRY, DEFAULT_REVERB_WET};
+use crate::effects::{ImpulseResponse, ImpulseResponseError, Reverb};
+use crate::limiter::DEFAULT_CEILING;
+use crate::mixer::GroupMix;
+use crate::output::{placeholder_output, AudioOutput, OutputBackend};
+use crate::play_settings::PlaySettingsError;
+use crate::prot::{CombinationError, Prot, SelectionError};
+use crate::resample::ResampleQuality;
 use crate::{info::Info, player_engine::PlayerEngine};
 use crate::timer;
 
+// Mixed chunks the sink may hold before the engine is made to wait. Keeping
+// the engine close to the playhead means a new selection is heard soon after
+// it is made.
+const MAX_QUEUED_CHUNKS: usize = 2;
+
 #[derive(Clone)]
 pub struct Player {
     pub info: Info,
@@ -22,45 +34,76 @@ pub struct Player {
     prot: Arc<Mutex<Prot>>,
     audio_heard: Arc<AtomicBool>,
     volume: Arc<Mutex<f32>>,
-    sink: Arc<Mutex<Sink>>,
+    sink: Arc<Mutex<Box<dyn AudioOutput>>>,
+    backend: OutputBackend,
+    engine: Arc<Mutex<Option<PlayerEngine>>>,
+    reshuffle_crossfade: Arc<Mutex<Option<f64>>>,
+    resample_quality: Arc<Mutex<ResampleQuality>>,
+    normalize_loudness: Arc<Mutex<bool>>,
+    limiter_ceiling: Arc<Mutex<Option<f32>>>,
+    reverb: Arc<Mutex<Option<Reverb>>>,
+    group_mixes: Arc<Mutex<Vec<GroupMix>>>,
 }
 
 impl Player {
     pub fn new(file_path: &String) -> Self {
-        let info = Info::new(file_path.clone());
-        let prot = Arc::new(Mutex::new(Prot::new(file_path)));
+        let prot = Prot::new(file_path);
+        let info = prot.info.clone();
+        let prot = Arc::new(Mutex::new(prot));
 
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink: Arc<Mutex<Sink>> = Arc::new(Mutex::new(Sink::try_new(&stream_handle).unwrap()));
+        Self::from_prot(info, prot, OutputBackend::Device)
+    }
 
-        let mut this = Self {
-            info,
-            finished_tracks: Arc::new(Mutex::new(Vec::new())),
-            playing: Arc::new(AtomicBool::new(false)),
-            paused: Arc::new(AtomicBool::new(false)),
-            ts: Arc::new(Mutex::new(0.0)),
-            playback_thread_exists: Arc::new(AtomicBool::new(true)),
-            duration: Arc::new(Mutex::new(0.0)),
-            stop: Arc::new(AtomicBool::new(false)),
-            audio_heard: Arc::new(AtomicBool::new(false)),
-            volume: Arc::new(Mutex::new(0.8)),
-            sink,
-            prot,
-        };
+    pub fn try_new(file_path: &str) -> Result<Self, PlaySettingsError> {
+        Self::try_new_with_seed(file_path, None)
+    }
 
-        this.initialize_thread(None);
+    pub fn try_new_with_seed(file_path: &str, seed: Option<u64>) -> Result<Self, PlaySettingsError> {
+        Self::try_new_with_output(file_path, seed, OutputBackend::Device)
+    }
 
-        this
+    /// Like `try_new_with_seed`, playing to `backend` instead of the default
+    /// audio device.
+    pub fn try_new_with_output(
+        file_path: &str,
+        seed: Option<u64>,
+        backend: OutputBackend,
+    ) -> Result<Self, PlaySettingsError> {
+        let prot = Prot::try_new_with_seed(file_path, seed)?;
+        let info = prot.info.clone();
+        let prot = Arc::new(Mutex::new(prot));
+
+        Ok(Self::from_prot(info, prot, backend))
     }
 
     pub fn new_from_file_paths(file_paths: &Vec<Vec<String>>) -> Self {
-        let prot = Arc::new(Mutex::new(Prot::new_from_file_paths(file_paths)));
+        Self::new_from_file_paths_with_seed(file_paths, None)
+    }
+
+    pub fn new_from_file_paths_with_seed(file_paths: &Vec<Vec<String>>, seed: Option<u64>) -> Self {
+        Self::new_from_file_paths_with_output(file_paths, seed, OutputBackend::Device)
+    }
+
+    /// Like `new_from_file_paths_with_seed`, playing to `backend` instead of
+    /// the default audio device.
+    pub fn new_from_file_paths_with_output(
+        file_paths: &Vec<Vec<String>>,
+        seed: Option<u64>,
+        backend: OutputBackend,
+    ) -> Self {
+        let prot = Arc::new(Mutex::new(Prot::new_from_file_paths_with_seed(file_paths, seed)));
         let locked_prot = prot.lock().unwrap();
         let info = Info::new_from_file_paths(locked_prot.get_file_paths_dictionary());
         drop(locked_prot);
-        
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink: Arc<Mutex<Sink>> = Arc::new(Mutex::new(Sink::try_new(&stream_handle).unwrap()));
+
+        Self::from_prot(info, prot, backend)
+    }
+
+    fn from_prot(info: Info, prot: Arc<Mutex<Prot>>, backend: OutputBackend) -> Self {
+        // The playback thread opens the real output
+        let sink: Arc<Mutex<Box<dyn AudioOutput>>> = Arc::new(Mutex::new(placeholder_output()));
+        let group_mixes = prot.lock().unwrap().get_group_mixes();
+        let reverb = prot.lock().unwrap().get_reverb();
 
         let mut this = Self {
             info,
@@ -74,13 +117,20 @@ impl Player {
             audio_heard: Arc::new(AtomicBool::new(false)),
             volume: Arc::new(Mutex::new(0.8)),
             sink,
+            backend,
             prot,
+            engine: Arc::new(Mutex::new(None)),
+            reshuffle_crossfade: Arc::new(Mutex::new(None)),
+            resample_quality: Arc::new(Mutex::new(ResampleQuality::default())),
+            normalize_loudness: Arc::new(Mutex::new(false)),
+            limiter_ceiling: Arc::new(Mutex::new(Some(DEFAULT_CEILING))),
+            reverb: Arc::new(Mutex::new(reverb)),
+            group_mixes: Arc::new(Mutex::new(group_mixes)),
         };
 
         this.initialize_thread(None);
 
         this
-
     }
 
     fn initialize_thread(&mut self, ts: Option<f64>) {
@@ -106,7 +156,13 @@ impl Player {
         let audio_heard = self.audio_heard.clone();
         let volume = self.volume.clone();
         let sink_mutex = self.sink.clone();
-        let channels = 1.0 * self.info.channels as f64;
+        let backend = self.backend.clone();
+        let engine_mutex = self.engine.clone();
+        let resample_quality = *self.resample_quality.lock().unwrap();
+        let normalize_loudness = *self.normalize_loudness.lock().unwrap();
+        let limiter_ceiling = *self.limiter_ceiling.lock().unwrap();
+        let reverb = self.reverb.lock().unwrap().clone();
+        let group_mixes = self.group_mixes.clone();
 
         audio_heard.store(false, Ordering::Relaxed);
 
@@ -125,12 +181,22 @@ impl Player {
                 Some(ts) => ts,
                 None => 0.0,
             };
+            let (output, _stream) = backend.open();
             let mut engine = PlayerEngine::new(prot, Some(abort.clone()), start_time);
-            let (_stream, stream_handle) = OutputStream::try_default().unwrap();
+            engine.set_output_sample_rate(output.sample_rate());
+            engine.set_output_channels(output.channels());
+            engine.set_resample_quality(resample_quality);
+            engine.set_loudness_normalization(normalize_loudness);
+            engine.set_limiter_ceiling(limiter_ceiling);
+            engine.set_reverb(reverb);
+            for (key, group_mix) in group_mixes.lock().unwrap().iter().enumerate() {
+                engine.set_group_mix(key as i32, *group_mix);
+            }
+            *engine_mutex.lock().unwrap() = Some(engine.clone());
             // let sink_mutex = Arc::new(Mutex::new(Sink::try_new(&stream_handle).unwrap()));
 
             let mut sink = sink_mutex.lock().unwrap();
-            *sink = Sink::try_new(&stream_handle).unwrap();
+            *sink = output;
             sink.set_volume(*volume.lock().unwrap());
             sink.play();
             drop(sink);
@@ -150,7 +216,7 @@ impl Player {
             *time_passed_unlocked = start_time;
             drop(time_passed_unlocked);
 
-            let pause_sink = |sink: &Sink, fade_length_in_seconds: f32| {
+            let pause_sink = |sink: &mut Box<dyn AudioOutput>, fade_length_in_seconds: f32| {
                 let timestamp = *time_passed.lock().unwrap();
 
                 let fade_increments = sink.volume() / (fade_length_in_seconds * 100.0);
@@ -162,7 +228,7 @@ impl Player {
                 sink.pause();
             };
 
-            let resume_sink = |sink: &Sink, fade_length_in_seconds: f32| {
+            let resume_sink = |sink: &mut Box<dyn AudioOutput>, fade_length_in_seconds: f32| {
                 let volume = *volume.lock().unwrap();
                 let fade_increments = (volume - sink.volume()) / (fade_length_in_seconds * 100.0);
                 // Fade in and play sink
@@ -178,20 +244,20 @@ impl Player {
             // ===================== //
             let check_details = || {
                 if abort.load(Ordering::SeqCst) {
-                    let sink = sink_mutex.lock().unwrap();
-                    pause_sink(&sink, 0.1);
+                    let mut sink = sink_mutex.lock().unwrap();
+                    pause_sink(&mut sink, 0.1);
                     sink.clear();
                     drop(sink);
                     
                     return false;
                 }
                 
-                let sink = sink_mutex.lock().unwrap();
+                let mut sink = sink_mutex.lock().unwrap();
                 if paused.load(Ordering::SeqCst) && !sink.is_paused() {
-                    pause_sink(&sink, 0.1);
+                    pause_sink(&mut sink, 0.1);
                 }
                 if !paused.load(Ordering::SeqCst) && sink.is_paused() {
-                    resume_sink(&sink, 0.1);
+                    resume_sink(&mut sink, 0.1);
                 }
                 drop(sink);
                 
@@ -219,7 +285,7 @@ impl Player {
                 // Check how many chunks have been played (chunk_lengths.len() - sink.len())
                 // since the last time this function was called
                 // and add that to time_passed
-                let sink = sink_mutex.lock().unwrap();
+                let mut sink = sink_mutex.lock().unwrap();
                 let chunks_played = chunk_lengths.len() - sink.len();
 
                 drop(sink);
@@ -227,9 +293,7 @@ impl Player {
                 for _ in 0..chunks_played {
                     timer.reset();
                     timer.start();
-                    // TODO: Handle audio channels properly. Currently all audio is treated as stereo
-                    // *chunks_passed += chunk_lengths.remove(0) / channels;
-                    *chunks_passed += chunk_lengths.remove(0) / 2.0;
+                    *chunks_passed += chunk_lengths.remove(0);
                 }
 
                 *time_passed_unlocked = *chunks_passed + timer.get_time();
@@ -246,8 +310,9 @@ impl Player {
             let update_sink = |(mixer, length_in_seconds): (SamplesBuffer<f32>, f64)| {
                 audio_heard.store(true, Ordering::Relaxed);
 
-                let sink = sink_mutex.lock().unwrap();
-                sink.append(mixer);
+                let mut sink = sink_mutex.lock().unwrap();
+                let (channels, sample_rate) = (mixer.channels(), mixer.sample_rate());
+                sink.append(mixer.collect(), channels, sample_rate);
                 drop(sink);
 
                 let mut chunk_lengths = chunk_lengths.lock().unwrap();
@@ -256,6 +321,17 @@ impl Player {
 
                 update_chunk_lengths();
                 check_details();
+
+                // Wait for the sink to play through what it holds
+                loop {
+                    let queued_chunks = sink_mutex.lock().unwrap().len();
+                    if queued_chunks <= MAX_QUEUED_CHUNKS || !check_details() {
+                        break;
+                    }
+
+                    update_chunk_lengths();
+                    thread::sleep(Duration::from_millis(10));
+                }
             };
 
             engine.reception_loop(&update_sink);
@@ -269,8 +345,8 @@ impl Player {
                     break;
                 }
                 
-                let sink = sink_mutex.lock().unwrap();
-                let sink_empty = sink.empty();
+                let mut sink = sink_mutex.lock().unwrap();
+                let sink_empty = sink.is_empty();
                 drop(sink);
                 // If all tracks are finished buffering and sink is finished playing, exit the loop
                 if sink_empty && engine.finished_buffering() {
@@ -280,6 +356,8 @@ impl Player {
                 thread::sleep(Duration::from_millis(100));
             }
 
+            *engine_mutex.lock().unwrap() = None;
+
             // ===================== //
             // Set playback_thread_exists to false
             // ===================== //
@@ -404,11 +482,169 @@ impl Player {
         prot.refresh_tracks();
         drop(prot);
 
+        self.restart_with_selection();
+    }
+
+    /// Sets how likely each take is to be chosen and picks new tracks with
+    /// them. See `Prot::set_weights`.
+    pub fn set_weights(&mut self, weights: Vec<Option<Vec<f32>>>) -> Result<(), PlaySettingsError> {
+        let mut prot = self.prot.lock().unwrap();
+        prot.set_weights(weights)?;
+        drop(prot);
+
+        self.refresh_tracks();
+
+        Ok(())
+    }
+
+    /// Replays the selection produced by `seed`.
+    pub fn set_seed(&mut self, seed: u64) {
+        let mut prot = self.prot.lock().unwrap();
+        prot.set_seed(seed);
+        drop(prot);
+
+        self.restart_with_selection();
+    }
+
+    /// Returns the seed that produced the current selection.
+    pub fn get_seed(&self) -> Option<u64> {
+        let prot = self.prot.lock().unwrap();
+
+        prot.get_seed()
+    }
+
+    /// Plays the exact takes described by a combination id without
+    /// reshuffling.
+    pub fn set_combination(&mut self, combination: &str) -> Result<(), CombinationError> {
+        let mut prot = self.prot.lock().unwrap();
+        prot.set_combination(combination)?;
+        drop(prot);
+
+        self.restart_with_selection();
+
+        Ok(())
+    }
+
+    pub fn get_combination(&self) -> String {
+        let prot = self.prot.lock().unwrap();
+
+        prot.get_combination()
+    }
+
+    /// Sets a crossfade, in seconds, for new selections. When set, new takes
+    /// from `shuffle`, `set_weights`, `set_seed` and `set_combination` fade in
+    /// while playback carries on, rather than playback restarting at the
+    /// current time.
+    pub fn set_reshuffle_crossfade(&mut self, crossfade: Option<f64>) {
+        let mut reshuffle_crossfade = self.reshuffle_crossfade.lock().unwrap();
+        *reshuffle_crossfade = crossfade;
+    }
+
+    pub fn get_reshuffle_crossfade(&self) -> Option<f64> {
+        *self.reshuffle_crossfade.lock().unwrap()
+    }
+
+    /// Sets how carefully tracks, and the output, are resampled where their
+    /// sample rates differ from the file's. Takes effect the next time
+    /// playback starts or seeks.
+    pub fn set_resample_quality(&mut self, quality: ResampleQuality) {
+        *self.resample_quality.lock().unwrap() = quality;
+    }
+
+    pub fn get_resample_quality(&self) -> ResampleQuality {
+        *self.resample_quality.lock().unwrap()
+    }
+
+    /// Sets whether takes are brought in line with the loudness of the other
+    /// takes in their group, and the combination within the loudness window.
+    /// See `PlayerEngine::set_loudness_normalization`. Turning it on measures
+    /// the takes the file gives no loudness for, as `analyze_loudness` does.
+    /// Takes effect the next time playback starts or seeks.
+    pub fn set_loudness_normalization(&mut self, normalize_loudness: bool) {
+        if normalize_loudness {
+            self.analyze_loudness();
+        }
+        *self.normalize_loudness.lock().unwrap() = normalize_loudness;
+    }
+
+    pub fn get_loudness_normalization(&self) -> bool {
+        *self.normalize_loudness.lock().unwrap()
+    }
+
+    /// Sets the ceiling, in dBTP, of the master bus limiter, or `None` to
+    /// turn it off. See `PlayerEngine::set_limiter_ceiling`. Takes effect
+    /// the next time playback starts or seeks.
+    pub fn set_limiter_ceiling(&mut self, ceiling: Option<f32>) {
+        *self.limiter_ceiling.lock().unwrap() = ceiling;
+    }
+
+    pub fn get_limiter_ceiling(&self) -> Option<f32> {
+        *self.limiter_ceiling.lock().unwrap()
+    }
+
+    /// Sets the reverb over the mix, in place of the one the file sets, or
+    /// `None` to turn it off. Takes effect the next time playback starts or
+    /// seeks.
+    pub fn set_reverb(&mut self, reverb: Option<Reverb>) {
+        *self.reverb.lock().unwrap() = reverb;
+    }
+
+    pub fn get_reverb(&self) -> Option<Reverb> {
+        self.reverb.lock().unwrap().clone()
+    }
+
+    /// Loads the reverb's impulse response from a WAV or FLAC file, keeping
+    /// the reverb's levels. Takes effect the next time playback starts or
+    /// seeks.
+    pub fn load_impulse_response(&mut self, file_path: &str) -> Result<(), ImpulseResponseError> {
+        let impulse_response = Arc::new(ImpulseResponse::load(file_path)?);
+
+        let mut reverb = self.reverb.lock().unwrap();
+        match reverb.as_mut() {
+            Some(reverb) => reverb.impulse_response = impulse_response,
+            None => {
+                *reverb = Some(Reverb { impulse_response, wet: DEFAULT_REVERB_WET, dry: DEFAULT_REVERB_DRY });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the levels, from 0 to 1, of the reverb and of the mix without
+    /// it. Does nothing when there is no reverb. Takes effect the next time
+    /// playback starts or seeks.
+    pub fn set_reverb_levels(&mut self, wet: f32, dry: f32) {
+        if let Some(reverb) = self.reverb.lock().unwrap().as_mut() {
+            reverb.wet = wet;
+            reverb.dry = dry;
+        }
+    }
+
+    /// Measures the loudness of takes the file does not give one for. See
+    /// `Prot::analyze_loudness`.
+    pub fn analyze_loudness(&mut self) {
+        self.prot.lock().unwrap().analyze_loudness();
+    }
+
+    fn restart_with_selection(&mut self) {
         // If stopped, return
         if self.is_finished() {
             return;
         }
 
+        // Crossfade to the new takes if playback is under way
+        let crossfade = self.get_reshuffle_crossfade();
+        let engine = self.engine.lock().unwrap().clone();
+        if let (Some(crossfade), Some(engine)) = (crossfade, engine) {
+            if self.audio_heard.load(Ordering::Relaxed) {
+                engine.crossfade_to_selection(crossfade);
+
+                let mut duration = self.duration.lock().unwrap();
+                *duration = engine.get_duration();
+                return;
+            }
+        }
+
         // Kill current thread and start 
         // new thread at the current timestamp
         let ts = self.get_time();
@@ -429,8 +665,56 @@ impl Player {
         self.refresh_tracks();
     }
 
+    /// Picks a new take for one track group while the other groups play on.
+    /// See `Prot::reroll_group`.
+    pub fn reroll_group(&mut self, key: usize) -> Result<(), SelectionError> {
+        let mut prot = self.prot.lock().unwrap();
+        prot.reroll_group(key)?;
+        drop(prot);
+
+        self.swap_group(key);
+
+        Ok(())
+    }
+
+    /// Plays the given take in one track group while the other groups play
+    /// on. See `Prot::choose_take`.
+    pub fn choose_take(&mut self, key: usize, take: Option<usize>) -> Result<(), SelectionError> {
+        let mut prot = self.prot.lock().unwrap();
+        prot.choose_take(key, take)?;
+        drop(prot);
+
+        self.swap_group(key);
+
+        Ok(())
+    }
+
+    fn swap_group(&mut self, key: usize) {
+        // If stopped, return
+        if self.is_finished() {
+            return;
+        }
+
+        // Only the group's own decode thread is replaced, using the file's
+        // crossfade between takes unless a reshuffle crossfade is set
+        let engine = self.engine.lock().unwrap().clone();
+        match engine {
+            Some(engine) if self.audio_heard.load(Ordering::Relaxed) => {
+                let crossfade = match self.get_reshuffle_crossfade() {
+                    Some(crossfade) => crossfade,
+                    None => self.prot.lock().unwrap().get_crossfade(),
+                };
+                engine.crossfade_group(key as i32, crossfade);
+
+                let mut duration = self.duration.lock().unwrap();
+                *duration = engine.get_duration();
+            }
+            _ => self.restart_with_selection(),
+        }
+    }
+
     pub fn set_volume(&mut self, new_volume: f32) {
-        let sink = self.sink.lock().unwrap();
+        let mut sink = self.sink.lock().unwrap();
         sink.set_volume(new_volume);
         drop(sink);
         
@@ -443,6 +727,54 @@ impl Player {
         *self.volume.lock().unwrap()
     }
 
+    /// Sets the gain, pan, mute and solo of one track group. Playback carries
+    /// on, with the change ramped in so it does not click.
+    pub fn set_group_mix(&mut self, key: usize, group_mix: GroupMix) -> Result<(), SelectionError> {
+        let mut group_mixes = self.group_mixes.lock().unwrap();
+        let Some(current) = group_mixes.get_mut(key) else {
+            return Err(SelectionError::UnknownGroup(key));
+        };
+        *current = GroupMix { pan: group_mix.pan.clamp(-1.0, 1.0), ..group_mix };
+        let group_mix = *current;
+        drop(group_mixes);
+
+        if let Some(engine) = self.engine.lock().unwrap().as_ref() {
+            engine.set_group_mix(key as i32, group_mix);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_group_mix(&self, key: usize) -> Result<GroupMix, SelectionError> {
+        let group_mixes = self.group_mixes.lock().unwrap();
+        group_mixes.get(key).copied().ok_or(SelectionError::UnknownGroup(key))
+    }
+
+    /// Sets the level of one track group, in dB.
+    pub fn set_group_gain(&mut self, key: usize, gain: f32) -> Result<(), SelectionError> {
+        let group_mix = self.get_group_mix(key)?;
+        self.set_group_mix(key, GroupMix { gain, ..group_mix })
+    }
+
+    /// Sets the stereo position of one track group, from -1 (left) to 1
+    /// (right).
+    pub fn set_group_pan(&mut self, key: usize, pan: f32) -> Result<(), SelectionError> {
+        let group_mix = self.get_group_mix(key)?;
+        self.set_group_mix(key, GroupMix { pan, ..group_mix })
+    }
+
+    pub fn set_group_mute(&mut self, key: usize, mute: bool) -> Result<(), SelectionError> {
+        let group_mix = self.get_group_mix(key)?;
+        self.set_group_mix(key, GroupMix { mute, ..group_mix })
+    }
+
+    /// Solos one track group. While any group is soloed, only the soloed
+    /// groups are heard.
+    pub fn set_group_solo(&mut self, key: usize, solo: bool) -> Result<(), SelectionError> {
+        let group_mix = self.get_group_mix(key)?;
+        self.set_group_mix(key, GroupMix { solo, ..group_mix })
+    }
+
     pub fn get_ids(&self) -> Vec<String> {
         let prot = self.prot.lock().unwrap();
 
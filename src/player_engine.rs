@@ -1,26 +1,104 @@
 use dasp_ring_buffer::Bounded;
-use rodio::{
-    buffer::SamplesBuffer,
-    dynamic_mixer::{self, DynamicMixer},
-    Source,
-};
+use rodio::buffer::SamplesBuffer;
+use std::f32::consts::FRAC_PI_2;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
-use std::{collections::HashMap, sync::mpsc::Receiver, thread};
+use std::{sync::mpsc::Receiver, thread};
 
+use crate::channels::{default_layout, ChannelMatrix};
+use crate::effects::{Convolver, MasterBus, Reverb};
+use crate::limiter::{Limiter, DEFAULT_CEILING};
+use crate::mixer::{GroupMix, GroupMixer};
+use crate::resample::{ResampleQuality, Resampler};
 use crate::{buffer::*, prot::Prot};
-// use crate::effects::*;
 use crate::track::*;
 
+// Samples mixed at a time in `fill_buffer`, when the mix is remixed or
+// resampled on its way out
+const PULL_MIX_SAMPLES: usize = 8192;
+
+// A decode thread feeding one track group into the mix
+#[derive(Debug, Clone)]
+struct Voice {
+    key: i32,
+    buffer_id: i32,
+    abort: Arc<AtomicBool>,
+    fade: Option<Fade>,
+    // Whether any of its frames have been mixed
+    started: bool,
+    // Frames mixed while it had none decoded. As many are dropped from its
+    // buffer as they arrive, to keep it in time with the other voices.
+    lag: usize,
+}
+
+// Equal power fade, from one angle on the quarter sine to another
+#[derive(Debug, Clone, Copy)]
+struct Fade {
+    from: f32,
+    to: f32,
+    position: u64,
+    length: u64,
+}
+
+impl Fade {
+    fn angle(&self) -> f32 {
+        let progress = (self.position as f32 + 0.5) / self.length as f32;
+        self.from + (self.to - self.from) * progress.min(1.0)
+    }
+
+    fn next_gain(&mut self) -> f32 {
+        let gain = self.angle().sin();
+        self.position += 1;
+        gain
+    }
+
+    // Gain of the last frame faded, held while the fade waits
+    fn held_gain(&self) -> f32 {
+        match self.position {
+            0 => self.from.sin(),
+            position => Fade { position: position - 1, ..*self }.angle().sin(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+}
+
+#[derive(Debug)]
+struct Mix {
+    voices: Vec<Voice>,
+    groups: GroupMixer,
+    // Frames mixed since start_time
+    position: u64,
+    next_buffer_id: i32,
+    // Whether the decode threads have been started
+    started: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct PlayerEngine {
     pub finished_tracks: Arc<Mutex<Vec<i32>>>,
     start_time: f64,
     abort: Arc<AtomicBool>,
-    buffer_map: Arc<Mutex<HashMap<i32, Bounded<Vec<f32>>>>>,
+    buffer_map: BufferMap,
     effects_buffer: Arc<Mutex<Bounded<Vec<f32>>>>,
     prot: Arc<Mutex<Prot>>,
+    mix: Arc<Mutex<Mix>>,
+    // Samples each track can decode ahead of the mix
+    buffer_size: usize,
+    // Rate the mix is resampled to, when it differs from the file's
+    output_sample_rate: Option<u32>,
+    resampler: Arc<Mutex<Option<Resampler>>>,
+    resample_quality: ResampleQuality,
+    normalize_loudness: bool,
+    // Remixes the mix to the output's channels, when they differ from the file's
+    channel_matrix: Option<ChannelMatrix>,
+    // Reverb and limiter, at the output's channels and the file's sample rate
+    master_bus: Arc<Mutex<Option<MasterBus>>>,
+    reverb: Option<Reverb>,
+    limiter_ceiling: Option<f32>,
 }
 
 impl PlayerEngine {
@@ -34,27 +112,120 @@ impl PlayerEngine {
         };
 
         let prot_unlocked = prot.lock().unwrap();
+        let sample_rate = prot_unlocked.info.sample_rate;
+        let channels = prot_unlocked.info.channels;
+        let reverb = prot_unlocked.get_reverb();
         let buffer_size = prot_unlocked.info.sample_rate as usize * 10; // Ten seconds of audio at the sample rate
         let effects_buffer = Arc::new(Mutex::new(Bounded::from(vec![0.0; buffer_size])));
+        let groups = GroupMixer::new(
+            prot_unlocked.get_group_mixes(),
+            prot_unlocked.get_group_automation(),
+            prot_unlocked.info.channel_layout,
+            sample_rate,
+        );
         drop(prot_unlocked);
 
+        let mix = Mix {
+            voices: Vec::new(),
+            groups,
+            position: 0,
+            next_buffer_id: 0,
+            started: false,
+        };
+
         let this = Self {
             finished_tracks,
             start_time,
             buffer_map,
             effects_buffer,
             abort,
-            prot
+            prot,
+            mix: Arc::new(Mutex::new(mix)),
+            buffer_size: sample_rate as usize * channels as usize, // One second of audio at the sample rate
+            output_sample_rate: None,
+            resampler: Arc::new(Mutex::new(None)),
+            resample_quality: ResampleQuality::default(),
+            normalize_loudness: false,
+            channel_matrix: None,
+            master_bus: Arc::new(Mutex::new(None)),
+            reverb,
+            limiter_ceiling: Some(DEFAULT_CEILING),
         };
 
         this
     }
 
-    pub fn reception_loop(&mut self, f: &dyn Fn((SamplesBuffer<f32>, f64))) {
+    /// Sets how many samples each track may decode ahead of the mix. Takes
+    /// effect for tracks started after the call.
+    pub fn set_buffer_size(&mut self, buffer_size: usize) {
+        self.buffer_size = buffer_size;
+    }
+
+    /// Sets the sample rate of the audio the engine gives out. The tracks are
+    /// mixed at the file's own rate and resampled to this one. `None` keeps
+    /// the file's rate. Takes effect when the engine is started.
+    pub fn set_output_sample_rate(&mut self, sample_rate: Option<u32>) {
+        self.output_sample_rate = sample_rate;
+    }
+
+    /// Sets how carefully tracks not at the file's sample rate, and the mix
+    /// when it goes out at another rate, are resampled. Takes effect for
+    /// tracks started after the call.
+    pub fn set_resample_quality(&mut self, quality: ResampleQuality) {
+        self.resample_quality = quality;
+    }
+
+    /// Sets whether takes are turned up or down to bring their loudness in
+    /// line with the other takes of their group, and the whole combination
+    /// within the file's loudness window. Uses the loudness measured when the
+    /// file was written or by `Prot::analyze_loudness`, and leaves takes
+    /// without one alone. Takes effect for tracks started after the call.
+    pub fn set_loudness_normalization(&mut self, normalize_loudness: bool) {
+        self.normalize_loudness = normalize_loudness;
+    }
+
+    /// Sets the ceiling, in dBTP, that the master bus limiter keeps the true
+    /// peaks of the mix under. `None` turns the limiter off, so that a loud
+    /// mix can clip. Takes effect when the engine is started.
+    pub fn set_limiter_ceiling(&mut self, ceiling: Option<f32>) {
+        self.limiter_ceiling = ceiling;
+    }
+
+    /// Sets the reverb over the mix, in place of the one the file sets.
+    /// `None` turns the reverb off. Takes effect when the engine is started.
+    pub fn set_reverb(&mut self, reverb: Option<Reverb>) {
+        self.reverb = reverb;
+    }
+
+    /// Sample rate of the audio the engine gives out.
+    pub fn get_sample_rate(&self) -> u32 {
+        let prot = self.prot.lock().unwrap();
+        self.output_sample_rate.unwrap_or(prot.info.sample_rate)
+    }
+
+    /// Sets how many channels the audio the engine gives out has, in their
+    /// usual layout (see `channels::default_layout`). The tracks are mixed in
+    /// the file's own channels and remixed to these, by the rules of
+    /// `ChannelMatrix`. `None` keeps the file's channels.
+    pub fn set_output_channels(&mut self, channels: Option<u16>) {
         let prot = self.prot.lock().unwrap();
-        let keys = prot.get_keys();
+        let channel_layout = prot.info.channel_layout;
         drop(prot);
-        self.ready_buffer_map(&keys);
+
+        self.channel_matrix = channels
+            .map(|channels| ChannelMatrix::new(channel_layout, default_layout(channels)))
+            .filter(|matrix| !matrix.is_identity());
+    }
+
+    /// Channels of the audio the engine gives out.
+    pub fn get_channels(&self) -> u16 {
+        match &self.channel_matrix {
+            Some(matrix) => matrix.to().count() as u16,
+            None => self.prot.lock().unwrap().info.channels as u16,
+        }
+    }
+
+    pub fn reception_loop(&mut self, f: &dyn Fn((SamplesBuffer<f32>, f64))) {
         let receiver = self.get_receiver();
 
         for (mixer, length_in_seconds) in receiver {
@@ -69,196 +240,409 @@ impl PlayerEngine {
         let prot = self.prot.lock().unwrap();
         let audio_info = prot.info.clone();
         drop(prot);
-        let buffer_map = self.buffer_map.clone();
         let abort = self.abort.clone();
+        let engine = self.clone();
+        let sample_rate = self.get_sample_rate();
+        let mix_channels = audio_info.channels as usize;
+        let channels = self.get_channels();
 
-        let playing_map: Arc<Mutex<std::collections::HashMap<i32, Arc<Mutex<bool>>>>> =
-            Arc::new(Mutex::new(std::collections::HashMap::new()));
-
-        let finished_tracks = self.finished_tracks.clone();
-        let effects_buffer = self.effects_buffer.clone();
-        let prot_locked = self.prot.clone();
-        let start_time = self.start_time;
+        self.start();
 
         thread::spawn(move || {
-            let prot = prot_locked.lock().unwrap();
-            let enumerated_list = prot.enumerated_list();
-            drop(prot);
-
-            for (key, file_path, track_id) in enumerated_list {
-                let playing = buffer_track(
-                    TrackArgs {
-                        file_path: file_path.clone(),
-                        track_id,
-                        track_key: key,
-                        buffer_map: buffer_map.clone(),
-                        finished_tracks: finished_tracks.clone(),
-                        start_time
-                    },
-                    abort.clone(),
-                );
-
-                let mut playing_map = playing_map.lock().unwrap();
-                playing_map.insert(key, playing);
-                drop(playing_map);
-            }
-
-            // let sink_mutex_copy = sink_mutex.clone();
-            let hash_buffer_copy = buffer_map.clone();
+            // No track decodes more than one buffer ahead of the mix
+            let mut mixed_samples = vec![0.0; engine.buffer_size / mix_channels * mix_channels];
 
             loop {
                 if abort.load(Ordering::SeqCst) {
+                    engine.stop();
                     break;
                 }
 
-                let mut hash_buffer = hash_buffer_copy.lock().unwrap();
-
-                let mut removable_tracks: Vec<i32> = Vec::new();
+                // Mix as much as every track has decoded
+                let mixed = engine.mix_into(&mut mixed_samples, true);
+                let mut samples = mixed_samples[..mixed.unwrap_or(0) * mix_channels].to_vec();
 
-                // if all buffers are not empty, add samples from each buffer to the mixer
-                // until at least one buffer is empty
-                let mut all_buffers_full = true;
-                for (track_key, buffer) in hash_buffer.iter() {
-                    if buffer.len() == 0 {
-                        let finished = finished_tracks.lock().unwrap();
-                        if finished.contains(&track_key) {
-                            removable_tracks.push(*track_key);
-                            continue;
-                        }
-                        all_buffers_full = false;
-                    }
+                if let Some(matrix) = &engine.channel_matrix {
+                    samples = matrix.mix(&samples);
                 }
 
-                for track_id in removable_tracks {
-                    hash_buffer.remove(&track_id);
+                let samples = engine.process_effects(&samples, mixed.is_none());
+                let samples = engine.resample(samples, mixed.is_none());
+                if !samples.is_empty() {
+                    let length_in_seconds = samples.len() as f64 / sample_rate as f64 / channels as f64;
+                    let samples_buffer = SamplesBuffer::new(channels, sample_rate, samples);
+
+                    // Sending waits for the sink, so new tracks can be started meanwhile
+                    sender.send((samples_buffer, length_in_seconds)).unwrap();
                 }
 
-                // If hash_buffer contains no tracks, exit the loop
-                if hash_buffer.len() == 0 && effects_buffer.lock().unwrap().len() == 0 {
+                if mixed.is_none() {
                     break;
                 }
 
-                if all_buffers_full || (effects_buffer.lock().unwrap().len() > 0 && hash_buffer.len() == 0) {
-                    let (controller, mixer) = dynamic_mixer::mixer::<f32>(2, 44_100);
-                    
-                    // Hash buffer plus effects buffer
-                    let mut effects_buffer_unlocked = effects_buffer.lock().unwrap();
-                    let mut combined_buffer: HashMap<i32, Bounded<Vec<f32>>> = HashMap::new();
-                    for (track_key, buffer) in hash_buffer.iter() {
-                        combined_buffer.insert(*track_key, buffer.clone());
-                    }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        // Arc::new(receiver)
+        receiver
+    }
+
+    /// Starts a decode thread for each track group, for `fill_buffer` to mix
+    /// from. Does nothing if the tracks have already been started.
+    pub fn start(&self) {
+        let prot = self.prot.lock().unwrap();
+        let track_segments = prot.get_track_segments();
+        let audio_info = prot.info.clone();
+        drop(prot);
 
+        let mut mix = self.mix.lock().unwrap();
+        if mix.started {
+            return;
+        }
+        mix.started = true;
 
-                    // combined_buffer.append(&mut effects_buffer.lock().unwrap());
-                    // combined_buffer.insert(*track_key, combined_buffer);
+        let channels = self.get_channels() as usize;
+        let reverb = self.reverb.as_ref().map(|reverb| Convolver::new(reverb, channels, audio_info.sample_rate));
+        let limiter = self.limiter_ceiling.map(|ceiling| Limiter::new(channels, audio_info.sample_rate, ceiling));
+        *self.master_bus.lock().unwrap() = MasterBus::new(reverb, limiter);
 
-                    
-                    let length_of_smallest_buffer = hash_buffer
-                        .iter()
-                        .map(|(_, buffer)| buffer.len())
-                        .min()
-                        .unwrap();
-                    for (_, buffer) in hash_buffer.iter_mut() {
-                        let mut samples: Vec<f32> = Vec::new();
-                        for _ in 0..length_of_smallest_buffer {
-                            samples.push(buffer.pop().unwrap());
-                        }
+        let sample_rate = self.get_sample_rate();
+        if sample_rate != audio_info.sample_rate {
+            *self.resampler.lock().unwrap() =
+                Some(Resampler::new(audio_info.sample_rate, sample_rate, channels, self.resample_quality));
+        }
+
+        for (key, segments) in track_segments {
+            let voice = self.spawn_voice(&mut mix, key, segments, self.start_time, None);
+            mix.voices.push(voice);
+        }
+    }
+
+    /// Stops every decode thread. Frames already decoded can still be mixed.
+    pub fn stop(&self) {
+        self.abort.store(true, Ordering::SeqCst);
 
-                        let source =
-                            SamplesBuffer::new(2, audio_info.sample_rate as u32, samples);
+        let mix = self.mix.lock().unwrap();
+        for voice in mix.voices.iter() {
+            voice.abort.store(true, Ordering::SeqCst);
+        }
+    }
 
-                        controller.add(source.convert_samples().amplify(0.2));
+    /// Fills `buffer` with interleaved frames mixed from the tracks, at the
+    /// engine's output channels, for hosts that pull audio from their own
+    /// callback. Never waits on decoding once the first frames are in: a track
+    /// that falls behind is left silent until it catches up, and frames no
+    /// track has decoded yet are left silent. Returns how many frames were
+    /// mixed from the tracks.
+    pub fn fill_buffer(&self, buffer: &mut [f32]) -> usize {
+        let mix_channels = self.prot.lock().unwrap().info.channels as usize;
+        let channels = self.get_channels() as usize;
+        let mut master_bus = self.master_bus.lock().unwrap();
+        let mut resampler = self.resampler.lock().unwrap();
+
+        if master_bus.is_none() && resampler.is_none() && self.channel_matrix.is_none() {
+            let frames = self.mix_into(buffer, false).unwrap_or(0);
+            buffer[frames * channels..].fill(0.0);
+            return frames;
+        }
+
+        // Mixed and remixed a chunk at a time on the stack
+        let chunk_frames = PULL_MIX_SAMPLES / mix_channels.max(channels);
+        let mut mixed = [0.0; PULL_MIX_SAMPLES];
+        let mut remixed = [0.0; PULL_MIX_SAMPLES];
+
+        let frames = if master_bus.is_none() && resampler.is_none() {
+            let mut frames = 0;
+            while frames < buffer.len() / channels {
+                let wanted = (buffer.len() / channels - frames).min(chunk_frames);
+                match self.mix_chunk(wanted, &mut mixed, &mut remixed) {
+                    Some((mixed_frames, samples)) if mixed_frames > 0 => {
+                        buffer[frames * channels..(frames + mixed_frames) * channels].copy_from_slice(samples);
+                        frames += mixed_frames;
                     }
+                    _ => break,
+                }
+            }
 
-                    // Add effects buffer to mixer
-                    let num_effects_samples = if effects_buffer_unlocked.len() < length_of_smallest_buffer {
-                        effects_buffer_unlocked.len()
-                    } else {
-                        length_of_smallest_buffer
-                    };
-                    
-                    {
-                        let mut samples: Vec<f32> = Vec::new();
-                        for _ in 0..num_effects_samples {
-                            samples.push(effects_buffer_unlocked.pop().unwrap());
-                        }
+            frames
+        } else {
+            // The master bus and the resampler hold frames back, so chunks are
+            // mixed until the last of them has enough to fill the buffer
+            loop {
+                let ready = match (resampler.as_ref(), master_bus.as_ref()) {
+                    (Some(resampler), _) => resampler.len(),
+                    (None, Some(master_bus)) => master_bus.len(),
+                    (None, None) => unreachable!(),
+                };
+                if ready >= buffer.len() {
+                    break;
+                }
 
-                        let source =
-                            SamplesBuffer::new(2, audio_info.sample_rate as u32, samples);
+                let (samples, finished) = match self.mix_chunk(chunk_frames, &mut mixed, &mut remixed) {
+                    Some((0, _)) => break,
+                    Some((_, samples)) => (samples, false),
+                    None => (&[][..], true),
+                };
+
+                let processed;
+                let samples = match master_bus.as_mut() {
+                    Some(master_bus) => {
+                        master_bus.push(samples);
+                        if finished {
+                            master_bus.finish();
+                        }
+                        // Passed on to the resampler, when there is one
+                        processed = if resampler.is_some() { master_bus.take() } else { Vec::new() };
+                        &processed[..]
+                    }
+                    None => samples,
+                };
 
-                        controller.add(source.convert_samples().amplify(0.2));
+                if let Some(resampler) = resampler.as_mut() {
+                    resampler.push(samples);
+                    if finished {
+                        resampler.finish();
                     }
+                }
 
-                    drop(effects_buffer_unlocked);
+                if finished {
+                    break;
+                }
+            }
 
-                    // let buffer = mixer.buffered().reverb(Duration::from_millis(100), 0.5).buffered();
+            match (resampler.as_mut(), master_bus.as_mut()) {
+                (Some(resampler), _) => resampler.pop(buffer) / channels,
+                (None, Some(master_bus)) => master_bus.pop(buffer) / channels,
+                (None, None) => unreachable!(),
+            }
+        };
+        buffer[frames * channels..].fill(0.0);
 
-                    let samples_buffer =
-                        PlayerEngine::process_effects(mixer, effects_buffer.clone());
+        frames
+    }
 
-                    // while let Some(sample) = mixer.next() {
-                    //     // mixer.sample_rate();
-                    //     // mixer.channels();
-                    //     // Process each sample as needed
-                    //     // println!("Sample: {:?}", sample);
-                    // }
+    // Mixes up to `frames` frames into `mixed`, then into `remixed` when the
+    // output has other channels. Returns the frames mixed and their samples
+    // at the output's channels, or `None` once every track has finished.
+    fn mix_chunk<'a>(
+        &self,
+        frames: usize,
+        mixed: &'a mut [f32],
+        remixed: &'a mut [f32],
+    ) -> Option<(usize, &'a [f32])> {
+        let mix_channels = self.prot.lock().unwrap().info.channels as usize;
+        let mixed_frames = self.mix_into(&mut mixed[..frames * mix_channels], false)?;
+
+        match &self.channel_matrix {
+            Some(matrix) => {
+                let channels = matrix.to().count();
+                matrix.mix_into(&mixed[..mixed_frames * mix_channels], remixed);
+                Some((mixed_frames, &remixed[..mixed_frames * channels]))
+            }
+            None => Some((mixed_frames, &mixed[..mixed_frames * mix_channels])),
+        }
+    }
 
-                    // println!("Mixer size: {:?}", mixer.total_duration());
-                    // println!("Smallest buffer size: {:?}", length_of_smallest_buffer);
+    /// Whether every track has been decoded and mixed.
+    pub fn is_finished(&self) -> bool {
+        let master_bus = self.master_bus.lock().unwrap();
+        if master_bus.as_ref().is_some_and(|master_bus| !master_bus.is_finished()) {
+            return false;
+        }
+        drop(master_bus);
 
-                    let length_in_seconds = length_of_smallest_buffer as f64 / audio_info.sample_rate as f64 / audio_info.channels as f64;
+        let resampler = self.resampler.lock().unwrap();
+        if resampler.as_ref().is_some_and(|resampler| !resampler.is_finished()) {
+            return false;
+        }
+        drop(resampler);
 
-                    sender.send((samples_buffer, length_in_seconds)).unwrap();
-                }
+        let mix = self.mix.lock().unwrap();
+        let buffer_map = self.buffer_map.lock().unwrap();
+        let finished_tracks = self.finished_tracks.lock().unwrap();
 
-                drop(hash_buffer);
+        mix.started
+            && self.effects_buffer.lock().unwrap().is_empty()
+            && buffer_map
+                .iter()
+                .all(|(buffer_id, buffer)| buffer.is_empty() && finished_tracks.contains(buffer_id))
+    }
 
-                thread::sleep(Duration::from_millis(100));
-            }
-        });
+    // Passes mixed samples through the resampler, if there is one. `finished`
+    // gives out what the resampler holds back once the mix is over.
+    fn resample(&self, samples: Vec<f32>, finished: bool) -> Vec<f32> {
+        let mut resampler = self.resampler.lock().unwrap();
+        let resampler = match resampler.as_mut() {
+            Some(resampler) => resampler,
+            None => return samples,
+        };
 
-        // Arc::new(receiver)
-        receiver
+        resampler.push(&samples);
+        if finished {
+            resampler.finish();
+        }
+
+        resampler.take()
     }
 
-    pub fn process_effects(
-        mixer: DynamicMixer<f32>,
-        _effects_buffer: Arc<Mutex<Bounded<Vec<f32>>>>,
-    ) -> SamplesBuffer<f32> {
-        // TODO: Implement effects
-        let sample_rate = mixer.sample_rate();
-        let mixer_buffered = mixer.buffered();
-        // let starting_length = mixer_buffered.clone().into_iter().count();
-        // // let samples: Vec<f32> = mixer.buffered().take(length_of_smallest_buffer).collect();
-        // let mut samples: Vec<f32> = Vec::new();
-        // let mut left_over_samples: Vec<f32> = Vec::new();
+    // Mixes frames the tracks have decoded into the start of `output`, up to
+    // its length. Until the first frames are mixed, every track is waited
+    // for. After that, with `wait`, the mix goes as far as every voice that
+    // has started playing has decoded, so nothing is left out. Without it,
+    // the mix goes as far as any voice has decoded, and a voice that falls
+    // behind is left silent until it catches up. Either way a voice fading
+    // in is left silent, rather than holding up the mix, until its first
+    // frames are decoded. Returns the frames mixed, or `None` once every
+    // track has finished.
+    fn mix_into(&self, output: &mut [f32], wait: bool) -> Option<usize> {
+        let prot = self.prot.lock().unwrap();
+        let channels = prot.info.channels as usize;
+        let sample_rate = prot.info.sample_rate as f64;
+        drop(prot);
+        let mut mix = self.mix.lock().unwrap();
+        let mut hash_buffer = self.buffer_map.lock().unwrap();
+        let mut effects_buffer = self.effects_buffer.lock().unwrap();
+
+        // Frames voices were left silent for are dropped as they come in
+        for voice in mix.voices.iter_mut().filter(|voice| voice.lag > 0) {
+            if let Some(buffer) = hash_buffer.get_mut(&voice.buffer_id) {
+                let dropped = voice.lag.min(buffer.len() / channels);
+                for _ in 0..dropped * channels {
+                    buffer.pop();
+                }
+                voice.lag -= dropped;
+            }
+        }
+
+        let mut removable_tracks: Vec<i32> = Vec::new();
+        for (track_key, buffer) in hash_buffer.iter() {
+            if buffer.is_empty() && self.finished_tracks.lock().unwrap().contains(track_key) {
+                removable_tracks.push(*track_key);
+            }
+        }
 
-        let vector_samples = mixer_buffered.clone().into_iter().collect::<Vec<f32>>();
+        for track_id in removable_tracks {
+            hash_buffer.remove(&track_id);
+            mix.voices.retain(|voice| voice.buffer_id != track_id);
+        }
 
-        // let max = mixer_buffered.clone().max_by(|x, y| x.abs().total_cmp(&y.abs()));
-        // println!("Max peak: {:?}", max);
-        
-        // let samples_with_reverb = apply_convolution_reverb(vector_samples);
-        // let samples_with_reverb = simple_reverb(vector_samples, 22050, 0.5);
+        // If hash_buffer contains no tracks, the mix is over
+        if hash_buffer.is_empty() && effects_buffer.is_empty() {
+            return None;
+        }
 
-        // let mut index = 0;
-        // for sample in vector_samples {
-        //     index += 1;
+        let available = |voice: &Voice| hash_buffer.get(&voice.buffer_id).map_or(0, |buffer| buffer.len() / channels);
+        let first_frames = mix.position == 0;
+        let waited_for = mix.voices.iter().filter(|voice| voice.started || first_frames);
+        let frames = if wait || first_frames { waited_for.map(available).min() } else { None };
+        let frames = frames
+            .or_else(|| mix.voices.iter().map(available).max())
+            .unwrap_or(effects_buffer.len() / channels)
+            .min(output.len() / channels);
+        let output = &mut output[..frames * channels];
+        output.fill(0.0);
+
+        // Tracks start decoding at the frame nearest start_time
+        let start_frame = (self.start_time * sample_rate).round() as u64;
+        let mix = &mut *mix;
+
+        // Takes fading in start once they have decoded all the frames mixed.
+        // Until then the takes they replace hold their level, so there is no gap.
+        let fading_in: Vec<&Voice> = mix
+            .voices
+            .iter()
+            .filter(|voice| !voice.started && voice.fade.is_some_and(|fade| fade.to > 0.0) && available(voice) < frames)
+            .collect();
+        let pending: Vec<i32> = fading_in.iter().map(|voice| voice.buffer_id).collect();
+        let held_keys: Vec<i32> = fading_in.iter().map(|voice| voice.key).collect();
+
+        let mut gains = vec![0.0; channels];
+        for voice in mix.voices.iter_mut() {
+            let buffer = hash_buffer.get_mut(&voice.buffer_id).unwrap();
+            let decoded = if pending.contains(&voice.buffer_id) { 0 } else { (buffer.len() / channels).min(frames) };
+            let held = held_keys.contains(&voice.key);
+            for (index, frame) in output.chunks_exact_mut(channels).enumerate() {
+                // A fade starts with the voice's first frame, and keeps time
+                // if the voice falls behind after that
+                let gain = match voice.fade.as_mut() {
+                    Some(fade) if held || (!voice.started && index >= decoded) => fade.held_gain(),
+                    Some(fade) => fade.next_gain(),
+                    None => 1.0,
+                };
+                if index >= decoded {
+                    continue;
+                }
 
-        //     if index <= starting_length {
-        //         samples.push(sample);
-        //     } else {
-        //         left_over_samples.push(sample);
-        //     }
-        // }
+                // Automation follows the song's timeline, frame by frame
+                let time = (start_frame + mix.position + index as u64) as f64 / sample_rate;
+                mix.groups.frame_gains(voice.key, index, time, &mut gains);
 
-        // let mut effects_buffer_unlocked = effects_buffer.lock().unwrap();
-        // for sample in left_over_samples {
-        //     effects_buffer_unlocked.push(sample);
-        // }
-        // drop(effects_buffer_unlocked);
+                for (sample, group_gain) in frame.iter_mut().zip(&gains) {
+                    *sample += buffer.pop().unwrap() * gain * group_gain;
+                }
+            }
 
-        SamplesBuffer::new(mixer_buffered.channels(), sample_rate, vector_samples)
+            voice.started |= decoded > 0;
+            voice.lag += frames - decoded;
+        }
+        mix.groups.advance(frames);
+
+        // Tracks that have faded out are stopped, and tracks that have
+        // faded in play on as usual
+        for voice in mix.voices.iter_mut() {
+            if let Some(fade) = voice.fade.filter(|fade| fade.is_done()) {
+                if fade.to == 0.0 {
+                    voice.abort.store(true, Ordering::SeqCst);
+                    hash_buffer.remove(&voice.buffer_id);
+                }
+                voice.fade = None;
+            }
+        }
+        mix.voices.retain(|voice| !voice.abort.load(Ordering::SeqCst));
+        mix.position += frames as u64;
+
+        // Add effects buffer to the mix
+        for sample in output.iter_mut() {
+            match effects_buffer.pop() {
+                Some(effect) => *sample += effect,
+                None => break,
+            }
+        }
+
+        Some(frames)
+    }
+
+    /// Runs the master bus over mixed samples at the output's channels: the
+    /// reverb, when there is one, and then the limiter, which keeps their
+    /// true peaks under its ceiling. Both hold the last samples back until
+    /// more come in; `finished` gives them out once the mix is over.
+    pub fn process_effects(&self, samples: &[f32], finished: bool) -> Vec<f32> {
+        let mut master_bus = self.master_bus.lock().unwrap();
+        let master_bus = match master_bus.as_mut() {
+            Some(master_bus) => master_bus,
+            None => return samples.to_vec(),
+        };
+
+        master_bus.push(samples);
+        if finished {
+            master_bus.finish();
+        }
+
+        master_bus.take()
+    }
+
+    /// Sets the gain, pan, mute and solo of one track group. While playing,
+    /// the change ramps in over a few milliseconds.
+    pub fn set_group_mix(&self, key: i32, group_mix: GroupMix) {
+        let mut mix = self.mix.lock().unwrap();
+        let ramp = mix.started;
+        mix.groups.set(key, group_mix, ramp);
+    }
+
+    /// The mix of one track group, or `None` if there is no such group.
+    pub fn get_group_mix(&self, key: i32) -> Option<GroupMix> {
+        self.mix.lock().unwrap().groups.get(key)
     }
 
     pub fn get_duration(&self) -> f64 {
@@ -266,20 +650,114 @@ impl PlayerEngine {
         *prot.get_duration()
     }
 
-    fn ready_buffer_map(&mut self, keys: &Vec<u32>) {
-        self.buffer_map = init_buffer_map();
+    /// Crossfades from the takes that are playing to the ones now selected in
+    /// the prot, over `crossfade` seconds. The new takes start decoding at the
+    /// next frame to be mixed, so playback carries on without a gap.
+    pub fn crossfade_to_selection(&self, crossfade: f64) {
+        self.crossfade_groups(None, crossfade);
+    }
+
+    /// Like `crossfade_to_selection`, for one track group only. The other
+    /// groups play on untouched.
+    pub fn crossfade_group(&self, key: i32, crossfade: f64) {
+        self.crossfade_groups(Some(key), crossfade);
+    }
+
+    fn crossfade_groups(&self, only_key: Option<i32>, crossfade: f64) {
+        let replaced = |key: i32| only_key.is_none_or(|only_key| only_key == key);
 
         let prot = self.prot.lock().unwrap();
+        let track_segments = prot.get_track_segments();
         let sample_rate = prot.info.sample_rate;
         drop(prot);
-        let buffer_size = sample_rate as usize * 1; // Ten seconds of audio at the sample rate
-
-        for key in keys {
-            let ring_buffer = Bounded::from(vec![0.0; buffer_size]);
-            self.buffer_map
-                .lock()
-                .unwrap()
-                .insert(*key as i32, ring_buffer);
+
+        let length = ((crossfade * sample_rate as f64).round() as u64).max(1);
+
+        let mut mix = self.mix.lock().unwrap();
+        let start_time = self.start_time + mix.position as f64 / sample_rate as f64;
+
+        // Takes fading in that have not started were never heard, so they are dropped
+        let mut buffer_map = self.buffer_map.lock().unwrap();
+        mix.voices.retain(|voice| {
+            let unheard = replaced(voice.key) && !voice.started && voice.fade.is_some_and(|fade| fade.to > 0.0);
+            if unheard {
+                voice.abort.store(true, Ordering::SeqCst);
+                buffer_map.remove(&voice.buffer_id);
+            }
+            !unheard
+        });
+        drop(buffer_map);
+
+        for voice in mix.voices.iter_mut().filter(|voice| replaced(voice.key)) {
+            // Tracks already fading out carry on, tracks fading in turn around
+            let angle = match voice.fade {
+                Some(fade) if fade.to == 0.0 => continue,
+                Some(fade) => fade.angle(),
+                None => FRAC_PI_2,
+            };
+            voice.fade = Some(Fade { from: angle, to: 0.0, position: 0, length });
+        }
+
+        for (key, segments) in track_segments.into_iter().filter(|(key, _)| replaced(*key)) {
+            let fade = Fade { from: 0.0, to: FRAC_PI_2, position: 0, length };
+            let voice = self.spawn_voice(&mut mix, key, segments, start_time, Some(fade));
+            mix.voices.push(voice);
+        }
+    }
+
+    fn spawn_voice(
+        &self,
+        mix: &mut Mix,
+        key: i32,
+        mut segments: Vec<TrackSegment>,
+        start_time: f64,
+        fade: Option<Fade>,
+    ) -> Voice {
+        if self.normalize_loudness {
+            for segment in segments.iter_mut() {
+                segment.gain *= segment.loudness_gain;
+            }
+        }
+
+        let prot = self.prot.lock().unwrap();
+        let sample_rate = prot.info.sample_rate;
+        let channel_layout = prot.info.channel_layout;
+        let crossfade = prot.get_crossfade();
+        drop(prot);
+
+        // Each decode thread gets its own buffer, so a track group can be
+        // played by two threads while one fades into the other
+        let buffer_id = mix.next_buffer_id;
+        mix.next_buffer_id += 1;
+
+        self.buffer_map
+            .lock()
+            .unwrap()
+            .insert(buffer_id, Bounded::from(vec![0.0; self.buffer_size]));
+
+        let abort = Arc::new(AtomicBool::new(false));
+        buffer_track(
+            TrackArgs {
+                segments,
+                crossfade,
+                sample_rate,
+                channel_layout,
+                resample_quality: self.resample_quality,
+                track_key: buffer_id,
+                buffer_map: self.buffer_map.clone(),
+                finished_tracks: self.finished_tracks.clone(),
+                start_time,
+            },
+            abort.clone(),
+        );
+
+        Voice {
+            key,
+            buffer_id,
+            abort,
+            fade,
+            started: false,
+            lag: 0,
         }
     }
 
@@ -288,13 +766,11 @@ impl PlayerEngine {
     // }
 
     pub fn finished_buffering(&self) -> bool {
+        let mix = self.mix.lock().unwrap();
         let finished_tracks = self.finished_tracks.lock().unwrap();
-        let prot = self.prot.lock().unwrap();
-        let keys = prot.get_keys();
-        drop(prot);
 
-        for key in keys {
-            if !finished_tracks.contains(&(key as i32)) {
+        for voice in mix.voices.iter() {
+            if !finished_tracks.contains(&voice.buffer_id) {
                 return false;
             }
         }
@@ -1,8 +1,94 @@
-use matroska::{Matroska, Audio, Settings};
-use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use matroska::Audio;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use symphonia::core::audio::Channels;
 
+use crate::constants::*;
+use crate::effects::{ImpulseResponse, Reverb};
 use crate::info::*;
+use crate::loudness::measure_loudness;
+use crate::mixer::GroupMix;
+use crate::play_settings::*;
+use crate::selection::*;
+use crate::track::{TrackSegment, TrackSource};
+
+#[derive(Debug)]
+pub enum CombinationError {
+    Malformed(String),
+    WrongFile,
+    WrongLength { expected: usize, found: usize },
+    TakeOutOfRange { group: usize, take: u64, takes: usize },
+    ExcludedTake { group: usize, take: usize },
+    BreaksRules,
+    Silent,
+}
+
+impl fmt::Display for CombinationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CombinationError::Malformed(combination) => {
+                write!(f, "\"{}\" is not a combination id", combination)
+            }
+            CombinationError::WrongFile => {
+                write!(f, "combination id is mistyped or belongs to a different file")
+            }
+            CombinationError::WrongLength { expected, found } => write!(
+                f,
+                "combination id should have {} take digits, found {}",
+                expected, found
+            ),
+            CombinationError::TakeOutOfRange { group, take, takes } => write!(
+                f,
+                "combination id picks take {} in group {}, which only has {} takes",
+                take, group, takes
+            ),
+            CombinationError::ExcludedTake { group, take } => write!(
+                f,
+                "combination id picks take {} in group {}, which its weights never choose",
+                take, group
+            ),
+            CombinationError::BreaksRules => {
+                write!(f, "combination id picks takes that the file's rules do not allow together")
+            }
+            CombinationError::Silent => write!(f, "combination id leaves out every track group"),
+        }
+    }
+}
+
+impl std::error::Error for CombinationError {}
+
+#[derive(Debug)]
+pub enum SelectionError {
+    UnknownGroup(usize),
+    TakeOutOfRange { group: usize, take: usize, takes: usize },
+    NotOptional(usize),
+    BreaksRules,
+    Silent,
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectionError::UnknownGroup(group) => write!(f, "there is no track group {}", group),
+            SelectionError::TakeOutOfRange { group, take, takes } => write!(
+                f,
+                "cannot pick take {} in group {}, which only has {} takes",
+                take, group, takes
+            ),
+            SelectionError::NotOptional(group) => write!(f, "track group {} cannot be left out", group),
+            SelectionError::BreaksRules => {
+                write!(f, "the file's rules do not allow that take with the other takes playing")
+            }
+            SelectionError::Silent => write!(f, "that would leave out every track group"),
+        }
+    }
+}
+
+impl std::error::Error for SelectionError {}
 
 #[derive(Debug, Clone)]
 pub struct Prot {
@@ -10,31 +96,72 @@ pub struct Prot {
     file_path: Option<String>,
     file_paths: Option<Vec<Vec<String>>>,
     file_paths_dictionary: Option<Vec<String>>,
-    track_ids: Option<Vec<u32>>,
-    track_paths: Option<Vec<String>>,
-    duration: f64
+    play_settings: Option<PlaySettingsV1>,
+    file_path_weights: Option<Vec<Option<Vec<f32>>>>,
+    // Loudness of takes measured by analyze_loudness, by id
+    measured_loudness: HashMap<u32, Option<f32>>,
+    // Reverb the file sets, with its impulse response read from the container
+    reverb: Option<Reverb>,
+    // Chosen take of every group, per section
+    section_takes: Vec<Vec<Option<usize>>>,
+    duration: f64,
+    seed_rng: ChaCha8Rng,
+    next_seed: Option<u64>,
+    selection_seed: Option<u64>,
 }
 
 impl Prot {
     pub fn new(file_path: &String) -> Self {
-        let info = Info::new(file_path.clone());
+        match Self::try_new(file_path) {
+            Ok(this) => this,
+            Err(err) => panic!("Could not load {}: {}", file_path, err),
+        }
+    }
+
+    pub fn try_new(file_path: &str) -> Result<Self, PlaySettingsError> {
+        Self::try_new_with_seed(file_path, None)
+    }
+
+    /// Loads the file and makes the first selection from `seed`, or from a
+    /// random seed when `None`.
+    pub fn try_new_with_seed(file_path: &str, seed: Option<u64>) -> Result<Self, PlaySettingsError> {
+        // Reading the settings first turns a missing or malformed file into
+        // an error before anything probes it
+        let play_settings = PlaySettings::from_file(file_path)?.migrate();
+        let info = Info::new(file_path.to_string());
+        let available_ids: Vec<u32> = info.duration_map.keys().cloned().collect();
+        play_settings.validate(&available_ids)?;
+        let reverb = match &play_settings.reverb {
+            Some(reverb) => Some(Self::load_reverb(file_path, reverb)?),
+            None => None,
+        };
 
         let mut this = Self {
             info,
-            file_path: Some(file_path.clone()),
+            file_path: Some(file_path.to_string()),
             file_paths: None,
             file_paths_dictionary: None,
-            track_ids: None,
-            track_paths: None,
-            duration: 0.0
+            play_settings: Some(play_settings),
+            file_path_weights: None,
+            measured_loudness: HashMap::new(),
+            reverb,
+            section_takes: Vec::new(),
+            duration: 0.0,
+            seed_rng: Self::seed_rng(seed),
+            next_seed: seed,
+            selection_seed: None,
         };
 
         this.refresh_tracks();
 
-        this
+        Ok(this)
     }
 
     pub fn new_from_file_paths(file_paths: &Vec<Vec<String>>) -> Self {
+        Self::new_from_file_paths_with_seed(file_paths, None)
+    }
+
+    pub fn new_from_file_paths_with_seed(file_paths: &Vec<Vec<String>>, seed: Option<u64>) -> Self {
         let mut file_paths_dictionary = Vec::new();
         // Add all file paths to file_paths_dictionary
         // but do not add duplicates
@@ -53,9 +180,15 @@ impl Prot {
             file_path: None,
             file_paths: Some(file_paths.clone()),
             file_paths_dictionary: Some(file_paths_dictionary),
-            track_ids: None,
-            track_paths: None,
-            duration: 0.0
+            play_settings: None,
+            file_path_weights: None,
+            measured_loudness: HashMap::new(),
+            reverb: None,
+            section_takes: Vec::new(),
+            duration: 0.0,
+            seed_rng: Self::seed_rng(seed),
+            next_seed: seed,
+            selection_seed: None,
         };
 
         this.refresh_tracks();
@@ -68,93 +201,616 @@ impl Prot {
     //     let symphonia: Symphonia = Symphonia::open(file).expect("Could not open file");
     // }
 
+    fn seed_rng(seed: Option<u64>) -> ChaCha8Rng {
+        match seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+            None => ChaCha8Rng::from_entropy(),
+        }
+    }
+
+    /// Reseeds the selection and chooses new tracks. The same seed always
+    /// produces the same selection, and the shuffles that follow it are
+    /// reproducible too.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed_rng = Self::seed_rng(Some(seed));
+        self.next_seed = Some(seed);
+        self.refresh_tracks();
+    }
+
+    /// Returns the seed that produced the current selection. Passing it to
+    /// `set_seed` (or `--seed`) replays the same combination. `None` when the
+    /// selection was set with `set_combination`.
+    pub fn get_seed(&self) -> Option<u64> {
+        self.selection_seed
+    }
+
     pub fn refresh_tracks(&mut self) {
-        let mut longest_duration = 0.0;
-        
-        if let Some(file_paths) = &self.file_paths {
-            // Choose random file path from each file_paths array
-            let mut track_paths: Vec<String> = Vec::new();
-            for file_path in file_paths {
-                let random_number = rand::thread_rng().gen_range(0..file_path.len());
-                let track_path = file_path[random_number].clone();
+        // ChaCha is used over StdRng because its output is stable across
+        // rand versions and platforms, so seeds stay valid
+        let seed = match self.next_seed.take() {
+            Some(seed) => seed,
+            None => self.seed_rng.gen(),
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+        let groups = self.get_groups();
+        let rules = self.get_rules();
+
+        // Rules are checked up front, so a valid selection always exists.
+        // Every section chooses on its own.
+        let section_takes = self
+            .get_section_bounds()
+            .iter()
+            .map(|_| {
+                if rules.is_empty() {
+                    draw_takes(&groups, &mut rng)
+                } else {
+                    pick_takes(&groups, &rules, &mut rng).unwrap()
+                }
+            })
+            .collect();
+
+        self.select_takes(section_takes);
+        self.selection_seed = Some(seed);
+    }
+
+    /// Draws a new take for one track group, in every section, while the other
+    /// groups keep theirs. A different take is chosen whenever the weights and
+    /// rules allow one.
+    pub fn reroll_group(&mut self, key: usize) -> Result<(), SelectionError> {
+        let groups = self.get_groups();
+        let group = groups.get(key).ok_or(SelectionError::UnknownGroup(key))?;
+        let rules = self.get_rules();
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed_rng.gen());
 
-                let index_in_dictionary = self.file_paths_dictionary.as_ref().unwrap().iter().position(|x| *x == track_path).unwrap();
-                let duration = self.info.get_duration(index_in_dictionary as u32).unwrap();
+        let section_takes = self
+            .section_takes
+            .iter()
+            .map(|takes| {
+                // The other groups can only play the take they already have
+                let mut fixed: Vec<TrackSettingsV1> = groups
+                    .iter()
+                    .zip(takes)
+                    .map(|(group, take)| TrackSettingsV1 {
+                        ids: take.map(|take| vec![group.ids[take]]).unwrap_or_default(),
+                        ..Default::default()
+                    })
+                    .collect();
 
-                if duration > longest_duration {
-                    longest_duration = duration;
-                    self.duration = longest_duration;
+                let mut picked = None;
+                for candidate in [without_take(group, takes[key]), Some(group.clone())].into_iter().flatten() {
+                    fixed[key] = candidate;
+                    picked = pick_takes(&fixed, &rules, &mut rng)
+                        .filter(|picked| picked.iter().any(|take| take.is_some()))
+                        .map(|picked| picked[key]);
+                    if picked.is_some() {
+                        break;
+                    }
                 }
 
-                track_paths.push(track_path);
+                let mut takes = takes.clone();
+                takes[key] = picked.unwrap_or(takes[key]);
+                takes
+            })
+            .collect();
+
+        self.select_takes(section_takes);
+        self.selection_seed = None;
+
+        Ok(())
+    }
+
+    /// Plays `take`, an index into the group's takes, in one track group and
+    /// in every section, while the other groups keep theirs. `None` leaves the
+    /// group out, which only optional groups allow.
+    pub fn choose_take(&mut self, key: usize, take: Option<usize>) -> Result<(), SelectionError> {
+        let groups = self.get_groups();
+        let group = groups.get(key).ok_or(SelectionError::UnknownGroup(key))?;
+
+        match take {
+            Some(take) if take >= group.ids.len() => {
+                return Err(SelectionError::TakeOutOfRange { group: key, take, takes: group.ids.len() });
             }
+            None if !is_optional(group) => return Err(SelectionError::NotOptional(key)),
+            _ => {}
+        }
+
+        let rules = self.get_rules();
+        let mut section_takes = self.section_takes.clone();
+        for takes in section_takes.iter_mut() {
+            takes[key] = take;
 
-            self.track_paths = Some(track_paths);
+            if takes.iter().all(|take| take.is_none()) {
+                return Err(SelectionError::Silent);
+            }
 
-            return;
+            if breaks_rules(&selected_ids(&groups, takes), &rules) {
+                return Err(SelectionError::BreaksRules);
+            }
         }
 
-        if !self.file_path.is_some() {
-            return;
+        self.select_takes(section_takes);
+        self.selection_seed = None;
+
+        Ok(())
+    }
+
+    fn get_rules(&self) -> Vec<Rule> {
+        match &self.play_settings {
+            Some(play_settings) => play_settings.rules.clone(),
+            None => Vec::new(),
         }
+    }
 
-        let file_path = self.file_path.as_ref().unwrap();
-        let file = std::fs::File::open(file_path).unwrap();
+    /// Every track group in the same shape as play settings. In file path
+    /// mode the ids are positions in the file paths dictionary.
+    fn get_groups(&self) -> Vec<TrackSettingsV1> {
+        if let Some(file_paths) = &self.file_paths {
+            let dictionary = self.file_paths_dictionary.as_ref().unwrap();
 
-        let mka: Matroska = Matroska::open(file).expect("Could not open file");
+            return file_paths
+                .iter()
+                .enumerate()
+                .map(|(index, file_path)| TrackSettingsV1 {
+                    ids: file_path
+                        .iter()
+                        .map(|path| dictionary.iter().position(|x| x == path).unwrap() as u32)
+                        .collect(),
+                    weights: self.file_path_weights.as_ref().and_then(|weights| weights[index].clone()),
+                    ..Default::default()
+                })
+                .collect();
+        }
 
-        let mut track_index_array: Vec<u32> = Vec::new();
-        mka.attachments.iter().for_each(|attachment| {
-            // Only print if name is "play_settings.json"
-            if attachment.name == "play_settings.json" {
-                // read json data from attachment.data to object
-                let json_data: serde_json::Value = serde_json::from_slice(&attachment.data).unwrap();
+        match &self.play_settings {
+            Some(play_settings) => play_settings.tracks.clone(),
+            None => Vec::new(),
+        }
+    }
 
-                let encoder_version = json_data["encoder_version"].as_f64();
+    /// Sets how likely each take is to be chosen, per track group and in the
+    /// same order as the group's files or ids. `None` makes every take in the
+    /// group equally likely. Takes effect on the next `refresh_tracks`.
+    pub fn set_weights(&mut self, weights: Vec<Option<Vec<f32>>>) -> Result<(), PlaySettingsError> {
+        let groups = self.get_groups();
 
-                // For each track in json_data, print the track number
-                json_data["play_settings"]["tracks"]
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .for_each(|track| {
-                        if let Some(_version) = encoder_version {
-                            let indexes = track["ids"].as_array().unwrap();
-                            if indexes.len() == 0 {
-                                return;
-                            }
-                            let random_number = rand::thread_rng().gen_range(0..indexes.len());
-                            let index = indexes[random_number].to_string().parse::<u32>().unwrap();
-                            if let Some(track_duration) = self.info.get_duration(index) {
-                                if track_duration > longest_duration {
-                                    longest_duration = track_duration;
-                                    self.duration = longest_duration;
-                                } 
-                            }
-                            track_index_array.push(index);
-                        } else {
-                            let starting_index =
-                                track["startingIndex"].to_string().parse::<u32>().unwrap() + 1;
-                            let length = track["length"].to_string().parse::<u32>().unwrap();
-
-                            // Get random number between starting_index and starting_index + length
-                            let index =
-                                rand::thread_rng().gen_range(starting_index..(starting_index + length));
-
-                            if let Some(track_duration) = self.info.get_duration(index) {
-                                if track_duration > longest_duration {
-                                    longest_duration = track_duration;
-                                    self.duration = longest_duration;
-                                } 
-                            }
-
-                            track_index_array.push(index);
+        if weights.len() != groups.len() {
+            return Err(PlaySettingsError::InvalidField {
+                field: "weights",
+                reason: format!("expected weights for {} track groups, found {}", groups.len(), weights.len()),
+            });
+        }
+
+        for (index, (weights, group)) in weights.iter().zip(&groups).enumerate() {
+            if let Some(weights) = weights {
+                check_weights(weights, group.ids.len())
+                    .map_err(|reason| PlaySettingsError::InvalidWeights { index, reason })?;
+            }
+        }
+
+        if self.file_paths.is_some() {
+            self.file_path_weights = Some(weights);
+        } else if let Some(play_settings) = &mut self.play_settings {
+            // Zero weights can leave the rules with nothing to choose from
+            let mut weighted = play_settings.clone();
+            for (track, weights) in weighted.tracks.iter_mut().zip(weights) {
+                track.weights = weights;
+            }
+
+            if !is_satisfiable(&weighted.tracks, &weighted.rules) {
+                return Err(PlaySettingsError::Unsatisfiable);
+            }
+
+            *play_settings = weighted;
+        }
+
+        Ok(())
+    }
+
+    /// Start and end, in seconds, of every section. Files without sections
+    /// are a single section with no end.
+    pub fn get_section_bounds(&self) -> Vec<(f64, Option<f64>)> {
+        match &self.play_settings {
+            Some(play_settings) => play_settings.get_section_bounds(),
+            None => vec![(0.0, None)],
+        }
+    }
+
+    /// Length in seconds of the crossfade between sections.
+    pub fn get_crossfade(&self) -> f64 {
+        self.play_settings
+            .as_ref()
+            .and_then(|play_settings| play_settings.crossfade)
+            .unwrap_or(DEFAULT_SECTION_CROSSFADE)
+    }
+
+    /// Gain, pan, mute and solo of every track group as the play settings
+    /// set them. Groups in file path mode start at the defaults.
+    pub fn get_group_mixes(&self) -> Vec<GroupMix> {
+        self.get_groups().iter().map(GroupMix::from_settings).collect()
+    }
+
+    /// Measures the loudness of every take the play settings do not already
+    /// give one for, so that loudness normalization covers them too. Decodes
+    /// each of those takes in full.
+    pub fn analyze_loudness(&mut self) {
+        for group in self.get_groups() {
+            if group.loudness.is_some() {
+                continue;
+            }
+
+            for id in group.ids {
+                if !self.measured_loudness.contains_key(&id) {
+                    let source = self.get_track_source(id);
+                    let loudness = measure_loudness(&source.file_path, source.track_id);
+                    self.measured_loudness.insert(id, loudness);
+                }
+            }
+        }
+    }
+
+    // Loudness in LUFS of one take of a group, if it is known and not silent
+    fn get_take_loudness(&self, group: &TrackSettingsV1, take: usize) -> Option<f32> {
+        match &group.loudness {
+            Some(loudness) => loudness[take],
+            None => self.measured_loudness.get(&group.ids[take]).copied().flatten(),
+        }
+    }
+
+    // Average loudness in LUFS of the takes of a group that have one
+    fn get_group_loudness(&self, group: &TrackSettingsV1) -> Option<f32> {
+        let known: Vec<f32> = (0..group.ids.len()).filter_map(|take| self.get_take_loudness(group, take)).collect();
+        if known.is_empty() {
+            return None;
+        }
+        Some(known.iter().sum::<f32>() / known.len() as f32)
+    }
+
+    fn get_loudness_window(&self) -> f32 {
+        self.play_settings
+            .as_ref()
+            .and_then(|play_settings| play_settings.loudness_window)
+            .unwrap_or(DEFAULT_LOUDNESS_WINDOW)
+    }
+
+    // Gain in dB for each take of a group that brings its loudness within
+    // the loudness window around the average of the group's takes. Takes of
+    // unknown loudness are left alone.
+    fn get_loudness_compensation(&self, group: &TrackSettingsV1) -> Vec<f32> {
+        let window = self.get_loudness_window();
+        let average = self.get_group_loudness(group);
+
+        (0..group.ids.len())
+            .map(|take| match (self.get_take_loudness(group, take), average) {
+                (Some(loudness), Some(average)) => window_compensation(loudness - average, window),
+                _ => 0.0,
+            })
+            .collect()
+    }
+
+    // Gain in dB that brings the loudness of the whole combination, its
+    // takes evened out within their groups, within the loudness window
+    // around that of every group playing at its average. Takes add up by
+    // energy, as unrelated signals do, and each section counts for its
+    // length. Groups left out make the combination quieter, so it is turned
+    // up.
+    fn get_combination_compensation(&self, groups: &[TrackSettingsV1], compensations: &[Vec<f32>]) -> f32 {
+        let energy = |loudness: f32| 10f64.powf(loudness as f64 / 10.0);
+
+        let reference: f64 = groups.iter().filter_map(|group| self.get_group_loudness(group)).map(energy).sum();
+        let mut combination = 0.0;
+        let mut total_length = 0.0;
+        for ((start, end), takes) in self.get_section_bounds().into_iter().zip(&self.section_takes) {
+            let length = (end.unwrap_or(self.duration) - start).max(0.0);
+            let section: f64 = groups
+                .iter()
+                .zip(compensations)
+                .zip(takes)
+                .filter_map(|((group, compensation), take)| {
+                    let take = (*take)?;
+                    Some(energy(self.get_take_loudness(group, take)? + compensation[take]))
+                })
+                .sum();
+            combination += length * section;
+            total_length += length;
+        }
+
+        if reference == 0.0 || combination == 0.0 {
+            return 0.0;
+        }
+        let difference = 10.0 * (combination / total_length / reference).log10();
+        window_compensation(difference as f32, self.get_loudness_window())
+    }
+
+    /// The reverb the file sets over the mix, if any.
+    pub fn get_reverb(&self) -> Option<Reverb> {
+        self.reverb.clone()
+    }
+
+    fn load_reverb(file_path: &str, settings: &ReverbSettings) -> Result<Reverb, PlaySettingsError> {
+        let data = PlaySettings::read_attachment(file_path, &settings.impulse_response)?;
+        let impulse_response = ImpulseResponse::from_bytes(data).map_err(|err| PlaySettingsError::InvalidAttachment {
+            name: settings.impulse_response.clone(),
+            reason: err.to_string(),
+        })?;
+
+        Ok(Reverb {
+            impulse_response: Arc::new(impulse_response),
+            wet: settings.wet.unwrap_or(DEFAULT_REVERB_WET),
+            dry: settings.dry.unwrap_or(DEFAULT_REVERB_DRY),
+        })
+    }
+
+    /// Volume and pan envelopes of every track group. Groups without any are
+    /// left at their mix.
+    pub fn get_group_automation(&self) -> Vec<AutomationSettings> {
+        self.get_groups()
+            .into_iter()
+            .map(|group| group.automation.unwrap_or_default())
+            .collect()
+    }
+
+    fn select_takes(&mut self, section_takes: Vec<Vec<Option<usize>>>) {
+        let mut longest_duration = 0.0;
+
+        // A take plays until the end of its section or until it runs out
+        let groups = self.get_groups();
+        for ((start, end), takes) in self.get_section_bounds().into_iter().zip(&section_takes) {
+            for (group, take) in groups.iter().zip(takes) {
+                if let Some(take) = take {
+                    if let Some(duration) = self.info.get_duration(group.ids[*take]) {
+                        let section_duration = duration.min(end.unwrap_or(f64::INFINITY));
+                        if duration > start && section_duration > longest_duration {
+                            longest_duration = section_duration;
                         }
-                    });
+                    }
+                }
+            }
+        }
+
+        self.section_takes = section_takes;
+        self.duration = longest_duration;
+    }
+
+    // Chosen container track ids (or dictionary positions) of each group that
+    // plays, in key order and without repeats
+    fn get_selected_ids(&self) -> Vec<(u32, u32)> {
+        let mut selected_ids: Vec<(u32, u32)> = Vec::new();
+
+        for (key, group) in self.get_groups().iter().enumerate() {
+            for takes in &self.section_takes {
+                if let Some(take) = takes[key] {
+                    let selected = (key as u32, group.ids[take]);
+                    if !selected_ids.contains(&selected) {
+                        selected_ids.push(selected);
+                    }
+                }
+            }
+        }
+
+        selected_ids
+    }
+
+    // What each playing group plays over the song. Consecutive sections with
+    // the same take become one segment so the take is not cut.
+    pub(crate) fn get_track_segments(&self) -> Vec<(i32, Vec<TrackSegment>)> {
+        let groups = self.get_groups();
+        let bounds = self.get_section_bounds();
+        let compensations: Vec<Vec<f32>> = groups.iter().map(|group| self.get_loudness_compensation(group)).collect();
+        let combination_compensation = self.get_combination_compensation(&groups, &compensations);
+        let mut track_segments = Vec::new();
+
+        for (key, group) in groups.iter().enumerate() {
+            let mut segments: Vec<TrackSegment> = Vec::new();
+
+            for ((start, end), takes) in bounds.iter().zip(&self.section_takes) {
+                let source = takes[key].map(|take| self.get_track_source(group.ids[take]));
+                let gain = match (takes[key], &group.take_gains) {
+                    (Some(take), Some(take_gains)) => 10f32.powf(take_gains[take] / 20.0),
+                    _ => 1.0,
+                };
+                let loudness_gain = match takes[key] {
+                    Some(take) => {
+                        let compensation = compensations[key][take] + combination_compensation;
+                        10f32.powf(compensation.clamp(-MAX_LOUDNESS_COMPENSATION, MAX_LOUDNESS_COMPENSATION) / 20.0)
+                    }
+                    None => 1.0,
+                };
+
+                match segments.last_mut() {
+                    Some(previous) if previous.source == source => previous.end = *end,
+                    _ => segments.push(TrackSegment { source, start: *start, end: *end, gain, loudness_gain }),
+                }
+            }
+
+            // Nothing to play after the last take
+            while segments.last().is_some_and(|segment| segment.source.is_none()) {
+                segments.pop();
+            }
+
+            if let Some(last) = segments.last_mut() {
+                last.end = None;
+                track_segments.push((key as i32, segments));
+            }
+        }
+
+        track_segments
+    }
+
+    fn get_track_source(&self, id: u32) -> TrackSource {
+        match (&self.file_paths_dictionary, &self.file_path) {
+            (Some(dictionary), _) => TrackSource {
+                file_path: dictionary[id as usize].clone(),
+                track_id: None,
+            },
+            (None, file_path) => TrackSource {
+                file_path: file_path.clone().unwrap_or_default(),
+                track_id: Some(id),
+            },
+        }
+    }
+
+    /// Returns a short token identifying the current take in every group, e.g.
+    /// `"3k0z-102"`. The prefix is a checksum of the file's groups and of the
+    /// takes, so a token is only accepted by the file it came from, and a
+    /// mistyped one is turned down. Groups that may be left out use one past
+    /// their last take to mean "not playing". With sections, the takes of
+    /// each section follow one another.
+    pub fn get_combination(&self) -> String {
+        format_combination(self.get_groups_hash(), &self.get_groups(), &self.section_takes)
+    }
+
+    /// Number of different combinations the file can play: every choice of
+    /// takes, in every section, that the weights and rules allow.
+    /// Saturates at `u128::MAX`.
+    pub fn count_combinations(&self) -> u128 {
+        let per_section = count_selections(&self.get_groups(), &self.get_rules());
+        let section_count = self.get_section_bounds().len() as u32;
+
+        per_section.saturating_pow(section_count)
+    }
+
+    /// Iterates the ids of every combination counted by `count_combinations`,
+    /// without building the whole list. Each id can be passed to
+    /// `set_combination`.
+    pub fn combinations(&self) -> Combinations {
+        let groups = self.get_groups();
+        let options: Vec<Vec<Option<usize>>> = groups.iter().map(take_options).collect();
+        let section_count = self.get_section_bounds().len();
+
+        Combinations {
+            groups_hash: self.get_groups_hash(),
+            rules: self.get_rules(),
+            positions: vec![0; groups.len() * section_count],
+            groups,
+            options,
+            done: false,
+        }
+    }
+
+    /// Selects the exact takes described by a token from `get_combination`.
+    pub fn set_combination(&mut self, combination: &str) -> Result<(), CombinationError> {
+        let section_takes = self.parse_combination(combination)?;
+
+        let groups = self.get_groups();
+        for takes in &section_takes {
+            if takes.iter().all(|take| take.is_none()) {
+                return Err(CombinationError::Silent);
+            }
+
+            // Takes the weights never choose are only in hand-made ids, or
+            // ids from before the weights changed
+            for (group, (options, take)) in groups.iter().map(take_options).zip(takes).enumerate() {
+                if let Some(take) = take.filter(|take| !options.contains(&Some(*take))) {
+                    return Err(CombinationError::ExcludedTake { group, take });
+                }
+            }
+
+            if let Some(play_settings) = &self.play_settings {
+                if breaks_rules(&selected_ids(&play_settings.tracks, takes), &play_settings.rules) {
+                    return Err(CombinationError::BreaksRules);
+                }
+            }
+        }
+
+        self.select_takes(section_takes);
+        self.selection_seed = None;
+
+        Ok(())
+    }
+
+    fn parse_combination(&self, combination: &str) -> Result<Vec<Vec<Option<usize>>>, CombinationError> {
+        let (checksum, digits) = combination
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| CombinationError::Malformed(combination.to_string()))?;
+
+        let digits = digits.to_ascii_lowercase();
+        if !checksum.eq_ignore_ascii_case(&combination_checksum(self.get_groups_hash(), &digits)) {
+            return Err(CombinationError::WrongFile);
+        }
+
+        let groups = self.get_groups();
+        let section_count = self.get_section_bounds().len();
+        let expected: usize = groups
+            .iter()
+            .filter(|group| !group.ids.is_empty())
+            .map(|group| base36_width(option_count(group)))
+            .sum::<usize>()
+            * section_count;
+        if digits.len() != expected {
+            return Err(CombinationError::WrongLength { expected, found: digits.len() });
+        }
+
+        let mut section_takes = Vec::new();
+        let mut takes = Vec::new();
+        let mut offset = 0;
+        for (group_index, group) in groups.iter().enumerate().cycle().take(groups.len() * section_count) {
+            if group_index == 0 && !takes.is_empty() {
+                section_takes.push(std::mem::take(&mut takes));
+            }
+
+            if group.ids.is_empty() {
+                takes.push(None);
+                continue;
+            }
+
+            let count = option_count(group);
+            let width = base36_width(count);
+            let take = digits
+                .get(offset..offset + width)
+                .and_then(|digits| u64::from_str_radix(digits, 36).ok())
+                .ok_or_else(|| CombinationError::Malformed(combination.to_string()))?;
+
+            if take >= count as u64 {
+                return Err(CombinationError::TakeOutOfRange {
+                    group: group_index,
+                    take,
+                    takes: group.ids.len(),
+                });
+            }
+
+            takes.push(Some(take as usize).filter(|take| *take < group.ids.len()));
+            offset += width;
+        }
+
+        if !takes.is_empty() {
+            section_takes.push(takes);
+        }
+
+        Ok(section_takes)
+    }
+
+    // FNV-1a over the shape of every group and the tracks it can play, to be
+    // carried on over the takes of a combination id
+    fn get_groups_hash(&self) -> u32 {
+        let mut hash: u32 = 0x811c9dc5;
+        let mut add = |bytes: &[u8]| hash = fnv1a(hash, bytes);
+
+        if let Some(file_paths) = &self.file_paths {
+            for file_path in file_paths {
+                add(&(file_path.len() as u32).to_le_bytes());
+                for path in file_path {
+                    add(path.as_bytes());
+                }
+            }
+        } else if let Some(play_settings) = &self.play_settings {
+            if !play_settings.sections.is_empty() {
+                add(&(play_settings.sections.len() as u32).to_le_bytes());
+            }
+
+            for track in play_settings.tracks.iter().filter(|track| !track.ids.is_empty()) {
+                add(&(track.ids.len() as u32).to_le_bytes());
+                for id in &track.ids {
+                    add(&id.to_le_bytes());
+                }
+                if is_optional(track) {
+                    add(b"?");
+                }
             }
-        });
+        }
 
-        self.track_ids = Some(track_index_array);
+        hash
     }
 
     fn get_audio_settings(file_path: &str) -> Audio {
@@ -203,66 +859,79 @@ impl Prot {
         // first_audio_settings
     }
 
+    /// Keys of the track groups that play in the current selection. A key is
+    /// the group's position in the play settings (or file paths), so groups
+    /// that are left out leave gaps.
     pub fn get_keys(&self) -> Vec<u32> {
-        // This should just be a range from 0 to the length of the track_paths or track_ids array
-        if let Some(track_paths) = &self.track_paths {
-            return (0..track_paths.len() as u32).collect();
-        }
-
-        if let Some(track_ids) = &self.track_ids {
-            return (0..track_ids.len() as u32).collect();
-        }
+        let mut keys: Vec<u32> = self.get_selected_ids().into_iter().map(|(key, _)| key).collect();
+        keys.dedup();
 
-        Vec::new()
+        keys
     }
 
     pub fn get_ids(&self) ->  Vec<String> {
-        if let Some(track_paths) = &self.track_paths {
-            return track_paths.clone();
-        }
+        if self.file_paths.is_some() {
+            let dictionary = self.file_paths_dictionary.as_ref().unwrap();
 
-        if let Some(track_ids) = &self.track_ids {
-            return track_ids.into_iter().map(|id| format!("{}", id)).collect();
+            return self
+                .get_selected_ids()
+                .into_iter()
+                .map(|(_, id)| dictionary[id as usize].clone())
+                .collect();
         }
 
-        Vec::new()
+        self.get_selected_ids().into_iter().map(|(_, id)| format!("{}", id)).collect()
     }
 
+    /// Every take in the current selection with the key of its group. A group
+    /// that switches takes between sections is listed once per take.
     pub fn enumerated_list(&self) -> Vec<(i32, String, Option<u32>)> {
-        let mut list: Vec<(i32, String, Option<u32>)> = Vec::new();
-        if let Some(track_paths) = &self.track_paths {
-            for (index, file_path) in track_paths.iter().enumerate() {
-                list.push((index as i32, String::from(file_path), None));
-            }
+        if self.file_paths.is_some() {
+            let dictionary = self.file_paths_dictionary.as_ref().unwrap();
 
-            return list;
+            return self
+                .get_selected_ids()
+                .into_iter()
+                .map(|(key, id)| (key as i32, dictionary[id as usize].clone(), None))
+                .collect();
         }
 
-        if let Some(track_ids) = &self.track_ids {
-            for (index, track_id) in track_ids.iter().enumerate() {
-                list.push((index as i32, String::from(self.file_path.as_ref().unwrap()), Some(*track_id)));
-            }
-
-            return list;
+        match &self.file_path {
+            Some(file_path) => self
+                .get_selected_ids()
+                .into_iter()
+                .map(|(key, id)| (key as i32, file_path.clone(), Some(id)))
+                .collect(),
+            None => Vec::new(),
         }
+    }
+
+    /// Every track group with its key, name and takes. Takes are listed as
+    /// container track ids, or as file paths in file path mode.
+    pub fn get_group_list(&self) -> Vec<(i32, Option<String>, Vec<String>)> {
+        let take_name = |id: &u32| match &self.file_paths_dictionary {
+            Some(dictionary) => dictionary[*id as usize].clone(),
+            None => format!("{}", id),
+        };
 
-        list
+        self.get_groups()
+            .iter()
+            .enumerate()
+            .map(|(key, group)| (key as i32, group.name.clone(), group.ids.iter().map(take_name).collect()))
+            .collect()
     }
 
     pub fn get_duration(&self) -> &f64 {
         &self.duration
     }
 
+    /// Number of track groups that play in the current selection.
     pub fn get_length(&self) -> usize {
-        if let Some(file_paths) = &self.file_paths {
-            return file_paths.len();
-        }
-
-        if let Some(track_ids) = &self.track_ids {
-            return track_ids.len();
-        }
+        self.get_keys().len()
+    }
 
-        0
+    pub fn get_play_settings(&self) -> Option<&PlaySettingsV1> {
+        self.play_settings.as_ref()
     }
 
     pub fn get_file_paths_dictionary(&self) -> Vec<String> {
@@ -271,4 +940,150 @@ impl Prot {
             None => Vec::new()
         }
     }
-}
\ No newline at end of file
+}
+
+/// Every combination id of a file, in order. See `Prot::combinations`.
+pub struct Combinations {
+    groups_hash: u32,
+    groups: Vec<TrackSettingsV1>,
+    rules: Vec<Rule>,
+    options: Vec<Vec<Option<usize>>>,
+    // Chosen option of every group, per section
+    positions: Vec<usize>,
+    done: bool,
+}
+
+impl Combinations {
+    fn advance(&mut self) {
+        for (index, position) in self.positions.iter_mut().enumerate().rev() {
+            *position += 1;
+            if *position < self.options[index % self.groups.len()].len() {
+                return;
+            }
+            *position = 0;
+        }
+
+        self.done = true;
+    }
+}
+
+impl Iterator for Combinations {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        while !self.done {
+            let section_takes: Vec<Vec<Option<usize>>> = self
+                .positions
+                .chunks(self.groups.len().max(1))
+                .map(|positions| {
+                    positions
+                        .iter()
+                        .zip(&self.options)
+                        .map(|(position, options)| options[*position])
+                        .collect()
+                })
+                .collect();
+            self.advance();
+
+            if section_takes
+                .iter()
+                .all(|takes| is_valid_selection(&self.groups, &self.rules, takes))
+            {
+                return Some(format_combination(self.groups_hash, &self.groups, &section_takes));
+            }
+        }
+
+        None
+    }
+}
+
+fn format_combination(groups_hash: u32, groups: &[TrackSettingsV1], section_takes: &[Vec<Option<usize>>]) -> String {
+    let takes: String = section_takes
+        .iter()
+        .flat_map(|takes| groups.iter().zip(takes))
+        .filter(|(group, _)| !group.ids.is_empty())
+        .map(|(group, take)| {
+            let take = take.unwrap_or(group.ids.len());
+            to_base36(take as u64, base36_width(option_count(group)))
+        })
+        .collect();
+
+    format!("{}-{}", combination_checksum(groups_hash, &takes), takes)
+}
+
+// Gain in dB that brings a loudness `difference` dB from its target back
+// within `window` dB of it, up to the most compensation allowed
+fn window_compensation(difference: f32, window: f32) -> f32 {
+    let excess = (difference.abs() - window).max(0.0);
+    (-difference.signum() * excess).clamp(-MAX_LOUDNESS_COMPENSATION, MAX_LOUDNESS_COMPENSATION)
+}
+
+// Characters in the checksum of a combination id. A mistyped id gets past
+// one in 36^4, about 1.7 million.
+const CHECKSUM_WIDTH: usize = 4;
+
+fn combination_checksum(groups_hash: u32, takes: &str) -> String {
+    let hash = fnv1a(groups_hash, takes.as_bytes());
+    to_base36((hash % 36u32.pow(CHECKSUM_WIDTH as u32)) as u64, CHECKSUM_WIDTH)
+}
+
+fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+
+    hash
+}
+
+// The group with its current choice ruled out, or None when nothing else
+// could be chosen
+fn without_take(group: &TrackSettingsV1, current: Option<usize>) -> Option<TrackSettingsV1> {
+    let mut group = group.clone();
+
+    match current {
+        Some(take) => {
+            let mut weights = group.weights.clone().unwrap_or_else(|| vec![1.0; group.ids.len()]);
+            weights[take] = 0.0;
+
+            if weights.iter().any(|weight| *weight > 0.0) {
+                group.weights = Some(weights);
+            } else if is_optional(&group) {
+                group.presence = Some(0.0);
+            } else {
+                return None;
+            }
+        }
+        None => group.presence = None,
+    }
+
+    Some(group)
+}
+
+// Takes in the group, plus one for leaving it out when that is allowed
+fn option_count(group: &TrackSettingsV1) -> usize {
+    if is_optional(group) {
+        group.ids.len() + 1
+    } else {
+        group.ids.len()
+    }
+}
+
+fn base36_width(count: usize) -> usize {
+    let mut width = 1;
+    while 36usize.pow(width as u32) < count {
+        width += 1;
+    }
+
+    width
+}
+
+fn to_base36(mut value: u64, width: usize) -> String {
+    let mut digits = vec![b'0'; width];
+    for digit in digits.iter_mut().rev() {
+        *digit = b"0123456789abcdefghijklmnopqrstuvwxyz"[(value % 36) as usize];
+        value /= 36;
+    }
+
+    String::from_utf8(digits).unwrap()
+}
@@ -0,0 +1,691 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::warn;
+use rand::Rng;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::Decoder;
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatReader;
+
+use crate::constants::*;
+use crate::ebml::*;
+use crate::effects::{ImpulseResponse, ImpulseResponseError};
+use crate::flac::*;
+use crate::loudness::measure_loudness;
+use crate::play_settings::*;
+use crate::resample::gcd;
+use crate::tools::open_file;
+
+#[derive(Debug)]
+pub enum ProtWriteError {
+    Io(std::io::Error),
+    Decode { file_path: String, error: Error },
+    NoSources,
+    MissingSource(String),
+    UnsupportedSource { file_path: String, reason: String },
+    MismatchedSource { file_path: String, reason: String },
+    ImpulseResponse { file_path: String, error: ImpulseResponseError },
+    PlaySettings(PlaySettingsError),
+}
+
+impl fmt::Display for ProtWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtWriteError::Io(err) => write!(f, "could not write file: {}", err),
+            ProtWriteError::Decode { file_path, error } => {
+                write!(f, "could not decode {}: {}", file_path, error)
+            }
+            ProtWriteError::NoSources => write!(f, "no source files were given"),
+            ProtWriteError::MissingSource(file_path) => write!(f, "{} does not exist", file_path),
+            ProtWriteError::UnsupportedSource { file_path, reason } => {
+                write!(f, "{} is not supported: {}", file_path, reason)
+            }
+            ProtWriteError::MismatchedSource { file_path, reason } => {
+                write!(f, "{} does not match the other sources: {}", file_path, reason)
+            }
+            ProtWriteError::ImpulseResponse { file_path, error } => write!(f, "{}: {}", file_path, error),
+            ProtWriteError::PlaySettings(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProtWriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtWriteError::Io(err) => Some(err),
+            ProtWriteError::Decode { error, .. } => Some(error),
+            ProtWriteError::ImpulseResponse { error, .. } => Some(error),
+            ProtWriteError::PlaySettings(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtWriteError {
+    fn from(err: std::io::Error) -> Self {
+        ProtWriteError::Io(err)
+    }
+}
+
+impl From<PlaySettingsError> for ProtWriteError {
+    fn from(err: PlaySettingsError) -> Self {
+        ProtWriteError::PlaySettings(err)
+    }
+}
+
+// Matroska timestamps are written in milliseconds
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+
+// Audio tracks are always FLAC encoded. Symphonia cannot decode PCM tracks in
+// Matroska because the container does not carry a maximum packet size.
+
+#[derive(Debug, Clone)]
+pub struct ProtWriter {
+    file_paths: Vec<Vec<String>>,
+    names: Vec<Option<String>>,
+    weights: Vec<Option<Vec<f32>>>,
+    presence: Vec<Option<f32>>,
+    gains: Vec<Option<f32>>,
+    take_gains: Vec<Option<Vec<f32>>>,
+    automation: Vec<Option<AutomationSettings>>,
+    rules: Vec<Rule>,
+    sections: Vec<SectionSettings>,
+    crossfade: Option<f64>,
+    loudness_window: Option<f32>,
+    measure_loudness: bool,
+    impulse_response: Option<String>,
+    reverb_wet: Option<f32>,
+    reverb_dry: Option<f32>,
+}
+
+impl ProtWriter {
+    pub fn new(file_paths: &[Vec<String>]) -> Self {
+        Self {
+            file_paths: file_paths.to_vec(),
+            names: vec![None; file_paths.len()],
+            weights: vec![None; file_paths.len()],
+            presence: vec![None; file_paths.len()],
+            gains: vec![None; file_paths.len()],
+            take_gains: vec![None; file_paths.len()],
+            automation: vec![None; file_paths.len()],
+            rules: Vec::new(),
+            sections: Vec::new(),
+            crossfade: None,
+            loudness_window: None,
+            measure_loudness: false,
+            impulse_response: None,
+            reverb_wet: None,
+            reverb_dry: None,
+        }
+    }
+
+    // Names are matched to track groups by position
+    pub fn set_names(&mut self, names: Vec<String>) {
+        for (index, name) in names.into_iter().enumerate() {
+            if index < self.names.len() {
+                self.names[index] = Some(name);
+            }
+        }
+    }
+
+    // Weights are matched to track groups by position, one per file
+    pub fn set_weights(&mut self, weights: Vec<Option<Vec<f32>>>) {
+        for (index, weights) in weights.into_iter().enumerate() {
+            if index < self.weights.len() {
+                self.weights[index] = weights;
+            }
+        }
+    }
+
+    // Presence is matched to track groups by position
+    pub fn set_presence(&mut self, presence: Vec<Option<f32>>) {
+        for (index, presence) in presence.into_iter().enumerate() {
+            if index < self.presence.len() {
+                self.presence[index] = presence;
+            }
+        }
+    }
+
+    // Gains, in dB, are matched to track groups by position
+    pub fn set_gains(&mut self, gains: Vec<Option<f32>>) {
+        for (index, gain) in gains.into_iter().enumerate() {
+            if index < self.gains.len() {
+                self.gains[index] = gain;
+            }
+        }
+    }
+
+    // Take gains are matched to track groups by position, one per file
+    pub fn set_take_gains(&mut self, take_gains: Vec<Option<Vec<f32>>>) {
+        for (index, take_gains) in take_gains.into_iter().enumerate() {
+            if index < self.take_gains.len() {
+                self.take_gains[index] = take_gains;
+            }
+        }
+    }
+
+    // Automation is matched to track groups by position
+    pub fn set_automation(&mut self, automation: Vec<Option<AutomationSettings>>) {
+        for (index, automation) in automation.into_iter().enumerate() {
+            if index < self.automation.len() {
+                self.automation[index] = automation;
+            }
+        }
+    }
+
+    // Rule takes are container track ids, see get_play_settings
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.rules = rules;
+    }
+
+    pub fn set_sections(&mut self, sections: Vec<SectionSettings>) {
+        self.sections = sections;
+    }
+
+    pub fn set_crossfade(&mut self, crossfade: Option<f64>) {
+        self.crossfade = crossfade;
+    }
+
+    pub fn set_loudness_window(&mut self, loudness_window: Option<f32>) {
+        self.loudness_window = loudness_window;
+    }
+
+    // Measuring decodes every file once more before it is encoded
+    pub fn set_measure_loudness(&mut self, measure_loudness: bool) {
+        self.measure_loudness = measure_loudness;
+    }
+
+    // The impulse response is attached under its file name
+    pub fn set_reverb(&mut self, impulse_response: Option<String>, wet: Option<f32>, dry: Option<f32>) {
+        self.impulse_response = impulse_response;
+        self.reverb_wet = wet;
+        self.reverb_dry = dry;
+    }
+
+    pub fn get_file_paths_dictionary(&self) -> Vec<String> {
+        let mut file_paths_dictionary: Vec<String> = Vec::new();
+        for file_path in &self.file_paths {
+            for path in file_path {
+                if !file_paths_dictionary.contains(path) {
+                    file_paths_dictionary.push(path.clone());
+                }
+            }
+        }
+
+        file_paths_dictionary
+    }
+
+    // Container track ids are the (one based) position in the file paths dictionary
+    pub fn get_play_settings(&self) -> PlaySettingsV1 {
+        let file_paths_dictionary = self.get_file_paths_dictionary();
+
+        let tracks = self
+            .file_paths
+            .iter()
+            .enumerate()
+            .map(|(index, file_paths)| TrackSettingsV1 {
+                ids: file_paths
+                    .iter()
+                    .map(|path| file_paths_dictionary.iter().position(|x| x == path).unwrap() as u32 + 1)
+                    .collect(),
+                name: self.names[index].clone(),
+                weights: self.weights[index].clone(),
+                presence: self.presence[index],
+                gain: self.gains[index],
+                take_gains: self.take_gains[index].clone(),
+                automation: self.automation[index].clone(),
+                ..Default::default()
+            })
+            .collect();
+
+        PlaySettingsV1 {
+            tracks,
+            rules: self.rules.clone(),
+            sections: self.sections.clone(),
+            crossfade: self.crossfade,
+            loudness_window: self.loudness_window,
+            reverb: self.impulse_response.as_ref().map(|file_path| ReverbSettings {
+                impulse_response: attachment_name(file_path),
+                wet: self.reverb_wet,
+                dry: self.reverb_dry,
+            }),
+        }
+    }
+
+    pub fn write(&self, output_path: &str) -> Result<(), ProtWriteError> {
+        let file_paths_dictionary = self.get_file_paths_dictionary();
+        if file_paths_dictionary.is_empty() {
+            return Err(ProtWriteError::NoSources);
+        }
+
+        let mut play_settings = self.get_play_settings();
+        let ids: Vec<u32> = (1..=file_paths_dictionary.len() as u32).collect();
+        play_settings.validate(&ids)?;
+
+        let mut sources: Vec<SourceTrack> = Vec::new();
+        for (index, file_path) in file_paths_dictionary.iter().enumerate() {
+            sources.push(SourceTrack::open(file_path, index as u64 + 1)?);
+        }
+
+        // ===== Check that every source can share one stream format ===== //
+        let sample_rate = sources[0].sample_rate;
+        let channels = sources[0].channels;
+        for source in &sources {
+            if source.sample_rate != sample_rate {
+                return Err(ProtWriteError::MismatchedSource {
+                    file_path: source.file_path.clone(),
+                    reason: format!("sample rate is {} Hz, expected {} Hz", source.sample_rate, sample_rate),
+                });
+            }
+
+            if source.channels != channels {
+                return Err(ProtWriteError::MismatchedSource {
+                    file_path: source.file_path.clone(),
+                    reason: format!("has {} channels, expected {}", source.channels, channels),
+                });
+            }
+        }
+
+        if self.measure_loudness {
+            let loudness: Vec<Option<f32>> =
+                file_paths_dictionary.iter().map(|file_path| measure_loudness(file_path, None)).collect();
+            for track in play_settings.tracks.iter_mut() {
+                track.loudness = Some(track.ids.iter().map(|id| loudness[*id as usize - 1]).collect());
+            }
+        }
+
+        let impulse_response = match &self.impulse_response {
+            Some(file_path) => {
+                ImpulseResponse::load(file_path).map_err(|error| ProtWriteError::ImpulseResponse {
+                    file_path: file_path.clone(),
+                    error,
+                })?;
+                Some((attachment_name(file_path), std::fs::read(file_path)?))
+            }
+            None => None,
+        };
+
+        let bits_per_sample = if sources.iter().any(|source| source.bits_per_sample > 16) {
+            24
+        } else {
+            16
+        };
+
+        for source in sources.iter_mut() {
+            source.shift = 32 - bits_per_sample;
+            let encoder = FlacEncoder::new(sample_rate, channels, bits_per_sample);
+            source.encoder = Some(encoder.with_block_size(block_frames(sample_rate)));
+        }
+
+        let longest_frames = sources.iter().map(|source| source.frames).max().unwrap();
+        let duration = frames_to_timestamp(longest_frames, sample_rate) as f64;
+
+        // ===== Header elements ===== //
+        let mut header: Vec<u8> = Vec::new();
+        master_element(&mut header, EBML_HEADER, |b| {
+            uint_element(b, EBML_VERSION, 1);
+            uint_element(b, EBML_READ_VERSION, 1);
+            uint_element(b, EBML_MAX_ID_LENGTH, 4);
+            uint_element(b, EBML_MAX_SIZE_LENGTH, 8);
+            string_element(b, DOC_TYPE, "matroska");
+            uint_element(b, DOC_TYPE_VERSION, 4);
+            uint_element(b, DOC_TYPE_READ_VERSION, 2);
+        });
+
+        write_id(&mut header, SEGMENT);
+        let segment_size_offset = header.len();
+        write_size_with_length(&mut header, 0, PLACEHOLDER_SIZE_LENGTH);
+        let segment_start = header.len();
+
+        let app_name = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        master_element(&mut header, INFO, |b| {
+            uint_element(b, TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+            string_element(b, MUXING_APP, &app_name);
+            string_element(b, WRITING_APP, &app_name);
+            float_element(b, DURATION, duration);
+        });
+
+        master_element(&mut header, TRACKS, |b| {
+            for source in &sources {
+                self.write_track_entry(b, source, sample_rate, channels, bits_per_sample);
+            }
+        });
+
+        let play_settings = PlaySettings::V1 {
+            encoder_version: ENCODER_VERSION,
+            settings: play_settings,
+        };
+        let play_settings_data = serde_json::to_vec_pretty(&play_settings.to_json()).unwrap();
+
+        master_element(&mut header, ATTACHMENTS, |b| {
+            master_element(b, ATTACHED_FILE, |b| {
+                string_element(b, FILE_NAME, PLAY_SETTINGS_FILE_NAME);
+                string_element(b, FILE_MIME_TYPE, PLAY_SETTINGS_MIME_TYPE);
+                element(b, FILE_DATA, &play_settings_data);
+                uint_element(b, FILE_UID, random_uid());
+            });
+
+            if let Some((name, data)) = &impulse_response {
+                master_element(b, ATTACHED_FILE, |b| {
+                    string_element(b, FILE_NAME, name);
+                    string_element(b, FILE_MIME_TYPE, audio_mime_type(name));
+                    element(b, FILE_DATA, data);
+                    uint_element(b, FILE_UID, random_uid());
+                });
+            }
+        });
+
+        // Per track durations, read back by `info::get_durations`
+        master_element(&mut header, TAGS, |b| {
+            for source in &sources {
+                master_element(b, TAG, |b| {
+                    master_element(b, TARGETS, |b| {
+                        uint_element(b, TARGET_TYPE_VALUE, 50);
+                        uint_element(b, TAG_TRACK_UID, source.track_uid);
+                    });
+                    master_element(b, SIMPLE_TAG, |b| {
+                        string_element(b, TAG_NAME, "DURATION");
+                        string_element(b, TAG_STRING, &format_duration(source.frames, sample_rate));
+                    });
+                });
+            }
+        });
+
+        let mut file = BufWriter::new(File::create(output_path)?);
+        file.write_all(&header)?;
+        let mut segment_size = (header.len() - segment_start) as u64;
+
+        // ===== Clusters ===== //
+        // Each cluster holds roughly one second of every track, interleaved by timestamp
+        let frames_per_cluster = sample_rate as u64;
+        let mut cluster_index = 0;
+        loop {
+            let cluster_start = cluster_index * frames_per_cluster;
+            let cluster_end = cluster_start + frames_per_cluster;
+            let cluster_timestamp = frames_to_timestamp(cluster_start, sample_rate);
+
+            let mut blocks: Vec<(u64, u64, Vec<u8>)> = Vec::new();
+            for source in sources.iter_mut() {
+                while source.frames_written < cluster_end {
+                    let timestamp = frames_to_timestamp(source.frames_written, sample_rate);
+                    match source.read_block(block_frames(sample_rate))? {
+                        Some(samples) => {
+                            let data = source.encoder.as_mut().unwrap().encode_frame(&samples);
+                            blocks.push((timestamp, source.track_number, data));
+                        }
+                        None => break,
+                    }
+                }
+            }
+
+            if blocks.is_empty() {
+                break;
+            }
+
+            blocks.sort_by_key(|(timestamp, track_number, _)| (*timestamp, *track_number));
+
+            let mut cluster: Vec<u8> = Vec::new();
+            master_element(&mut cluster, CLUSTER, |b| {
+                uint_element(b, TIMESTAMP, cluster_timestamp);
+                for (timestamp, track_number, data) in &blocks {
+                    simple_block(b, *track_number, (timestamp - cluster_timestamp) as i16, data);
+                }
+            });
+
+            file.write_all(&cluster)?;
+            segment_size += cluster.len() as u64;
+            cluster_index += 1;
+        }
+
+        // ===== Patch the segment size ===== //
+        let mut size: Vec<u8> = Vec::new();
+        write_size_with_length(&mut size, segment_size, PLACEHOLDER_SIZE_LENGTH);
+        file.seek(SeekFrom::Start(segment_size_offset as u64))?;
+        file.write_all(&size)?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    fn write_track_entry(
+        &self,
+        buffer: &mut Vec<u8>,
+        source: &SourceTrack,
+        sample_rate: u32,
+        channels: u32,
+        bits_per_sample: u32,
+    ) {
+        master_element(buffer, TRACK_ENTRY, |b| {
+            uint_element(b, TRACK_NUMBER, source.track_number);
+            uint_element(b, TRACK_UID, source.track_uid);
+            uint_element(b, TRACK_TYPE, 2); // Audio
+            uint_element(b, FLAG_LACING, 0);
+            // Lets a seek keep the block the target time falls in
+            let block_duration = block_frames(sample_rate) as u64 * 1_000_000_000 / sample_rate as u64;
+            uint_element(b, DEFAULT_DURATION, block_duration);
+
+            if let Some(name) = Path::new(&source.file_path).file_stem().and_then(|stem| stem.to_str()) {
+                string_element(b, NAME, name);
+            }
+
+            string_element(b, CODEC_ID, "A_FLAC");
+            let codec_private = source.encoder.as_ref().unwrap().header(source.frames);
+            element(b, CODEC_PRIVATE, &codec_private);
+
+            master_element(b, AUDIO, |b| {
+                float_element(b, SAMPLING_FREQUENCY, sample_rate as f64);
+                uint_element(b, CHANNELS, channels as u64);
+                uint_element(b, BIT_DEPTH, bits_per_sample as u64);
+            });
+        });
+    }
+}
+
+struct SourceTrack {
+    file_path: String,
+    track_number: u64,
+    track_uid: u64,
+    decoder: Box<dyn Decoder>,
+    format: Box<dyn FormatReader>,
+    source_track_id: u32,
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+    frames: u64,
+    frames_written: u64,
+    end_of_stream: bool,
+    shift: u32,
+    pending: Vec<i32>,
+    encoder: Option<FlacEncoder>,
+}
+
+impl SourceTrack {
+    fn open(file_path: &str, track_number: u64) -> Result<Self, ProtWriteError> {
+        if !Path::new(file_path).is_file() {
+            return Err(ProtWriteError::MissingSource(file_path.to_string()));
+        }
+
+        let unsupported = |reason: &str| ProtWriteError::UnsupportedSource {
+            file_path: file_path.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (decoder, format) = open_file(file_path).map_err(|err| unsupported(&err.to_string()))?;
+        let track = &format.tracks()[0];
+        let codec_params = &track.codec_params;
+
+        let sample_rate = codec_params.sample_rate.ok_or_else(|| unsupported("unknown sample rate"))?;
+        let channels = match (codec_params.channels, codec_params.channel_layout) {
+            (Some(channels), _) => channels.count() as u32,
+            (None, Some(layout)) => layout.into_channels().count() as u32,
+            (None, None) => return Err(unsupported("unknown channel count")),
+        };
+
+        if channels == 0 || channels > 8 {
+            return Err(unsupported("only 1 to 8 channels are supported"));
+        }
+
+        let source_track_id = track.id;
+        let bits_per_sample = codec_params.bits_per_sample.unwrap_or(24);
+        let frames = match codec_params.n_frames {
+            Some(frames) => frames,
+            None => count_frames(file_path)?,
+        };
+
+        Ok(Self {
+            file_path: file_path.to_string(),
+            track_number,
+            track_uid: random_uid(),
+            decoder,
+            format,
+            source_track_id,
+            sample_rate,
+            channels,
+            bits_per_sample,
+            frames,
+            frames_written: 0,
+            end_of_stream: false,
+            shift: 8,
+            pending: Vec::new(),
+            encoder: None,
+        })
+    }
+
+    // Returns up to `block_frames` interleaved frames, or None once the source is exhausted
+    fn read_block(&mut self, block_frames: usize) -> Result<Option<Vec<i32>>, ProtWriteError> {
+        let channels = self.channels as usize;
+        let wanted = block_frames * channels;
+
+        while self.pending.len() < wanted && !self.end_of_stream {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    self.end_of_stream = true;
+                    break;
+                }
+                Err(error) => {
+                    return Err(ProtWriteError::Decode { file_path: self.file_path.clone(), error })
+                }
+            };
+
+            if packet.track_id() != self.source_track_id {
+                continue;
+            }
+
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    if decoded.spec().channels.count() != channels {
+                        return Err(ProtWriteError::MismatchedSource {
+                            file_path: self.file_path.clone(),
+                            reason: String::from("channel count changed while decoding"),
+                        });
+                    }
+
+                    let mut sample_buffer = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
+                    sample_buffer.copy_interleaved_ref(decoded);
+                    let shift = self.shift;
+                    self.pending.extend(sample_buffer.samples().iter().map(|sample| sample >> shift));
+                }
+                Err(Error::DecodeError(err)) => {
+                    // Decode errors are not fatal. Print the error message and try to decode the next
+                    // packet as usual.
+                    warn!("decode error: {}", err);
+                }
+                Err(error) => {
+                    return Err(ProtWriteError::Decode { file_path: self.file_path.clone(), error })
+                }
+            }
+        }
+
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+
+        let count = wanted.min(self.pending.len());
+        let samples: Vec<i32> = self.pending.drain(..count).collect();
+        self.frames_written += (count / channels) as u64;
+
+        Ok(Some(samples))
+    }
+}
+
+fn count_frames(file_path: &str) -> Result<u64, ProtWriteError> {
+    let (mut decoder, mut format) = open_file(file_path).map_err(|err| ProtWriteError::UnsupportedSource {
+        file_path: file_path.to_string(),
+        reason: err.to_string(),
+    })?;
+    let track_id = format.tracks()[0].id;
+    let mut frames = 0;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(ProtWriteError::Decode { file_path: file_path.to_string(), error }),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        match decoder.decode(&packet) {
+            Ok(decoded) => frames += decoded.frames() as u64,
+            Err(Error::DecodeError(err)) => warn!("decode error: {}", err),
+            Err(error) => return Err(ProtWriteError::Decode { file_path: file_path.to_string(), error }),
+        }
+    }
+
+    Ok(frames)
+}
+
+fn frames_to_timestamp(frames: u64, sample_rate: u32) -> u64 {
+    frames * 1_000_000_000 / TIMESTAMP_SCALE_NS / sample_rate as u64
+}
+
+// Frames in each FLAC block. Where the sample rate allows, a block spans a
+// whole number of timestamp ticks, so every block's timestamp is exact and
+// playback can place it on the right frame.
+fn block_frames(sample_rate: u32) -> usize {
+    let ticks_per_second = 1_000_000_000 / TIMESTAMP_SCALE_NS;
+    let exact_frames = (sample_rate as u64 / gcd(sample_rate as u64, ticks_per_second)) as usize;
+    if exact_frames <= FLAC_BLOCK_SIZE {
+        FLAC_BLOCK_SIZE / exact_frames * exact_frames
+    } else if exact_frames <= FLAC_MAX_BLOCK_SIZE {
+        exact_frames
+    } else {
+        FLAC_BLOCK_SIZE
+    }
+}
+
+// Formats a duration the way Matroska DURATION tags do, e.g. 00:03:25.120000000
+fn format_duration(frames: u64, sample_rate: u32) -> String {
+    let seconds = frames as f64 / sample_rate as f64;
+    let hours = (seconds / 3600.0).floor();
+    let minutes = ((seconds - hours * 3600.0) / 60.0).floor();
+    let seconds = seconds - hours * 3600.0 - minutes * 60.0;
+
+    format!("{:02}:{:02}:{:012.9}", hours as u64, minutes as u64, seconds)
+}
+
+fn random_uid() -> u64 {
+    rand::thread_rng().gen_range(1..u64::MAX)
+}
+
+fn attachment_name(file_path: &str) -> String {
+    Path::new(file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file_path.to_string())
+}
+
+fn audio_mime_type(file_name: &str) -> &'static str {
+    let extension = Path::new(file_name).extension().map(|extension| extension.to_ascii_lowercase());
+    match extension.as_ref().and_then(|extension| extension.to_str()) {
+        Some("wav") => "audio/wav",
+        Some("flac") => "audio/flac",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,284 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rand::seq::IteratorRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::audio_file::*;
+use crate::constants::{DEFAULT_REVERB_DRY, DEFAULT_REVERB_WET};
+use crate::effects::{ImpulseResponse, Reverb};
+use crate::limiter::DEFAULT_CEILING;
+use crate::play_settings::PlaySettingsError;
+use crate::player_engine::PlayerEngine;
+use crate::prot::Prot;
+use crate::resample::ResampleQuality;
+
+#[derive(Debug)]
+pub enum RenderError {
+    Io(std::io::Error),
+    PlaySettings(PlaySettingsError),
+    TooFewCombinations { requested: usize, available: u128 },
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Io(err) => write!(f, "could not write render: {}", err),
+            RenderError::PlaySettings(err) => write!(f, "{}", err),
+            RenderError::TooFewCombinations { requested, available } => {
+                write!(f, "{} different combinations were asked for, but the file only has {}", requested, available)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RenderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RenderError::Io(err) => Some(err),
+            RenderError::PlaySettings(err) => Some(err),
+            RenderError::TooFewCombinations { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RenderError {
+    fn from(err: std::io::Error) -> Self {
+        RenderError::Io(err)
+    }
+}
+
+impl From<PlaySettingsError> for RenderError {
+    fn from(err: PlaySettingsError) -> Self {
+        RenderError::PlaySettings(err)
+    }
+}
+
+// What went into one rendered file. `ids` mirrors `Prot::get_ids`
+#[derive(Debug, Clone, Serialize)]
+pub struct Render {
+    pub file_path: String,
+    pub seed: Option<u64>,
+    pub combination: String,
+    pub ids: Vec<String>,
+    pub duration: f64,
+}
+
+pub const RENDER_BITS_PER_SAMPLE: u32 = 24;
+pub const INDEX_JSON_FILE_NAME: &str = "index.json";
+pub const INDEX_CSV_FILE_NAME: &str = "index.csv";
+
+/// How a render is written.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub format: AudioFileFormat,
+    /// Rate to resample the mix to, instead of the file's own.
+    pub sample_rate: Option<u32>,
+    /// Channels to remix the mix to, instead of the file's own. See
+    /// `PlayerEngine::set_output_channels`.
+    pub channels: Option<u16>,
+    /// How carefully tracks and mixes not at the file's rate are resampled.
+    pub resample_quality: ResampleQuality,
+    /// Whether takes are brought in line with the loudness of the other
+    /// takes in their group, and the combination within the loudness window.
+    /// See `PlayerEngine::set_loudness_normalization`.
+    pub normalize_loudness: bool,
+    /// True peak, in dBTP, the master bus limiter keeps the mix under, or
+    /// `None` to leave it unlimited. See `PlayerEngine::set_limiter_ceiling`.
+    pub limiter_ceiling: Option<f32>,
+    /// Whether the reverb is heard, when the file or `impulse_response`
+    /// sets one.
+    pub reverb: bool,
+    /// Impulse response for the reverb, in place of the one the file
+    /// attaches.
+    pub impulse_response: Option<Arc<ImpulseResponse>>,
+    /// Levels, from 0 to 1, of the reverb and of the mix without it, in
+    /// place of the file's.
+    pub reverb_wet: Option<f32>,
+    pub reverb_dry: Option<f32>,
+}
+
+impl RenderOptions {
+    /// A render in `format` at the file's own rate and channels.
+    pub fn new(format: AudioFileFormat) -> Self {
+        Self {
+            format,
+            sample_rate: None,
+            channels: None,
+            resample_quality: ResampleQuality::High,
+            normalize_loudness: false,
+            limiter_ceiling: Some(DEFAULT_CEILING),
+            reverb: true,
+            impulse_response: None,
+            reverb_wet: None,
+            reverb_dry: None,
+        }
+    }
+}
+
+// Seconds of audio each track decodes ahead of the mix. Far more than playback
+// needs, so the engine is never left waiting on its decode threads.
+const RENDER_BUFFER_SECONDS: usize = 30;
+
+/// Renders the selection made from `seed` (or a random one when `None`) to a
+/// WAV or FLAC file.
+pub fn render(file_path: &str, seed: Option<u64>, output: &str, options: &RenderOptions) -> Result<Render, RenderError> {
+    let prot = Prot::try_new_with_seed(file_path, seed)?;
+
+    render_prot(prot, output, options)
+}
+
+/// Renders the current selection of `prot` to a WAV or FLAC file, through the
+/// same mix the player uses, as fast as the tracks can be decoded. The mix is
+/// resampled and remixed where `options` ask for another rate or channels.
+pub fn render_prot(mut prot: Prot, output: &str, options: &RenderOptions) -> Result<Render, RenderError> {
+    if options.normalize_loudness {
+        prot.analyze_loudness();
+    }
+
+    let buffer_size = prot.info.sample_rate as usize * prot.info.channels as usize * RENDER_BUFFER_SECONDS;
+    let render = Render {
+        file_path: output.to_string(),
+        seed: prot.get_seed(),
+        combination: prot.get_combination(),
+        ids: prot.get_ids(),
+        duration: *prot.get_duration(),
+    };
+
+    let reverb = get_reverb(&prot, options);
+    let mut engine = PlayerEngine::new(Arc::new(Mutex::new(prot)), None, 0.0);
+    engine.set_buffer_size(buffer_size);
+    engine.set_output_sample_rate(options.sample_rate);
+    engine.set_output_channels(options.channels);
+    engine.set_resample_quality(options.resample_quality);
+    engine.set_loudness_normalization(options.normalize_loudness);
+    engine.set_limiter_ceiling(options.limiter_ceiling);
+    engine.set_reverb(reverb);
+
+    let writer = AudioFileWriter::create(
+        output,
+        options.format,
+        engine.get_sample_rate(),
+        engine.get_channels() as u32,
+        RENDER_BITS_PER_SAMPLE,
+    )?;
+    let writer = RefCell::new(writer);
+    let result = RefCell::new(Ok(()));
+
+    // Every chunk is taken, even after an error, so the engine can finish
+    engine.reception_loop(&|(chunk, _)| {
+        if result.borrow().is_err() {
+            return;
+        }
+
+        let samples: Vec<i32> = chunk.map(to_integer_sample).collect();
+        *result.borrow_mut() = writer.borrow_mut().write_samples(&samples);
+    });
+
+    result.into_inner()?;
+    writer.into_inner().finalize()?;
+
+    Ok(render)
+}
+
+/// Renders `count` different combinations of the file into `output_dir`,
+/// several at a time, and writes an index of the takes in each file as both
+/// JSON and CSV. The combinations are drawn from `seed` so a batch can be
+/// repeated. Fails before rendering anything when the file does not have
+/// `count` combinations.
+pub fn render_batch(
+    file_path: &str,
+    count: usize,
+    seed: Option<u64>,
+    output_dir: &str,
+    options: &RenderOptions,
+) -> Result<Vec<Render>, RenderError> {
+    std::fs::create_dir_all(output_dir)?;
+
+    let mut prot = Prot::try_new_with_seed(file_path, seed)?;
+    // Measured once here rather than in every render
+    if options.normalize_loudness {
+        prot.analyze_loudness();
+    }
+    let available = prot.count_combinations();
+    if count as u128 > available {
+        return Err(RenderError::TooFewCombinations { requested: count, available });
+    }
+    let mut rng = match seed {
+        Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+        None => ChaCha8Rng::from_entropy(),
+    };
+
+    // Pick the selections up front from every combination there is, so none
+    // is taken twice
+    let stem = Path::new(file_path).file_stem().and_then(|stem| stem.to_str()).unwrap_or("render");
+    let width = count.to_string().len();
+    let mut jobs = Vec::new();
+    for combination in prot.combinations().choose_multiple(&mut rng, count) {
+        prot.set_combination(&combination).unwrap();
+        let file_name = format!("{}_{:0width$}.{}", stem, jobs.len() + 1, options.format.extension(), width = width);
+        let output = Path::new(output_dir).join(file_name).to_string_lossy().to_string();
+        jobs.push((prot.clone(), output));
+    }
+
+    let renders = jobs
+        .into_par_iter()
+        .map(|(prot, output)| render_prot(prot, &output, options))
+        .collect::<Result<Vec<Render>, RenderError>>()?;
+
+    let index_path = Path::new(output_dir).join(INDEX_JSON_FILE_NAME);
+    std::fs::write(index_path, serde_json::to_vec_pretty(&renders).unwrap())?;
+
+    let mut csv = String::from("file_path,seed,combination,ids,duration\n");
+    for render in &renders {
+        let seed = render.seed.map(|seed| seed.to_string()).unwrap_or_default();
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            csv_field(&render.file_path),
+            seed,
+            render.combination,
+            csv_field(&render.ids.join(" ")),
+            render.duration
+        ));
+    }
+    std::fs::write(Path::new(output_dir).join(INDEX_CSV_FILE_NAME), csv)?;
+
+    Ok(renders)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn to_integer_sample(sample: f32) -> i32 {
+    let scale = (1 << (RENDER_BITS_PER_SAMPLE - 1)) as f32 - 1.0;
+    (sample.clamp(-1.0, 1.0) * scale).round() as i32
+}
+
+// The file's reverb, with whatever `options` change about it
+fn get_reverb(prot: &Prot, options: &RenderOptions) -> Option<Reverb> {
+    if !options.reverb {
+        return None;
+    }
+
+    let reverb = prot.get_reverb();
+    let impulse_response = options
+        .impulse_response
+        .clone()
+        .or_else(|| reverb.as_ref().map(|reverb| reverb.impulse_response.clone()))?;
+
+    Some(Reverb {
+        impulse_response,
+        wet: options.reverb_wet.or(reverb.as_ref().map(|reverb| reverb.wet)).unwrap_or(DEFAULT_REVERB_WET),
+        dry: options.reverb_dry.or(reverb.as_ref().map(|reverb| reverb.dry)).unwrap_or(DEFAULT_REVERB_DRY),
+    })
+}
@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use rubato::{
+    FastFixedIn, PolynomialDegree, SincFixedIn, SincInterpolationParameters, SincInterpolationType, VecResampler,
+    WindowFunction,
+};
+
+// Frames the resampler takes in at a time
+const RESAMPLER_CHUNK_FRAMES: usize = 1024;
+
+/// How carefully audio is resampled, where a track or the output is not at
+/// the engine's sample rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResampleQuality {
+    /// Cubic interpolation. Cheap, but lets some aliasing through.
+    Fast,
+    /// A short sinc filter, good enough for playback.
+    #[default]
+    Balanced,
+    /// A long sinc filter, for renders.
+    High,
+}
+
+impl ResampleQuality {
+    // Input frames the resampler's first output frame lags behind. Output
+    // frame `k` is taken from `(k + 1) / ratio - offset` on the input.
+    fn input_offset(&self) -> usize {
+        match self {
+            ResampleQuality::Fast => 4,
+            ResampleQuality::Balanced | ResampleQuality::High => 1,
+        }
+    }
+
+    fn sinc_parameters(&self) -> Option<SincInterpolationParameters> {
+        match self {
+            ResampleQuality::Fast => None,
+            ResampleQuality::Balanced => Some(SincInterpolationParameters {
+                sinc_len: 128,
+                f_cutoff: 0.925,
+                oversampling_factor: 128,
+                interpolation: SincInterpolationType::Linear,
+                window: WindowFunction::Blackman2,
+            }),
+            ResampleQuality::High => Some(SincInterpolationParameters {
+                sinc_len: 256,
+                f_cutoff: 0.95,
+                oversampling_factor: 256,
+                interpolation: SincInterpolationType::Cubic,
+                window: WindowFunction::BlackmanHarris2,
+            }),
+        }
+    }
+}
+
+// Converts interleaved audio from one sample rate to another as it streams
+// through, carrying the filter state from one chunk to the next. The output
+// lines up with the input, frame for frame at the new rate.
+pub(crate) struct Resampler {
+    resampler: Box<dyn VecResampler<f32>>,
+    channels: usize,
+    ratio: f64,
+    // Input waiting for a full chunk, one vector per channel
+    input: Vec<Vec<f32>>,
+    output_buffer: Vec<Vec<f32>>,
+    // Interleaved output ready to be taken
+    output: VecDeque<f32>,
+    // Output frames still to drop for the delay of the resampler
+    delay: usize,
+    frames_in: u64,
+    frames_out: u64,
+    finished: bool,
+}
+
+impl fmt::Debug for Resampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resampler")
+            .field("channels", &self.channels)
+            .field("ratio", &self.ratio)
+            .field("frames_in", &self.frames_in)
+            .field("frames_out", &self.frames_out)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: usize, quality: ResampleQuality) -> Self {
+        let ratio = to as f64 / from as f64;
+        let resampler: Box<dyn VecResampler<f32>> = match quality.sinc_parameters() {
+            Some(parameters) => {
+                Box::new(SincFixedIn::new(ratio, 1.0, parameters, RESAMPLER_CHUNK_FRAMES, channels).unwrap())
+            }
+            None => Box::new(
+                FastFixedIn::new(ratio, 1.0, PolynomialDegree::Cubic, RESAMPLER_CHUNK_FRAMES, channels).unwrap(),
+            ),
+        };
+
+        // Leading silence moves the resampler's offset onto a whole output
+        // frame, which can then be dropped
+        let offset = quality.input_offset();
+        let step = (from / gcd(from as u64, to as u64) as u32) as usize;
+        let padding = offset.div_ceil(step) * step - offset;
+        let delay = ((offset + padding) as u64 * to as u64 / from as u64) as usize - 1;
+
+        let mut this = Self {
+            channels,
+            ratio,
+            input: vec![Vec::with_capacity(RESAMPLER_CHUNK_FRAMES); channels],
+            output_buffer: resampler.output_buffer_allocate(true),
+            output: VecDeque::new(),
+            delay,
+            frames_in: 0,
+            frames_out: 0,
+            finished: false,
+            resampler,
+        };
+        this.push(&vec![0.0; padding * channels]);
+        this.frames_in = 0;
+
+        this
+    }
+
+    /// Resamples interleaved `samples`. Frames are held back until there are
+    /// enough for the resampler to take.
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in self.input.iter_mut().zip(frame) {
+                channel.push(*sample);
+            }
+            self.frames_in += 1;
+
+            if self.input[0].len() == RESAMPLER_CHUNK_FRAMES {
+                self.process(false, u64::MAX);
+            }
+        }
+    }
+
+    /// Resamples everything held back, once there is no more input.
+    pub fn finish(&mut self) {
+        let frames_expected = (self.frames_in as f64 * self.ratio).round() as u64;
+
+        while self.frames_out < frames_expected {
+            self.process(true, frames_expected);
+        }
+        self.finished = true;
+    }
+
+    /// Whether the input has ended and every resampled sample has been taken.
+    pub fn is_finished(&self) -> bool {
+        self.finished && self.output.is_empty()
+    }
+
+    /// Moves resampled samples into `buffer`. Returns how many were moved.
+    pub fn pop(&mut self, buffer: &mut [f32]) -> usize {
+        let count = buffer.len().min(self.output.len());
+        for (out, sample) in buffer.iter_mut().zip(self.output.drain(..count)) {
+            *out = sample;
+        }
+
+        count
+    }
+
+    /// Takes every resampled sample.
+    pub fn take(&mut self) -> Vec<f32> {
+        self.output.drain(..).collect()
+    }
+
+    /// Resampled samples ready to be taken.
+    pub fn len(&self) -> usize {
+        self.output.len()
+    }
+
+    // Runs the resampler over the input held back. At the end of the stream
+    // the input is padded with silence, and the output stops at `frames_limit`.
+    fn process(&mut self, partial: bool, frames_limit: u64) {
+        let result = if !partial {
+            self.resampler.process_into_buffer(&self.input, &mut self.output_buffer, None)
+        } else if self.input[0].is_empty() {
+            self.resampler.process_partial_into_buffer(None, &mut self.output_buffer, None)
+        } else {
+            self.resampler.process_partial_into_buffer(Some(&self.input), &mut self.output_buffer, None)
+        };
+        let (_, frames) = result.unwrap();
+
+        for channel in self.input.iter_mut() {
+            channel.clear();
+        }
+
+        for index in 0..frames {
+            if self.delay > 0 {
+                self.delay -= 1;
+                continue;
+            }
+            if self.frames_out >= frames_limit {
+                break;
+            }
+
+            for channel in self.output_buffer.iter() {
+                self.output.push_back(channel[index]);
+            }
+            self.frames_out += 1;
+        }
+    }
+}
+
+pub(crate) fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
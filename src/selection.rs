@@ -0,0 +1,360 @@
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::play_settings::*;
+
+// Chooses one take per track independently, for settings without rules.
+// A track that sets a presence draws for it first, and gets None when it
+// loses, as do tracks without ids. Takes are drawn by weight, or evenly when
+// the track has no weights. The whole draw is made again when every track
+// is left out but one of them could have played.
+pub fn draw_takes<R: Rng>(tracks: &[TrackSettingsV1], rng: &mut R) -> Vec<Option<usize>> {
+    loop {
+        let takes: Vec<Option<usize>> = tracks
+            .iter()
+            .map(|track| {
+                if track.ids.is_empty() {
+                    return None;
+                }
+
+                if let Some(presence) = track.presence {
+                    if rng.gen::<f32>() >= presence {
+                        return None;
+                    }
+                }
+
+                match &track.weights {
+                    Some(weights) => Some(WeightedIndex::new(weights).unwrap().sample(rng)),
+                    None => Some(rng.gen_range(0..track.ids.len())),
+                }
+            })
+            .collect();
+
+        // Draw again rather than play silence
+        if takes.iter().any(|take| take.is_some()) || !tracks.iter().any(can_play) {
+            return takes;
+        }
+    }
+}
+
+// Independent draws are retried this many times before searching
+const MAX_DRAWS: usize = 256;
+
+// Chooses one take per track so that no rule is broken. Redrawing until the
+// rules hold keeps the odds set by weights and presence; the search is only
+// used when the rules reject nearly every draw.
+pub fn pick_takes<R: Rng>(tracks: &[TrackSettingsV1], rules: &[Rule], rng: &mut R) -> Option<Vec<Option<usize>>> {
+    for _ in 0..MAX_DRAWS {
+        let takes = draw_takes(tracks, rng);
+        if !breaks_rules(&selected_ids(tracks, &takes), rules) {
+            return Some(takes);
+        }
+    }
+
+    choose_takes(tracks, rules, Some(rng))
+}
+
+pub fn selected_ids(tracks: &[TrackSettingsV1], takes: &[Option<usize>]) -> Vec<u32> {
+    tracks
+        .iter()
+        .zip(takes)
+        .filter_map(|(track, take)| take.map(|take| track.ids[take]))
+        .collect()
+}
+
+// Chooses one take per track so that no rule is broken. Tracks are filled in
+// order, trying takes in a weighted random order and backing out of choices
+// that leave a rule impossible to satisfy. Takes with a weight of zero are
+// never chosen, and at least one track plays. Returns the index of the chosen
+// take within each track (None when the track is left out), or None when the
+// rules cannot be satisfied.
+pub fn choose_takes<R: Rng>(
+    tracks: &[TrackSettingsV1],
+    rules: &[Rule],
+    rng: Option<&mut R>,
+) -> Option<Vec<Option<usize>>> {
+    let mut takes = Vec::with_capacity(tracks.len());
+
+    if search(tracks, rules, &mut takes, rng) {
+        Some(takes)
+    } else {
+        None
+    }
+}
+
+// Number of different selections the tracks can make without breaking a rule
+pub fn count_selections(tracks: &[TrackSettingsV1], rules: &[Rule]) -> u128 {
+    if rules.is_empty() {
+        let options: Vec<Vec<Option<usize>>> = tracks.iter().map(take_options).collect();
+        let count = options.iter().fold(1u128, |count, options| count.saturating_mul(options.len() as u128));
+
+        // Leaving every track out only counts when nothing can play
+        let can_be_silent = options.iter().all(|options| options.contains(&None));
+        return if can_be_silent && tracks.iter().any(can_play) { count - 1 } else { count };
+    }
+
+    count_from(tracks, rules, &mut Vec::with_capacity(tracks.len()))
+}
+
+// Whether a complete selection could have been chosen by `choose_takes`
+pub fn is_valid_selection(tracks: &[TrackSettingsV1], rules: &[Rule], takes: &[Option<usize>]) -> bool {
+    (takes.iter().any(|take| take.is_some()) || !tracks.iter().any(can_play))
+        && !breaks_rules(&selected_ids(tracks, takes), rules)
+}
+
+// Every choice a track can make: the takes that can be drawn, then None when
+// the track may be left out
+pub fn take_options(track: &TrackSettingsV1) -> Vec<Option<usize>> {
+    let mut options: Vec<Option<usize>> = (0..track.ids.len())
+        .filter(|take| track.presence != Some(0.0) && take_weight(track, *take) > 0.0)
+        .map(Some)
+        .collect();
+
+    if track.ids.is_empty() || is_optional(track) {
+        options.push(None);
+    }
+
+    options
+}
+
+pub fn is_satisfiable(tracks: &[TrackSettingsV1], rules: &[Rule]) -> bool {
+    choose_takes::<ChaCha8Rng>(tracks, rules, None).is_some()
+}
+
+// Checks a complete selection of container track ids against the rules
+pub fn breaks_rules(selected_ids: &[u32], rules: &[Rule]) -> bool {
+    rules.iter().any(|rule| breaks_rule(rule, selected_ids, &[]))
+}
+
+fn search<R: Rng>(
+    tracks: &[TrackSettingsV1],
+    rules: &[Rule],
+    takes: &mut Vec<Option<usize>>,
+    mut rng: Option<&mut R>,
+) -> bool {
+    let track_index = takes.len();
+    if track_index == tracks.len() {
+        return takes.iter().any(|take| take.is_some()) || !tracks.iter().any(can_play);
+    }
+
+    for take in candidate_order(&tracks[track_index], rng.as_deref_mut()) {
+        takes.push(take);
+
+        let selected_ids = selected_ids(tracks, takes);
+        let possible_ids: Vec<u32> = tracks[takes.len()..].iter().flat_map(playable_ids).collect();
+        let allowed = !rules.iter().any(|rule| breaks_rule(rule, &selected_ids, &possible_ids));
+
+        if allowed && search(tracks, rules, takes, rng.as_deref_mut()) {
+            return true;
+        }
+
+        takes.pop();
+    }
+
+    false
+}
+
+fn count_from(tracks: &[TrackSettingsV1], rules: &[Rule], takes: &mut Vec<Option<usize>>) -> u128 {
+    let track_index = takes.len();
+    if track_index == tracks.len() {
+        return is_valid_selection(tracks, rules, takes) as u128;
+    }
+
+    let mut count: u128 = 0;
+    for take in take_options(&tracks[track_index]) {
+        takes.push(take);
+
+        let selected_ids = selected_ids(tracks, takes);
+        let possible_ids: Vec<u32> = tracks[takes.len()..].iter().flat_map(playable_ids).collect();
+        if !rules.iter().any(|rule| breaks_rule(rule, &selected_ids, &possible_ids)) {
+            count = count.saturating_add(count_from(tracks, rules, takes));
+        }
+
+        takes.pop();
+    }
+
+    count
+}
+
+// A rule is broken once no choice in the remaining tracks can fix it
+fn breaks_rule(rule: &Rule, selected_ids: &[u32], possible_ids: &[u32]) -> bool {
+    match rule {
+        Rule::Requires { take, any_of } => {
+            selected_ids.contains(take)
+                && !any_of.iter().any(|id| selected_ids.contains(id) || possible_ids.contains(id))
+        }
+        Rule::Excludes { takes } => takes.iter().filter(|id| selected_ids.contains(id)).count() > 1,
+    }
+}
+
+fn can_play(track: &TrackSettingsV1) -> bool {
+    track.presence.unwrap_or(1.0) > 0.0 && !playable_ids(track).is_empty()
+}
+
+fn playable_ids(track: &TrackSettingsV1) -> Vec<u32> {
+    if track.presence == Some(0.0) {
+        return Vec::new();
+    }
+
+    (0..track.ids.len())
+        .filter(|take| take_weight(track, *take) > 0.0)
+        .map(|take| track.ids[take])
+        .collect()
+}
+
+fn take_weight(track: &TrackSettingsV1, take: usize) -> f32 {
+    match &track.weights {
+        Some(weights) => weights[take],
+        None => 1.0,
+    }
+}
+
+// Without an rng the takes are tried in order, which is enough to check that a
+// valid selection exists. Leaving an optional track out is one more candidate.
+fn candidate_order<R: Rng>(track: &TrackSettingsV1, rng: Option<&mut R>) -> Vec<Option<usize>> {
+    let mut remaining: Vec<usize> = (0..track.ids.len())
+        .filter(|take| track.presence != Some(0.0) && take_weight(track, *take) > 0.0)
+        .collect();
+    let optional = track.ids.is_empty() || is_optional(track);
+
+    let rng = match rng {
+        Some(rng) => rng,
+        None => return take_options(track),
+    };
+
+    let present_first = match track.presence {
+        Some(presence) if optional && !remaining.is_empty() => rng.gen::<f32>() < presence,
+        _ => true,
+    };
+
+    // Weighted sampling without replacement
+    let mut order = Vec::with_capacity(remaining.len() + 1);
+    while !remaining.is_empty() {
+        let weights: Vec<f32> = remaining.iter().map(|take| take_weight(track, *take)).collect();
+        let picked = WeightedIndex::new(weights).unwrap().sample(rng);
+        order.push(Some(remaining.remove(picked)));
+    }
+
+    if optional {
+        if present_first {
+            order.push(None);
+        } else {
+            order.insert(0, None);
+        }
+    }
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn track(ids: &[u32]) -> TrackSettingsV1 {
+        TrackSettingsV1 {
+            ids: ids.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn optional(ids: &[u32]) -> TrackSettingsV1 {
+        TrackSettingsV1 {
+            presence: Some(0.5),
+            ..track(ids)
+        }
+    }
+
+    // Every selection the tracks can make, checked one by one
+    fn all_selections(tracks: &[TrackSettingsV1], rules: &[Rule]) -> Vec<Vec<Option<usize>>> {
+        let mut selections = vec![Vec::new()];
+        for track in tracks {
+            selections = selections
+                .into_iter()
+                .flat_map(|takes| {
+                    take_options(track).into_iter().map(move |take| {
+                        let mut takes = takes.clone();
+                        takes.push(take);
+                        takes
+                    })
+                })
+                .collect();
+        }
+
+        selections.retain(|takes| is_valid_selection(tracks, rules, takes));
+        selections
+    }
+
+    fn cases() -> Vec<(Vec<TrackSettingsV1>, Vec<Rule>)> {
+        let mut weighted = track(&[1, 2, 3]);
+        weighted.weights = Some(vec![1.0, 0.0, 2.0]);
+
+        vec![
+            (vec![track(&[1, 2]), track(&[3, 4, 5])], vec![]),
+            (vec![optional(&[1, 2]), optional(&[3])], vec![]),
+            (vec![weighted, optional(&[4, 5])], vec![Rule::Requires { take: 3, any_of: vec![5] }]),
+            (
+                vec![track(&[1, 2, 3]), optional(&[4, 5]), track(&[6])],
+                vec![Rule::Excludes { takes: vec![1, 4] }, Rule::Requires { take: 2, any_of: vec![5] }],
+            ),
+            (
+                vec![optional(&[1, 2]), optional(&[3, 4]), optional(&[5])],
+                vec![Rule::Excludes { takes: vec![1, 3, 5] }, Rule::Requires { take: 5, any_of: vec![2, 4] }],
+            ),
+        ]
+    }
+
+    #[test]
+    fn count_matches_every_selection_allowed() {
+        for (tracks, rules) in cases() {
+            assert_eq!(count_selections(&tracks, &rules), all_selections(&tracks, &rules).len() as u128, "{:?}", rules);
+        }
+    }
+
+    #[test]
+    fn picked_takes_obey_the_rules() {
+        for (tracks, rules) in cases() {
+            let allowed = all_selections(&tracks, &rules);
+            let mut picked = HashSet::new();
+            for seed in 0..200 {
+                let mut rng = ChaCha8Rng::seed_from_u64(seed);
+                let takes = pick_takes(&tracks, &rules, &mut rng).unwrap();
+                assert!(allowed.contains(&takes), "{:?} breaks {:?}", takes, rules);
+                picked.insert(takes);
+            }
+
+            // Every allowed selection comes up given enough seeds
+            assert_eq!(picked.len(), allowed.len(), "{:?}", rules);
+        }
+    }
+
+    #[test]
+    fn search_finds_the_one_selection_allowed() {
+        let tracks = vec![track(&[1, 2, 3, 4]), track(&[5, 6, 7, 8])];
+        let rules = vec![
+            Rule::Requires { take: 1, any_of: vec![8] },
+            Rule::Excludes { takes: vec![2, 5, 6, 7, 8] },
+            Rule::Excludes { takes: vec![3, 5, 6, 7, 8] },
+            Rule::Excludes { takes: vec![4, 5, 6, 7, 8] },
+        ];
+        assert_eq!(count_selections(&tracks, &rules), 1);
+
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        assert_eq!(choose_takes(&tracks, &rules, Some(&mut rng)), Some(vec![Some(0), Some(3)]));
+        assert_eq!(pick_takes(&tracks, &rules, &mut rng), Some(vec![Some(0), Some(3)]));
+    }
+
+    #[test]
+    fn unsatisfiable_rules_pick_nothing() {
+        let tracks = vec![track(&[1, 2]), track(&[3])];
+        let rules = vec![Rule::Excludes { takes: vec![1, 3] }, Rule::Excludes { takes: vec![2, 3] }];
+
+        assert!(!is_satisfiable(&tracks, &rules));
+        assert_eq!(count_selections(&tracks, &rules), 0);
+        assert_eq!(pick_takes(&tracks, &rules, &mut ChaCha8Rng::seed_from_u64(0)), None);
+    }
+}
@@ -1,62 +1,70 @@
 use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
 use symphonia::core::formats::{FormatOptions, FormatReader};
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
 
-pub fn open_file(file_path: &str) -> (Box<dyn Decoder>, Box<dyn FormatReader>) {
-    let format = get_reader(file_path);
-    let decoder = get_decoder(&format);
+/// Opens the file with a decoder for its first track, or returns an error
+/// when it cannot be opened or holds nothing that can be decoded.
+/// A decoder for the first track of a file, and the reader it decodes packets from.
+pub type OpenFile = (Box<dyn Decoder>, Box<dyn FormatReader>);
 
-    (decoder, format)
+pub fn open_file(file_path: &str) -> Result<OpenFile, Error> {
+    let format = try_get_reader(file_path)?;
+
+    // Use the default options for the decoder.
+    let dec_opts: DecoderOptions = Default::default();
+    let decoder = symphonia::default::get_codecs().make(&format.tracks()[0].codec_params, &dec_opts)?;
+
+    Ok((decoder, format))
 }
 
 pub fn get_reader(file_path: &str) -> Box<dyn FormatReader> {
+    try_get_reader(file_path).expect("unsupported format")
+}
+
+pub fn try_get_reader(file_path: &str) -> Result<Box<dyn FormatReader>, Error> {
     // Open the media source.
-    let src = std::fs::File::open(file_path).expect("failed to open media");
+    let src = std::fs::File::open(file_path)?;
 
     // Create the media source stream.
     let mss = MediaSourceStream::new(Box::new(src), Default::default());
 
     // Create a probe hint using the file's extension. [Optional]
     let mut hint = Hint::new();
-    let mut hint_extension = std::path::Path::new(file_path).extension().unwrap().to_str().unwrap();
-    // if hint_extension == "prot" replace with "mka"
-    if hint_extension == "prot" {
-        hint_extension = "mka";
+    if let Some(extension) = std::path::Path::new(file_path).extension().and_then(|extension| extension.to_str()) {
+        // .prot files are Matroska
+        hint.with_extension(if extension == "prot" { "mka" } else { extension });
     }
-    hint.with_extension(hint_extension);
 
     // Use the default options for metadata and format readers.
     let meta_opts: MetadataOptions = Default::default();
     let fmt_opts: FormatOptions = Default::default();
 
     // Probe the media source.
-    let probed = symphonia::default::get_probe()
-        .format(&hint, mss, &fmt_opts, &meta_opts)
-        .expect("unsupported format");
+    let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
 
     // Get the instantiated format reader.
     let format = probed.format;
 
     // Find the first audio track with a known (decodeable) codec.
-    format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .expect("no supported audio tracks");
+    if !format.tracks().iter().any(|t| t.codec_params.codec != CODEC_TYPE_NULL) {
+        return Err(Error::Unsupported("no supported audio tracks"));
+    }
 
-    format
+    Ok(format)
 }
 
-pub fn get_decoder(format: &Box<dyn FormatReader>) -> Box<dyn Decoder> {
+pub fn get_track_decoder(format: &dyn FormatReader, track_id: u32) -> Result<Box<dyn Decoder>, Error> {
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.id == track_id)
+        .ok_or(Error::Unsupported("no track with that id"))?;
+
     // Use the default options for the decoder.
     let dec_opts: DecoderOptions = Default::default();
 
-    // Create a decoder for the track.
-    let decoder = symphonia::default::get_codecs()
-        .make(&format.tracks()[0].codec_params, &dec_opts)
-        .expect("unsupported codec");
-
-    decoder
-}
\ No newline at end of file
+    symphonia::default::get_codecs().make(&track.codec_params, &dec_opts)
+}
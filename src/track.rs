@@ -1,23 +1,54 @@
-use std::collections::HashMap;
 use std::sync::atomic::AtomicBool;
 use std::time::Duration;
-use dasp_ring_buffer::Bounded;
 use symphonia::core::formats::{SeekMode, SeekTo};
 use symphonia::core::units::Time;
 use std::sync::{Mutex, Arc};
 use std::thread;
 use symphonia::core::errors::Error;
-use symphonia::core::audio::{AudioBufferRef, Signal, Channels};
+use symphonia::core::audio::{AudioBufferRef, Channels, Signal};
 use log::warn;
 
-use crate::buffer::buffer_remaining_space;
-use crate::tools::open_file;
+use crate::buffer::{buffer_remaining_space, BufferMap};
+use crate::channels::ChannelMatrix;
+use crate::resample::{gcd, ResampleQuality, Resampler};
+use crate::tools::{get_reader, get_track_decoder};
 
-pub struct TrackArgs {
+// Frames decoded beyond either end of a resampled segment, so the resampler's
+// filter has settled by the frames that are kept
+const RESAMPLE_PADDING_FRAMES: u64 = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackSource {
     pub file_path: String,
     pub track_id: Option<u32>,
+}
+
+/// A stretch of the song, in seconds, played from one source. Segments with
+/// no source are silent. The last segment has no end and plays until its
+/// source runs out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackSegment {
+    pub source: Option<TrackSource>,
+    pub start: f64,
+    pub end: Option<f64>,
+    /// Gain of the source, as a factor.
+    pub gain: f32,
+    /// Further gain that brings the source's loudness in line with the other
+    /// takes of its group, and the combination's within the loudness window,
+    /// as a factor. Only applied when the engine normalizes loudness.
+    pub loudness_gain: f32,
+}
+
+pub struct TrackArgs {
+    pub segments: Vec<TrackSegment>,
+    pub crossfade: f64,
+    pub sample_rate: u32,
+    /// Channels the track is mixed into. Sources with other channels are
+    /// remixed to them as they are decoded.
+    pub channel_layout: Channels,
+    pub resample_quality: ResampleQuality,
     pub track_key: i32,
-    pub buffer_map: Arc<Mutex<HashMap<i32, Bounded<Vec<f32>>>>>,
+    pub buffer_map: BufferMap,
     pub finished_tracks: Arc<Mutex<Vec<i32>>>,
     pub start_time: f64,
 }
@@ -44,25 +75,56 @@ pub fn convert_signed_16bit_to_f32(sample: i16) -> f32 {
 }
 
 pub fn convert_unsigned_16bit_to_f32(sample: u16) -> f32 {
-    let shifted_sample = sample as i16 - 2i16.pow(15);
-    let normalized_sample = shifted_sample as f32 / 2f32.powi(15);
-    normalized_sample
+    let shifted_sample = sample as i32 - 2i32.pow(15);
+    shifted_sample as f32 / 2f32.powi(15)
+}
+
+pub fn convert_signed_8bit_to_f32(sample: i8) -> f32 {
+    sample as f32 / 2f32.powi(7)
+}
+
+pub fn convert_unsigned_8bit_to_f32(sample: u8) -> f32 {
+    let shifted_sample = sample as i16 - 2i16.pow(7);
+    shifted_sample as f32 / 2f32.powi(7)
+}
+
+pub fn convert_signed_32bit_to_f32(sample: i32) -> f32 {
+    sample as f32 / 2f32.powi(31)
+}
+
+pub fn convert_unsigned_32bit_to_f32(sample: u32) -> f32 {
+    let shifted_sample = sample as i64 - 2i64.pow(31);
+    shifted_sample as f32 / 2f32.powi(31)
 }
 
 pub fn process_channel(decoded: AudioBufferRef<'_>, channel: usize) -> Vec<f32> {
     match decoded {
+        AudioBufferRef::U8(buf) => buf
+            .chan(channel)
+            .to_vec()
+            .into_iter()
+            .map(convert_unsigned_8bit_to_f32)
+            .collect(),
+
+        AudioBufferRef::S8(buf) => buf
+            .chan(channel)
+            .to_vec()
+            .into_iter()
+            .map(convert_signed_8bit_to_f32)
+            .collect(),
+
         AudioBufferRef::U16(buf) => buf
             .chan(channel)
             .to_vec()
             .into_iter()
-            .map(|s| convert_unsigned_16bit_to_f32(s))
+            .map(convert_unsigned_16bit_to_f32)
             .collect(),
 
         AudioBufferRef::S16(buf) => buf
             .chan(channel)
             .to_vec()
             .into_iter()
-            .map(|s| convert_signed_16bit_to_f32(s))
+            .map(convert_signed_16bit_to_f32)
             .collect(),
 
         AudioBufferRef::U24(buf) => buf
@@ -79,141 +141,497 @@ pub fn process_channel(decoded: AudioBufferRef<'_>, channel: usize) -> Vec<f32>
             .map(|s| convert_signed_24bit_to_f32(s.0))
             .collect(),
 
-        AudioBufferRef::F32(buf) => buf.chan(0).to_vec().into_iter().collect(),
-        _ => {
-            // Repeat for the different sample formats.
-            unimplemented!();
-            // return Vec::new();
-        }
+        AudioBufferRef::U32(buf) => buf
+            .chan(channel)
+            .to_vec()
+            .into_iter()
+            .map(convert_unsigned_32bit_to_f32)
+            .collect(),
+
+        AudioBufferRef::S32(buf) => buf
+            .chan(channel)
+            .to_vec()
+            .into_iter()
+            .map(convert_signed_32bit_to_f32)
+            .collect(),
+
+        AudioBufferRef::F32(buf) => buf.chan(channel).to_vec(),
+
+        AudioBufferRef::F64(buf) => buf
+            .chan(channel)
+            .to_vec()
+            .into_iter()
+            .map(|s| s as f32)
+            .collect(),
     }
 }
 
 pub fn buffer_track(args: TrackArgs, abort: Arc<AtomicBool>) -> Arc<Mutex<bool>> {
-    let TrackArgs { file_path, track_id, track_key, buffer_map, finished_tracks, start_time } = args;
-    // Create a channel for sending audio chunks from the decoder to the playback system.
-    let (mut decoder, mut format) = open_file(&file_path);
+    let TrackArgs {
+        segments,
+        crossfade,
+        sample_rate,
+        channel_layout,
+        resample_quality,
+        track_key,
+        buffer_map,
+        finished_tracks,
+        start_time,
+    } = args;
     let playing: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
 
+    thread::spawn(move || {
+        let to_frames = |seconds: f64| (seconds * sample_rate as f64).round() as u64;
+
+        // Neighbouring segments overlap by the crossfade, centred on the boundary
+        let half_fade = to_frames(crossfade) / 2;
+        let mut output = SegmentOutput {
+            track_key,
+            buffer_map,
+            start_frame: to_frames(start_time),
+            channel_layout,
+            samples: Vec::new(),
+            tail: Vec::new(),
+            next_tail: Vec::new(),
+            abort: abort.clone(),
+        };
 
-    let channels = {
-        let channels_option = &format
-            .tracks()
-            .first()
-            .unwrap()
-            .codec_params
-            .channels
-            .unwrap_or(Channels::FRONT_CENTRE);
-        channels_option.iter().count()
-    };
+        for (index, segment) in segments.iter().enumerate() {
+            let first = index == 0;
+            let last = index == segments.len() - 1;
 
-    thread::spawn(move || {
-        // If not explicitly specified, use the first audio track.
-        let track_id = track_id.unwrap_or(0);
+            let span = SegmentSpan {
+                start: to_frames(segment.start) - if first { 0 } else { half_fade.min(to_frames(segment.start)) },
+                end: segment.end.map(|end| to_frames(end) + if last { 0 } else { half_fade }),
+                fade_in: if first { 0 } else { half_fade * 2 },
+                fade_out: if last { 0 } else { half_fade * 2 },
+            };
+
+            output.begin_segment(&span);
+
+            // Skip segments that end before playback starts
+            if span.end.is_some_and(|end| end <= output.start_frame) {
+                output.end_segment();
+                continue;
+            }
 
-        // Get the selected track using the track ID.
-        let track = format.tracks().iter().find(|track| track.id == track_id).expect("no track found");
+            let result = match &segment.source {
+                Some(source) => {
+                    decode_segment(source, segment.gain, &span, &mut output, sample_rate, resample_quality, &abort)
+                }
+                None => {
+                    output.fill_silence(&span, span.start);
+                    Ok(())
+                }
+            };
 
-        // Get the selected track's timebase and duration.
-        // let tb = track.codec_params.time_base;
-        let dur = track.codec_params.n_frames.map(|frames| track.codec_params.start_ts + frames);
+            if let Err(err) = result {
+                warn!("error: {}", err);
+            }
 
-        // Start time at given time
-        let seconds = start_time.floor() as u64;
-        let frac_of_second = start_time.fract();
-        let time = Time::new(seconds, frac_of_second);
+            output.end_segment();
 
-        let seek_success = format.seek(SeekMode::Coarse, SeekTo::Time { time, track_id: Some(track_id) });
+            if abort.load(std::sync::atomic::Ordering::Relaxed) {
+                break;
+            }
+        }
 
-        if seek_success.is_err() {
-            mark_track_as_finished(&mut finished_tracks.clone(), track_key);
+        output.flush();
+
+        // Mark the track as finished
+        mark_track_as_finished(&mut finished_tracks.clone(), track_key);
+    });
+
+    playing
+}
+
+// Frames of one segment, including the overlaps with its neighbours
+struct SegmentSpan {
+    start: u64,
+    end: Option<u64>,
+    fade_in: u64,
+    fade_out: u64,
+}
+
+// Collects the frames of a track's segments, crossfading the overlaps, and
+// hands them to the track's ring buffer
+struct SegmentOutput {
+    track_key: i32,
+    buffer_map: BufferMap,
+    start_frame: u64,
+    channel_layout: Channels,
+    samples: Vec<f32>,
+    // Faded out end of the previous segment, to be mixed into the next one
+    tail: Vec<f32>,
+    next_tail: Vec<f32>,
+    abort: Arc<AtomicBool>,
+}
+
+impl SegmentOutput {
+    fn begin_segment(&mut self, span: &SegmentSpan) {
+        self.next_tail = vec![0.0; span.fade_out as usize * self.channel_layout.count()];
+    }
+
+    fn end_segment(&mut self) {
+        self.tail = std::mem::take(&mut self.next_tail);
+    }
+
+    fn write_frame(&mut self, span: &SegmentSpan, frame: u64, samples: &[f32]) {
+        let offset = frame - span.start;
+
+        // Equal power fades, as the takes on either side are unrelated recordings
+        let mut gain = 1.0;
+        if offset < span.fade_in {
+            gain *= ((offset as f32 + 0.5) / span.fade_in as f32 * std::f32::consts::FRAC_PI_2).sin();
+        }
+
+        if let Some(end) = span.end {
+            if end - frame <= span.fade_out {
+                let remaining = end - frame;
+                gain *= ((remaining as f32 - 0.5) / span.fade_out as f32 * std::f32::consts::FRAC_PI_2).sin();
+
+                let index = (span.fade_out - remaining) as usize * samples.len();
+                for (tail, sample) in self.next_tail[index..index + samples.len()].iter_mut().zip(samples) {
+                    *tail = sample * gain;
+                }
+                return;
+            }
+        }
+
+        if frame < self.start_frame {
             return;
         }
 
-        // TODO: Use actual time?
-        // let actual_time = track.codec_params.time_base.unwrap().calc_time(seek_success.unwrap().actual_ts);
+        let index = offset as usize * samples.len();
+        let tail = match self.tail.get(index..index + samples.len()) {
+            Some(tail) if offset < span.fade_in => tail,
+            _ => &[],
+        };
+        for (channel, sample) in samples.iter().enumerate() {
+            self.samples.push(sample * gain + tail.get(channel).unwrap_or(&0.0));
+        }
+
+        if self.samples.len() >= 8192 {
+            self.flush();
+        }
+    }
 
-        let _result: Result<bool, Error> = loop {
-            if abort.load(std::sync::atomic::Ordering::Relaxed) {
-                break Ok(true);
+    fn fill_silence(&mut self, span: &SegmentSpan, from: u64) {
+        if let Some(end) = span.end {
+            let silence = vec![0.0; self.channel_layout.count()];
+            for frame in from.max(self.start_frame.min(end)).max(span.start)..end {
+                self.write_frame(span, frame, &silence);
             }
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.samples.is_empty() {
+            return;
+        }
+
+        let samples = std::mem::take(&mut self.samples);
+        add_samples_to_buffer_map(&mut self.buffer_map.clone(), self.track_key, samples, &self.abort);
+    }
+}
+
+// Passes a source's frames on to the segment output, through a resampler when
+// the source is not at the engine's rate. Frames are written one after another
+// at the source's rate, from `source_start`.
+struct SourceFrames<'a> {
+    output: &'a mut SegmentOutput,
+    span: &'a SegmentSpan,
+    resampler: Option<Resampler>,
+    // Frames waiting for the resampler
+    pending: Vec<f32>,
+    // Engine frame of the next frame to come out of the resampler
+    frame: u64,
+    // Next engine frame the output takes
+    next_frame: u64,
+    source_start: u64,
+    source_end: Option<u64>,
+}
 
-            // Get the next packet from the format reader.
-            let packet = match format.next_packet() {
-                Ok(packet) => packet,
-                Err(err) => break Err(err),
+impl<'a> SourceFrames<'a> {
+    fn new(
+        output: &'a mut SegmentOutput,
+        span: &'a SegmentSpan,
+        start_frame: u64,
+        source_rate: u32,
+        sample_rate: u32,
+        quality: ResampleQuality,
+    ) -> Self {
+        if source_rate == sample_rate {
+            return Self {
+                output,
+                span,
+                resampler: None,
+                pending: Vec::new(),
+                frame: start_frame,
+                next_frame: start_frame,
+                source_start: start_frame,
+                source_end: span.end,
             };
+        }
+
+        // Start on a frame that falls on both rates' grids, so the resampled
+        // frames land exactly on the engine's timeline
+        let step = sample_rate as u64 / gcd(source_rate as u64, sample_rate as u64);
+        let first_frame = start_frame.saturating_sub(RESAMPLE_PADDING_FRAMES) / step * step;
+        let to_source = |frame: u64| frame * source_rate as u64 / sample_rate as u64;
+        let channels = output.channel_layout.count();
+
+        Self {
+            output,
+            span,
+            resampler: Some(Resampler::new(source_rate, sample_rate, channels, quality)),
+            pending: Vec::new(),
+            frame: first_frame,
+            next_frame: start_frame,
+            source_start: to_source(first_frame),
+            source_end: span.end.map(|end| to_source(end) + 1 + RESAMPLE_PADDING_FRAMES),
+        }
+    }
+
+    fn write(&mut self, samples: &[f32]) {
+        if self.resampler.is_some() {
+            self.pending.extend_from_slice(samples);
+            return;
+        }
+
+        self.output.write_frame(self.span, self.frame, samples);
+        self.frame += 1;
+        self.next_frame = self.frame;
+    }
+
+    fn flush(&mut self) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.push(&self.pending);
+            self.pending.clear();
+
+            let samples = resampler.take();
+            self.write_resampled(&samples);
+        }
+
+        self.output.flush();
+    }
+
+    // Resamples the frames held back. Returns the next engine frame the
+    // output takes.
+    fn finish(mut self) -> u64 {
+        if let Some(mut resampler) = self.resampler.take() {
+            resampler.push(&self.pending);
+            resampler.finish();
+
+            let samples = resampler.take();
+            self.write_resampled(&samples);
+            self.output.flush();
+        }
+
+        self.next_frame
+    }
+
+    // Writes resampled frames that fall within the segment, from where
+    // playback starts
+    fn write_resampled(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.output.channel_layout.count()) {
+            let position = self.frame;
+            self.frame += 1;
 
-            if packet.track_id() != track_id {
+            if position < self.next_frame || self.span.end.is_some_and(|end| position >= end) {
                 continue;
             }
 
-            // If playback is finished, break out of the loop.
-            if packet.ts() >= dur.unwrap_or(0) {
-                break Ok(true);
-            }
+            self.output.write_frame(self.span, position, frame);
+            self.next_frame = position + 1;
+        }
+    }
+}
 
-            match decoder.decode(&packet) {
-                Ok(decoded) => {
-                    let mut channel_samples = Vec::new();
+fn decode_segment(
+    source: &TrackSource,
+    gain: f32,
+    span: &SegmentSpan,
+    output: &mut SegmentOutput,
+    engine_sample_rate: u32,
+    resample_quality: ResampleQuality,
+    abort: &Arc<AtomicBool>,
+) -> Result<(), Error> {
+    let mut format = get_reader(&source.file_path);
+
+    // If not explicitly specified, use the first audio track.
+    let track_id = source.track_id.unwrap_or(0);
+    let mut decoder = get_track_decoder(format.as_ref(), track_id)?;
+
+    // Get the selected track using the track ID.
+    let track = format.tracks().iter().find(|track| track.id == track_id).expect("no track found");
+    let codec_params = track.codec_params.clone();
+
+    // Get the selected track's timebase and duration.
+    let dur = codec_params.n_frames.map(|frames| codec_params.start_ts + frames);
+    let source_rate = codec_params.sample_rate.unwrap_or(44_100);
+    let sample_rate = source_rate as f64;
+
+    // Start at the segment, or where playback starts if that is later. From
+    // here on frames are counted at the source's rate.
+    let start_frame = span.start.max(output.start_frame.min(span.end.unwrap_or(u64::MAX)));
+    let mut frames = SourceFrames::new(output, span, start_frame, source_rate, engine_sample_rate, resample_quality);
+    let mut next_frame = frames.source_start;
+    let end_frame = frames.source_end;
+    if next_frame > 0 {
+        let start_time = next_frame as f64 / sample_rate;
+        let time = Time::new(start_time.floor() as u64, start_time.fract());
+
+        // Seeking lands at or before the requested time, earlier frames are trimmed below
+        let seek_success = format.seek(SeekMode::Coarse, SeekTo::Time { time, track_id: Some(track_id) });
+        if seek_success.is_err() {
+            let next_frame = frames.finish();
+            output.fill_silence(span, next_frame);
+            return Ok(());
+        }
+    }
 
-                    for channel in 0..channels {
-                        // println!("channel: {}", channel);
-                        let samples = process_channel(decoded.clone(), channel);
-                        channel_samples.push(samples);
-                    }
+    // Frames in one tick of the time base, and where the last packet ended
+    let tick_frames = codec_params
+        .time_base
+        .map(|time_base| {
+            let tick = time_base.calc_time(1);
+            ((tick.seconds as f64 + tick.frac) * sample_rate).ceil() as u64
+        })
+        .unwrap_or(0);
+    let mut packet_end: Option<u64> = None;
+
+    // Remixes the source's channels to the track's, as they are decoded
+    let mut channel_matrix: Option<ChannelMatrix> = None;
+    let silence = vec![0.0; frames.output.channel_layout.count()];
+
+    let result: Result<(), Error> = loop {
+        if abort.load(std::sync::atomic::Ordering::Relaxed) {
+            break Ok(());
+        }
 
-                    // TODO: Handle audio channels properly
-                    let channel1 = channel_samples[0].clone();
-                    let channel2 = if channel_samples.len() > 1 {
-                        channel_samples[1].clone()
-                    } else {
-                        channel_samples[0].clone()
-                    };
+        if end_frame.is_some_and(|end| next_frame >= end) {
+            break Ok(());
+        }
 
-                    let stereo_samples: Vec<f32> = channel1
-                        .into_iter()
-                        .zip(channel2.into_iter())
-                        .flat_map(|(left, right)| vec![left, right])
-                        .collect();
+        // Get the next packet from the format reader.
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(err) => break Err(err),
+        };
 
+        if packet.track_id() != track_id {
+            continue;
+        }
 
-                    if stereo_samples.len() == 0 {
-                        continue;
-                    }
+        // If playback is finished, break out of the loop.
+        if packet.ts() >= dur.unwrap_or(0) {
+            break Ok(());
+        }
 
-                    add_samples_to_buffer_map(&mut buffer_map.clone(), track_key, stereo_samples);
+        // Position of the packet's first frame on the song's timeline. Containers
+        // with coarse timestamps (Matroska uses milliseconds) are only trusted to
+        // within one tick, so packets that follow on are kept contiguous.
+        let mut frame = match codec_params.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(packet.ts());
+                let frame = ((time.seconds as f64 + time.frac) * sample_rate).round() as u64;
+                match packet_end {
+                    Some(end) if frame.abs_diff(end) <= tick_frames => end,
+                    _ => frame,
                 }
-                Err(Error::DecodeError(err)) => {
-                    // Decode errors are not fatal. Print the error message and try to decode the next
-                    // packet as usual.
-                    warn!("decode error: {}", err);
-                }
-                Err(err) => break Err(err),
             }
+            None => next_frame,
         };
 
-        // If an error occurred, print the error message.
-        if let Err(err) = _result {
-            warn!("error: {}", err);
+        match decoder.decode(&packet) {
+            Ok(decoded) => {
+                let source_channels = decoded.spec().channels;
+                let channel_layout = frames.output.channel_layout;
+                if channel_matrix.as_ref().is_none_or(|matrix| matrix.from() != source_channels) {
+                    channel_matrix = Some(ChannelMatrix::new(source_channels, channel_layout));
+                }
+                let channel_matrix = channel_matrix.as_ref().unwrap();
+
+                let channel_samples: Vec<Vec<f32>> = (0..source_channels.count())
+                    .map(|channel| process_channel(decoded.clone(), channel))
+                    .collect();
+                let mut source_frame = vec![0.0; channel_samples.len()];
+                let mut mixed_frame = vec![0.0; channel_layout.count()];
+
+                for index in 0..decoded.frames() {
+                    if end_frame.is_some_and(|end| frame >= end) {
+                        break;
+                    }
+
+                    // Fill gaps with silence and drop frames from before the start
+                    while next_frame < frame && end_frame.is_none_or(|end| next_frame < end) {
+                        frames.write(&silence);
+                        next_frame += 1;
+                    }
+
+                    if frame == next_frame {
+                        for (sample, channel) in source_frame.iter_mut().zip(&channel_samples) {
+                            *sample = channel[index];
+                        }
+                        channel_matrix.mix_into(&source_frame, &mut mixed_frame);
+                        for sample in mixed_frame.iter_mut() {
+                            *sample *= gain;
+                        }
+                        frames.write(&mixed_frame);
+                        next_frame += 1;
+                    }
+
+                    frame += 1;
+                }
+
+                packet_end = Some(frame);
+                frames.flush();
+            }
+            Err(Error::DecodeError(err)) => {
+                // Decode errors are not fatal. Print the error message and try to decode the next
+                // packet as usual.
+                warn!("decode error: {}", err);
+            }
+            Err(err) => break Err(err),
         }
+    };
 
-        // Mark the track as finished
-        mark_track_as_finished(&mut finished_tracks.clone(), track_key);
-    });
+    // A take that ends early stays silent until the segment is over
+    let next_frame = frames.finish();
+    output.fill_silence(span, next_frame);
 
-    return playing;
+    match result {
+        Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+        result => result,
+    }
 }
 
-fn add_samples_to_buffer_map(buffer_map: &mut Arc<Mutex<HashMap<i32, Bounded<Vec<f32>>>>>, track_key: i32, samples: Vec<f32>) {    
+fn add_samples_to_buffer_map(
+    buffer_map: &mut BufferMap,
+    track_key: i32,
+    samples: Vec<f32>,
+    abort: &Arc<AtomicBool>,
+) {
     while buffer_remaining_space(buffer_map, track_key) < samples.len() {
+        // The buffer is removed once the track has been faded out
+        if abort.load(std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+
         thread::sleep(Duration::from_millis(100));
     }
 
 
     let mut hash_buffer = buffer_map.lock().unwrap();
 
+    let buffer = match hash_buffer.get_mut(&track_key) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+
     for sample in samples {
-        hash_buffer.get_mut(&track_key).unwrap().push(sample);
+        buffer.push(sample);
     }
 
     drop(hash_buffer);
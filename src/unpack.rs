@@ -0,0 +1,223 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use log::warn;
+use serde::Serialize;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::Decoder;
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatReader;
+
+use crate::audio_file::*;
+use crate::play_settings::*;
+use crate::tools::{get_track_decoder, try_get_reader};
+
+#[derive(Debug)]
+pub enum UnpackError {
+    Io(std::io::Error),
+    PlaySettings(PlaySettingsError),
+    Decode(Error),
+    UnsupportedTrack { track_id: u32, reason: String },
+}
+
+impl fmt::Display for UnpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnpackError::Io(err) => write!(f, "could not write stem: {}", err),
+            UnpackError::PlaySettings(err) => write!(f, "{}", err),
+            UnpackError::Decode(err) => write!(f, "could not decode file: {}", err),
+            UnpackError::UnsupportedTrack { track_id, reason } => {
+                write!(f, "track {} cannot be unpacked: {}", track_id, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UnpackError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UnpackError::Io(err) => Some(err),
+            UnpackError::PlaySettings(err) => Some(err),
+            UnpackError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for UnpackError {
+    fn from(err: std::io::Error) -> Self {
+        UnpackError::Io(err)
+    }
+}
+
+impl From<PlaySettingsError> for UnpackError {
+    fn from(err: PlaySettingsError) -> Self {
+        UnpackError::PlaySettings(err)
+    }
+}
+
+// One manifest entry. `key`, `file_path` and `track_id` mirror `Prot::enumerated_list`
+#[derive(Debug, Clone, Serialize)]
+pub struct Stem {
+    pub key: i32,
+    pub name: Option<String>,
+    pub take: usize,
+    pub track_id: u32,
+    pub file_path: String,
+}
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+struct StemDecoder {
+    decoder: Box<dyn Decoder>,
+    writers: Vec<AudioFileWriter>,
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+}
+
+// Writes every take in the .prot to its own file in `output_dir`, plus a manifest.json
+pub fn unpack(file_path: &str, output_dir: &str, format: AudioFileFormat) -> Result<Vec<Stem>, UnpackError> {
+    let play_settings = PlaySettings::from_file(file_path)?.migrate();
+    std::fs::create_dir_all(output_dir)?;
+
+    let mut reader = try_get_reader(file_path).map_err(UnpackError::Decode)?;
+
+    let mut stems: Vec<Stem> = Vec::new();
+    let mut group_names: HashSet<String> = HashSet::new();
+    for (key, track) in play_settings.tracks.iter().enumerate() {
+        let mut group_name = match &track.name {
+            Some(name) => sanitize_file_name(name),
+            None => format!("track_{}", key),
+        };
+        // Groups whose names are the same once sanitized are told apart by key
+        while !group_names.insert(group_name.clone()) {
+            group_name = format!("{}_{}", group_name, key);
+        }
+
+        for (take, track_id) in track.ids.iter().enumerate() {
+            let stem_name = format!("{}_{}.{}", group_name, take, format.extension());
+            let stem_path = Path::new(output_dir).join(stem_name);
+
+            stems.push(Stem {
+                key: key as i32,
+                name: track.name.clone(),
+                take,
+                track_id: *track_id,
+                file_path: stem_path.to_string_lossy().to_string(),
+            });
+        }
+    }
+
+    // ===== Open a decoder and the output files for every referenced track ===== //
+    let mut stem_decoders: HashMap<u32, StemDecoder> = HashMap::new();
+    for stem in &stems {
+        // A take used by more than one group is written once per group
+        let stem_decoder = match stem_decoders.entry(stem.track_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(open_stem_decoder(reader.as_ref(), stem)?),
+        };
+        stem_decoder.writers.push(AudioFileWriter::create(
+            &stem.file_path,
+            format,
+            stem_decoder.sample_rate,
+            stem_decoder.channels,
+            stem_decoder.bits_per_sample,
+        )?);
+    }
+
+    // ===== Decode every packet once and send it to each matching stem ===== //
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(UnpackError::Decode(err)),
+        };
+
+        let stem_decoder = match stem_decoders.get_mut(&packet.track_id()) {
+            Some(stem_decoder) => stem_decoder,
+            None => continue,
+        };
+
+        match stem_decoder.decoder.decode(&packet) {
+            Ok(decoded) => {
+                let mut sample_buffer = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
+                sample_buffer.copy_interleaved_ref(decoded);
+                let shift = 32 - stem_decoder.bits_per_sample;
+                let samples: Vec<i32> = sample_buffer.samples().iter().map(|sample| sample >> shift).collect();
+
+                for writer in stem_decoder.writers.iter_mut() {
+                    writer.write_samples(&samples)?;
+                }
+            }
+            Err(Error::DecodeError(err)) => {
+                // Decode errors are not fatal. Print the error message and try to decode the next
+                // packet as usual.
+                warn!("decode error: {}", err);
+            }
+            Err(err) => return Err(UnpackError::Decode(err)),
+        }
+    }
+
+    for (_, stem_decoder) in stem_decoders {
+        for writer in stem_decoder.writers {
+            writer.finalize()?;
+        }
+    }
+
+    let manifest_path = Path::new(output_dir).join(MANIFEST_FILE_NAME);
+    std::fs::write(manifest_path, serde_json::to_vec_pretty(&stems).unwrap())?;
+
+    Ok(stems)
+}
+
+fn open_stem_decoder(reader: &dyn FormatReader, stem: &Stem) -> Result<StemDecoder, UnpackError> {
+    let track = match reader.tracks().iter().find(|track| track.id == stem.track_id) {
+        Some(track) => track,
+        None => {
+            return Err(UnpackError::PlaySettings(PlaySettingsError::UnknownTrackId {
+                index: stem.key as usize,
+                id: stem.track_id,
+            }))
+        }
+    };
+
+    let unsupported = |reason: &str| UnpackError::UnsupportedTrack {
+        track_id: stem.track_id,
+        reason: reason.to_string(),
+    };
+
+    let decoder = get_track_decoder(reader, stem.track_id).map_err(|err| unsupported(&err.to_string()))?;
+
+    // The decoder knows more than the container, e.g. FLAC channels come from STREAMINFO
+    let codec_params = decoder.codec_params();
+    let sample_rate = codec_params
+        .sample_rate
+        .or(track.codec_params.sample_rate)
+        .ok_or_else(|| unsupported("unknown sample rate"))?;
+    let channels = match (codec_params.channels, track.codec_params.channel_layout) {
+        (Some(channels), _) => channels.count() as u32,
+        (None, Some(layout)) => layout.into_channels().count() as u32,
+        (None, None) => return Err(unsupported("unknown channel count")),
+    };
+    let bits_per_sample = match codec_params.bits_per_sample.or(track.codec_params.bits_per_sample) {
+        Some(bits) if bits <= 16 => 16,
+        _ => 24,
+    };
+
+    Ok(StemDecoder {
+        decoder,
+        writers: Vec::new(),
+        sample_rate,
+        channels,
+        bits_per_sample,
+    })
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,200 @@
+mod common;
+
+use std::collections::HashSet;
+
+use common::*;
+use proteus_audio::audio_file::AudioFileFormat;
+use proteus_audio::play_settings::{Rule, SectionSettings};
+use proteus_audio::prot::{CombinationError, Prot};
+use proteus_audio::prot_writer::ProtWriter;
+use proteus_audio::render::{render_batch, RenderError, RenderOptions};
+
+const SAMPLE_RATE: u32 = 44_100;
+
+// Writes a .prot file with a group of takes for each entry of `takes`, every
+// take a short ramp, and returns its path
+fn write_prot(name: &str, takes: &[usize], change: impl FnOnce(&mut ProtWriter)) -> String {
+    let dir = temp_dir(name);
+    let mut file_paths = Vec::new();
+    let mut file_index = 0;
+    for take_count in takes {
+        let mut group = Vec::new();
+        for _ in 0..*take_count {
+            let file_path = path_str(&dir, &format!("{}.wav", file_index));
+            write_wav(&file_path, SAMPLE_RATE, 1, &ramp(SAMPLE_RATE as usize / 2, 1));
+            group.push(file_path);
+            file_index += 1;
+        }
+        file_paths.push(group);
+    }
+
+    let prot_path = path_str(&dir, &format!("{}.prot", name));
+    let mut writer = ProtWriter::new(&file_paths);
+    change(&mut writer);
+    writer.write(&prot_path).unwrap();
+    prot_path
+}
+
+// Three groups, the second optional, with takes 1 to 6 and two sections.
+// Take 1 never plays with take 4, and take 2 only plays with take 5.
+fn write_ruled_prot(name: &str) -> String {
+    write_prot(name, &[3, 2, 1], |writer| {
+        writer.set_presence(vec![None, Some(0.5), None]);
+        writer.set_rules(vec![
+            Rule::Excludes { takes: vec![1, 4] },
+            Rule::Requires { take: 2, any_of: vec![5] },
+        ]);
+        writer.set_sections(vec![
+            SectionSettings { start: 0.0, name: None },
+            SectionSettings { start: 0.25, name: None },
+        ]);
+    })
+}
+
+#[test]
+fn a_seed_replays_its_combination() {
+    let prot_path = write_ruled_prot("seeds");
+
+    let mut combinations = HashSet::new();
+    for seed in 0..32 {
+        let mut first = Prot::try_new_with_seed(&prot_path, Some(seed)).unwrap();
+        let mut second = Prot::try_new_with_seed(&prot_path, Some(seed)).unwrap();
+        assert_eq!(first.get_seed(), Some(seed));
+        assert_eq!(first.get_combination(), second.get_combination());
+        combinations.insert(first.get_combination());
+
+        // The shuffles that follow the seed are the same too
+        first.refresh_tracks();
+        second.refresh_tracks();
+        assert_eq!(first.get_combination(), second.get_combination());
+    }
+    assert!(combinations.len() > 1, "every seed chose the same takes");
+
+    // A random seed can be read back and replayed
+    let random = Prot::try_new(&prot_path).unwrap();
+    let mut replayed = Prot::try_new_with_seed(&prot_path, Some(1)).unwrap();
+    replayed.set_seed(random.get_seed().unwrap());
+    assert_eq!(replayed.get_combination(), random.get_combination());
+}
+
+#[test]
+fn every_combination_id_selects_itself() {
+    let prot_path = write_ruled_prot("combination_ids");
+    let mut prot = Prot::try_new_with_seed(&prot_path, Some(0)).unwrap();
+
+    // Six choices per section obey the rules: take 1 with take 5 or none,
+    // take 2 with take 5, take 3 with any of the three
+    assert_eq!(prot.count_combinations(), 36);
+
+    let ids: Vec<String> = prot.combinations().collect();
+    assert_eq!(ids.len(), 36);
+    assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+
+    for id in &ids {
+        prot.set_combination(id).unwrap();
+        assert_eq!(&prot.get_combination(), id);
+        assert_eq!(prot.get_seed(), None);
+    }
+
+    // Seeded selections are among them
+    for seed in 0..16 {
+        prot.set_seed(seed);
+        assert!(ids.contains(&prot.get_combination()), "seed {} broke a rule", seed);
+    }
+}
+
+#[test]
+fn combination_ids_are_checked() {
+    let prot_path = write_ruled_prot("combination_checks");
+    let mut prot = Prot::try_new_with_seed(&prot_path, Some(0)).unwrap();
+    let id = prot.get_combination();
+    let (checksum, digits) = id.split_once('-').unwrap();
+    assert_eq!(checksum.len(), 4);
+
+    let other_path = write_prot("combination_other", &[2, 2], |_| {});
+    let other = Prot::try_new_with_seed(&other_path, Some(0)).unwrap();
+    assert!(matches!(prot.set_combination(&other.get_combination()), Err(CombinationError::WrongFile)));
+    assert!(matches!(prot.set_combination(digits), Err(CombinationError::Malformed(_))));
+
+    // Any one character mistyped
+    for index in 0..id.len() {
+        let mut mistyped = id.clone().into_bytes();
+        mistyped[index] = match mistyped[index] {
+            b'-' => continue,
+            b'0' => b'1',
+            _ => b'0',
+        };
+        let mistyped = String::from_utf8(mistyped).unwrap();
+        assert!(matches!(prot.set_combination(&mistyped), Err(CombinationError::WrongFile)), "{} was let through", mistyped);
+    }
+
+    // The same groups without the rules make ids that break them
+    let unruled_path = write_prot("combination_unruled", &[3, 2, 1], |writer| {
+        writer.set_presence(vec![None, Some(0.5), None]);
+        writer.set_sections(vec![
+            SectionSettings { start: 0.0, name: None },
+            SectionSettings { start: 0.25, name: None },
+        ]);
+    });
+    let unruled = Prot::try_new_with_seed(&unruled_path, Some(0)).unwrap();
+    let mut accepted = 0;
+    for unruled_id in unruled.combinations() {
+        match prot.set_combination(&unruled_id) {
+            Ok(()) => accepted += 1,
+            Err(err) => assert!(matches!(err, CombinationError::BreaksRules), "{}: {}", unruled_id, err),
+        }
+    }
+    assert_eq!(accepted, 36);
+
+    // The selection is untouched by the ids that were turned down
+    prot.set_combination(&id).unwrap();
+    assert!(prot.set_combination(&format!("{}-{}", checksum, &digits[1..])).is_err());
+    assert_eq!(prot.get_combination(), id);
+}
+
+#[test]
+fn takes_the_weights_never_choose_are_turned_down() {
+    let prot_path = write_ruled_prot("combination_weights");
+    let mut prot = Prot::try_new_with_seed(&prot_path, Some(0)).unwrap();
+    let third_take = prot.combinations().find(|id| id.split_once('-').unwrap().1.starts_with('2')).unwrap();
+    prot.set_combination(&third_take).unwrap();
+
+    prot.set_weights(vec![Some(vec![1.0, 1.0, 0.0]), None, None]).unwrap();
+    assert!(matches!(
+        prot.set_combination(&third_take),
+        Err(CombinationError::ExcludedTake { group: 0, take: 2 })
+    ));
+    assert!(prot.combinations().all(|id| prot.set_combination(&id).is_ok()));
+}
+
+#[test]
+fn a_batch_renders_every_combination_once() {
+    let prot_path = write_prot("batch", &[2, 2], |_| {});
+    let prot = Prot::try_new(&prot_path).unwrap();
+    let combinations: HashSet<String> = prot.combinations().collect();
+    let options = RenderOptions::new(AudioFileFormat::Wav);
+    let output_dir = path_str(&temp_dir("batch_renders"), "renders");
+
+    let renders = render_batch(&prot_path, 4, Some(5), &output_dir, &options).unwrap();
+    let rendered: HashSet<String> = renders.iter().map(|render| render.combination.clone()).collect();
+    assert_eq!(rendered, combinations);
+
+    // The same seed draws the same batch
+    let first: Vec<String> = render_batch(&prot_path, 2, Some(5), &output_dir, &options)
+        .unwrap()
+        .into_iter()
+        .map(|render| render.combination)
+        .collect();
+    let second: Vec<String> = render_batch(&prot_path, 2, Some(5), &output_dir, &options)
+        .unwrap()
+        .into_iter()
+        .map(|render| render.combination)
+        .collect();
+    assert_eq!(first, second);
+
+    let too_many_dir = path_str(&temp_dir("batch_too_many"), "renders");
+    assert!(matches!(
+        render_batch(&prot_path, 5, Some(5), &too_many_dir, &options),
+        Err(RenderError::TooFewCombinations { requested: 5, available: 4 })
+    ));
+}
@@ -0,0 +1,47 @@
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+// An empty directory for one test's files
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("proteus_audio_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn path_str(dir: &Path, file_name: &str) -> String {
+    dir.join(file_name).to_str().unwrap().to_string()
+}
+
+// Writes interleaved 16 bit samples to a WAV file
+pub fn write_wav(file_path: &str, sample_rate: u32, channels: u16, samples: &[i16]) {
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(file_path, spec).unwrap();
+    for sample in samples {
+        writer.write_sample(*sample).unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+// Reads a WAV file's spec and interleaved integer samples
+pub fn read_wav(file_path: &str) -> (hound::WavSpec, Vec<i32>) {
+    let mut reader = hound::WavReader::open(file_path).unwrap();
+    let samples = reader.samples::<i32>().map(|sample| sample.unwrap()).collect();
+    (reader.spec(), samples)
+}
+
+// A ramp that tells every frame of a take apart from its neighbours, negated
+// for a second take
+pub fn ramp(frames: usize, sign: i16) -> Vec<i16> {
+    (0..frames).map(|frame| sign * ramp_sample(frame)).collect()
+}
+
+pub fn ramp_sample(frame: usize) -> i16 {
+    1 + (frame % 20_000) as i16
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::*;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use proteus_audio::info::Info;
+
+fn write_silence(file_path: &str, sample_rate: u32, bits_per_sample: u16) {
+    let spec = WavSpec { channels: 2, sample_rate, bits_per_sample, sample_format: SampleFormat::Int };
+    let mut writer = WavWriter::create(file_path, spec).unwrap();
+    for _ in 0..sample_rate * 2 {
+        writer.write_sample(0).unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+#[test]
+fn files_of_different_rates_and_depths_play_together() {
+    let dir = temp_dir("mixed_formats");
+    let cd = path_str(&dir, "cd.wav");
+    let studio = path_str(&dir, "studio.wav");
+    write_silence(&cd, 44_100, 16);
+    write_silence(&studio, 48_000, 24);
+
+    let info = Info::new_from_file_paths(vec![cd, studio]);
+    assert_eq!(info.sample_rate, 48_000);
+    assert_eq!(info.bits_per_sample, 24);
+    assert_eq!(info.channels, 2);
+}
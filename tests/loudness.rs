@@ -0,0 +1,64 @@
+mod common;
+
+use std::f32::consts::PI;
+
+use common::*;
+use proteus_audio::audio_file::AudioFileFormat;
+use proteus_audio::loudness::measure_loudness;
+use proteus_audio::prot::Prot;
+use proteus_audio::prot_writer::ProtWriter;
+use proteus_audio::render::{render_prot, RenderOptions};
+
+const SAMPLE_RATE: u32 = 44_100;
+const LOUDNESS_WINDOW: f32 = 1.0;
+
+fn write_sine(file_path: &str, frequency: f32) {
+    let samples: Vec<i16> = (0..SAMPLE_RATE as usize * 2)
+        .map(|frame| ((2.0 * PI * frequency * frame as f32 / SAMPLE_RATE as f32).sin() * 3_000.0) as i16)
+        .collect();
+    write_wav(file_path, SAMPLE_RATE, 1, &samples);
+}
+
+#[test]
+fn every_combination_renders_within_the_loudness_window() {
+    let dir = temp_dir("combination_loudness");
+    let always = path_str(&dir, "always.wav");
+    let optional = path_str(&dir, "optional.wav");
+    write_sine(&always, 440.0);
+    write_sine(&optional, 1_000.0);
+
+    // Nothing is measured when packing, so rendering has to
+    let prot_path = path_str(&dir, "loudness.prot");
+    let mut writer = ProtWriter::new(&[vec![always], vec![optional]]);
+    writer.set_presence(vec![None, Some(0.5)]);
+    writer.set_loudness_window(Some(LOUDNESS_WINDOW));
+    writer.write(&prot_path).unwrap();
+
+    let render_loudness = |normalize_loudness: bool| -> Vec<f32> {
+        let prot = Prot::try_new_with_seed(&prot_path, Some(0)).unwrap();
+        let combinations: Vec<String> = prot.combinations().collect();
+        assert_eq!(combinations.len(), 2);
+
+        let mut options = RenderOptions::new(AudioFileFormat::Wav);
+        options.normalize_loudness = normalize_loudness;
+        options.reverb = false;
+        combinations
+            .iter()
+            .enumerate()
+            .map(|(index, combination)| {
+                let mut prot = Prot::try_new_with_seed(&prot_path, Some(0)).unwrap();
+                prot.set_combination(combination).unwrap();
+                let output = path_str(&dir, &format!("{}_{}.wav", normalize_loudness, index));
+                render_prot(prot, &output, &options).unwrap();
+                measure_loudness(&output, None).unwrap()
+            })
+            .collect()
+    };
+
+    // Leaving the second group out makes the mix about 3 LU quieter
+    let loudness = render_loudness(false);
+    assert!((loudness[0] - loudness[1]).abs() > 2.0 * LOUDNESS_WINDOW, "{:?}", loudness);
+
+    let loudness = render_loudness(true);
+    assert!((loudness[0] - loudness[1]).abs() <= LOUDNESS_WINDOW + 0.1, "{:?}", loudness);
+}
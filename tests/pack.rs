@@ -0,0 +1,63 @@
+mod common;
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use common::*;
+use proteus_audio::audio_file::AudioFileFormat;
+use proteus_audio::prot_writer::ProtWriter;
+use proteus_audio::unpack::{unpack, MANIFEST_FILE_NAME};
+
+const SAMPLE_RATE: u32 = 44_100;
+
+// Full scale noise from a fixed seed, so every bit of the samples is used
+fn noise(samples: usize, seed: u32) -> Vec<i16> {
+    let mut state = seed;
+    (0..samples)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 16) as u16 as i16
+        })
+        .collect()
+}
+
+#[test]
+fn unpacked_takes_match_the_packed_files() {
+    let dir = temp_dir("pack_round_trip");
+
+    // Stereo takes of odd lengths, so the last FLAC block is a short one
+    let mut takes = vec![
+        (path_str(&dir, "a.wav"), noise(2 * 100_003, 1)),
+        (path_str(&dir, "b.wav"), noise(2 * 70_001, 2)),
+        (path_str(&dir, "c.wav"), noise(2 * 50_000, 3)),
+    ];
+    takes[2].1.splice(0..0, [i16::MIN, i16::MAX, 0, -1, i16::MAX, i16::MIN]);
+    for (file_path, samples) in &takes {
+        write_wav(file_path, SAMPLE_RATE, 2, samples);
+    }
+
+    let prot_path = path_str(&dir, "takes.prot");
+    let mut writer = ProtWriter::new(&[vec![takes[0].0.clone(), takes[1].0.clone()], vec![takes[2].0.clone()]]);
+    // The same name once sanitized
+    writer.set_names(vec![String::from("Vox/1"), String::from("Vox:1")]);
+    writer.write(&prot_path).unwrap();
+
+    let output_dir = path_str(&dir, "stems");
+    let stems = unpack(&prot_path, &output_dir, AudioFileFormat::Wav).unwrap();
+    assert!(Path::new(&output_dir).join(MANIFEST_FILE_NAME).exists());
+
+    let stem_takes: Vec<(i32, usize)> = stems.iter().map(|stem| (stem.key, stem.take)).collect();
+    assert_eq!(stem_takes, vec![(0, 0), (0, 1), (1, 0)]);
+    let file_paths: HashSet<&String> = stems.iter().map(|stem| &stem.file_path).collect();
+    assert_eq!(file_paths.len(), stems.len(), "two stems share a file");
+
+    for (stem, (_, samples)) in stems.iter().zip(&takes) {
+        let (spec, unpacked) = read_wav(&stem.file_path);
+        assert_eq!(spec.sample_rate, SAMPLE_RATE);
+        assert_eq!(spec.channels, 2);
+        assert_eq!(spec.bits_per_sample, 16);
+
+        let samples: Vec<i32> = samples.iter().map(|sample| *sample as i32).collect();
+        assert!(unpacked == samples, "{} does not match the file packed", stem.file_path);
+    }
+}
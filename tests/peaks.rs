@@ -0,0 +1,44 @@
+mod common;
+
+use common::*;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use proteus_audio::peaks::get_peaks;
+
+const SAMPLE_RATE: u32 = 44_100;
+// Frames in each window of peaks
+const WINDOW: usize = SAMPLE_RATE as usize / 100;
+
+// A window at half scale, then a window at minus half scale
+fn write_half_scale(file_path: &str, bits_per_sample: u16, sample_format: SampleFormat) {
+    let spec = WavSpec { channels: 1, sample_rate: SAMPLE_RATE, bits_per_sample, sample_format };
+    let mut writer = WavWriter::create(file_path, spec).unwrap();
+    for sign in [1, -1] {
+        for _ in 0..WINDOW {
+            match sample_format {
+                SampleFormat::Float => writer.write_sample(sign as f32 * 0.5).unwrap(),
+                SampleFormat::Int => writer.write_sample(sign << (bits_per_sample - 2)).unwrap(),
+            }
+        }
+    }
+    writer.finalize().unwrap();
+}
+
+#[test]
+fn every_sample_format_is_read_at_the_same_scale() {
+    let dir = temp_dir("sample_formats");
+    let formats = [
+        (8, SampleFormat::Int),
+        (16, SampleFormat::Int),
+        (24, SampleFormat::Int),
+        (32, SampleFormat::Int),
+        (32, SampleFormat::Float),
+    ];
+
+    for (bits_per_sample, sample_format) in formats {
+        let file_path = path_str(&dir, &format!("{}_{:?}.wav", bits_per_sample, sample_format));
+        write_half_scale(&file_path, bits_per_sample, sample_format);
+
+        let peaks = get_peaks(&file_path, false);
+        assert_eq!(peaks, vec![vec![(0.5, 0.5), (-0.5, -0.5)]], "{} bit {:?}", bits_per_sample, sample_format);
+    }
+}
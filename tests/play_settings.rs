@@ -0,0 +1,175 @@
+mod common;
+
+use common::*;
+use proteus_audio::output::OutputBackend;
+use proteus_audio::play_settings::{PlaySettings, PlaySettingsError, PlaySettingsV1, Rule, SectionSettings, TrackSettingsV1};
+use proteus_audio::player::Player;
+use proteus_audio::prot::Prot;
+
+fn parse(json: &str) -> Result<PlaySettings, PlaySettingsError> {
+    PlaySettings::from_slice(json.as_bytes())
+}
+
+fn track(ids: &[u32]) -> TrackSettingsV1 {
+    TrackSettingsV1 {
+        ids: ids.to_vec(),
+        ..Default::default()
+    }
+}
+
+fn settings(tracks: Vec<TrackSettingsV1>) -> PlaySettingsV1 {
+    PlaySettingsV1 {
+        tracks,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn legacy_settings_migrate_to_ids() {
+    let play_settings = parse(r#"{"play_settings": {"tracks": [{"startingIndex": 0, "length": 2}, {"startingIndex": 2, "length": 1}]}}"#).unwrap();
+    assert_eq!(play_settings.encoder_version(), None);
+
+    let settings = play_settings.migrate();
+    let ids: Vec<Vec<u32>> = settings.tracks.iter().map(|track| track.ids.clone()).collect();
+    assert_eq!(ids, vec![vec![1, 2], vec![3]]);
+}
+
+#[test]
+fn encoder_version_may_be_a_number_or_a_string() {
+    let number = parse(r#"{"encoder_version": 1, "play_settings": {"tracks": [{"ids": [1]}]}}"#).unwrap();
+    assert_eq!(number.encoder_version(), Some(1.0));
+
+    let string = parse(r#"{"encoder_version": "1.5", "play_settings": {"tracks": [{"ids": [1]}]}}"#).unwrap();
+    assert_eq!(string.encoder_version(), Some(1.5));
+}
+
+#[test]
+fn malformed_settings_are_rejected() {
+    assert!(matches!(parse("{"), Err(PlaySettingsError::Json(_))));
+    assert!(matches!(parse(r#"{"tracks": []}"#), Err(PlaySettingsError::MissingField("play_settings"))));
+    assert!(matches!(
+        parse(r#"{"play_settings": {}}"#),
+        Err(PlaySettingsError::MissingField("play_settings.tracks"))
+    ));
+    assert!(matches!(
+        parse(r#"{"play_settings": {"tracks": {}}}"#),
+        Err(PlaySettingsError::InvalidField { field: "play_settings.tracks", .. })
+    ));
+    assert!(matches!(
+        parse(r#"{"encoder_version": "one", "play_settings": {"tracks": []}}"#),
+        Err(PlaySettingsError::InvalidField { field: "encoder_version", .. })
+    ));
+    assert!(matches!(
+        parse(r#"{"encoder_version": 1, "play_settings": {"tracks": [{"ids": [1]}, {"ids": "2"}]}}"#),
+        Err(PlaySettingsError::InvalidTrack { index: 1, .. })
+    ));
+    assert!(matches!(
+        parse(r#"{"encoder_version": 1, "play_settings": {"tracks": [{"ids": [1]}], "rules": [{"type": "excludes", "takes": [1, 2]}, {"type": "prefers"}]}}"#),
+        Err(PlaySettingsError::InvalidRule { index: 1, .. })
+    ));
+}
+
+#[test]
+fn valid_settings_pass() {
+    let mut settings = settings(vec![track(&[1, 2]), track(&[3])]);
+    settings.tracks[0].weights = Some(vec![1.0, 3.0]);
+    settings.tracks[1].presence = Some(0.5);
+    settings.sections = vec![SectionSettings { start: 0.0, name: None }, SectionSettings { start: 10.0, name: None }];
+    settings.crossfade = Some(0.1);
+    settings.rules = vec![Rule::Requires { take: 1, any_of: vec![3] }];
+
+    settings.validate(&[1, 2, 3]).unwrap();
+}
+
+#[test]
+fn tracks_are_validated() {
+    let settings_with = |change: &dyn Fn(&mut TrackSettingsV1)| {
+        let mut settings = settings(vec![track(&[1]), track(&[2, 3])]);
+        change(&mut settings.tracks[1]);
+        settings.validate(&[1, 2, 3])
+    };
+
+    assert!(matches!(
+        settings_with(&|track| track.ids.push(4)),
+        Err(PlaySettingsError::UnknownTrackId { index: 1, id: 4 })
+    ));
+    assert!(matches!(
+        settings_with(&|track| track.weights = Some(vec![1.0])),
+        Err(PlaySettingsError::InvalidWeights { index: 1, .. })
+    ));
+    assert!(matches!(
+        settings_with(&|track| track.weights = Some(vec![0.0, 0.0])),
+        Err(PlaySettingsError::InvalidWeights { index: 1, .. })
+    ));
+    assert!(matches!(
+        settings_with(&|track| track.presence = Some(1.5)),
+        Err(PlaySettingsError::InvalidTrack { index: 1, .. })
+    ));
+    assert!(matches!(
+        settings_with(&|track| track.pan = Some(-2.0)),
+        Err(PlaySettingsError::InvalidTrack { index: 1, .. })
+    ));
+    assert!(matches!(
+        settings_with(&|track| track.take_gains = Some(vec![0.0, 0.0, 0.0])),
+        Err(PlaySettingsError::InvalidTrack { index: 1, .. })
+    ));
+}
+
+#[test]
+fn sections_and_lengths_are_validated() {
+    let mut out_of_order = settings(vec![track(&[1])]);
+    out_of_order.sections = vec![SectionSettings { start: 5.0, name: None }, SectionSettings { start: 5.0, name: None }];
+    assert!(matches!(
+        out_of_order.validate(&[1]),
+        Err(PlaySettingsError::InvalidField { field: "play_settings.sections", .. })
+    ));
+
+    let mut negative_crossfade = settings(vec![track(&[1])]);
+    negative_crossfade.crossfade = Some(-1.0);
+    assert!(matches!(
+        negative_crossfade.validate(&[1]),
+        Err(PlaySettingsError::InvalidField { field: "play_settings.crossfade", .. })
+    ));
+}
+
+#[test]
+fn rules_are_validated() {
+    let with_rules = |rules: Vec<Rule>| {
+        let mut settings = settings(vec![track(&[1, 2]), track(&[3])]);
+        settings.rules = rules;
+        settings.validate(&[1, 2, 3])
+    };
+
+    assert!(matches!(
+        with_rules(vec![Rule::Excludes { takes: vec![1] }]),
+        Err(PlaySettingsError::InvalidRule { index: 0, .. })
+    ));
+    assert!(matches!(
+        with_rules(vec![Rule::Excludes { takes: vec![1, 3] }, Rule::Requires { take: 3, any_of: vec![4] }]),
+        Err(PlaySettingsError::InvalidRule { index: 1, .. })
+    ));
+
+    // Whichever take the first group picks, it cannot play with take 3,
+    // and the second group always plays take 3
+    assert!(matches!(
+        with_rules(vec![Rule::Excludes { takes: vec![1, 3] }, Rule::Excludes { takes: vec![2, 3] }]),
+        Err(PlaySettingsError::Unsatisfiable)
+    ));
+}
+
+#[test]
+fn files_that_cannot_be_read_are_errors() {
+    let dir = temp_dir("unreadable");
+    let missing = path_str(&dir, "missing.prot");
+    assert!(matches!(Prot::try_new(&missing), Err(PlaySettingsError::Io(_))));
+
+    let backend = OutputBackend::Null { speed: 1.0 };
+    assert!(matches!(Player::try_new_with_output(&missing, None, backend), Err(PlaySettingsError::Io(_))));
+
+    let not_matroska = path_str(&dir, "take.prot");
+    write_wav(&not_matroska, 44_100, 1, &ramp(1_000, 1));
+    assert!(matches!(
+        Prot::try_new(&not_matroska),
+        Err(PlaySettingsError::Container(_) | PlaySettingsError::MissingAttachment)
+    ));
+}
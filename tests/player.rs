@@ -0,0 +1,183 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use common::*;
+use proteus_audio::audio_file::AudioFileFormat;
+use proteus_audio::output::{Capture, OutputBackend};
+use proteus_audio::player::Player;
+use proteus_audio::player_engine::PlayerEngine;
+use proteus_audio::prot::Prot;
+use proteus_audio::prot_writer::ProtWriter;
+use proteus_audio::render::{render, RenderOptions};
+
+const SAMPLE_RATE: u32 = 44_100;
+const FRAMES: usize = SAMPLE_RATE as usize * 10;
+
+// A .prot file with one track group of two mono takes, the second the first
+// negated
+fn write_two_takes(name: &str) -> String {
+    let dir = temp_dir(name);
+    let take_a = path_str(&dir, "a.wav");
+    let take_b = path_str(&dir, "b.wav");
+    write_wav(&take_a, SAMPLE_RATE, 1, &ramp(FRAMES, 1));
+    write_wav(&take_b, SAMPLE_RATE, 1, &ramp(FRAMES, -1));
+
+    let prot_path = path_str(&dir, "takes.prot");
+    ProtWriter::new(&[vec![take_a, take_b]]).write(&prot_path).unwrap();
+    prot_path
+}
+
+// The take a frame comes from: 0 for the first, 1 for the second
+fn take_at(samples: &[f32], frame: usize) -> Option<usize> {
+    let expected = ramp_sample(frame) as f32 / 32768.0;
+    if (samples[frame] - expected).abs() < 1e-6 {
+        Some(0)
+    } else if (samples[frame] + expected).abs() < 1e-6 {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+// Every frame is in time and from one take or the other, but for the one
+// frame of the crossfade, and the takes switch once
+fn assert_switches_once(samples: &[f32]) {
+    assert_eq!(samples.len(), FRAMES);
+
+    let mut take = None;
+    let mut switches = 0;
+    let mut crossfaded = 0;
+    for frame in 0..samples.len() {
+        match take_at(samples, frame) {
+            Some(this_take) => {
+                if take.is_some_and(|take| take != this_take) {
+                    switches += 1;
+                }
+                take = Some(this_take);
+                crossfaded = 0;
+            }
+            None => {
+                crossfaded += 1;
+                assert!(crossfaded <= 1, "frame {} is {}, from neither take", frame, samples[frame]);
+            }
+        }
+    }
+
+    assert_eq!(switches, 1);
+}
+
+#[test]
+fn pulled_mix_carries_on_while_a_new_take_starts() {
+    let prot_path = write_two_takes("pull_reshuffle");
+    let prot = Arc::new(Mutex::new(Prot::try_new_with_seed(&prot_path, Some(1)).unwrap()));
+    let mut engine = PlayerEngine::new(prot.clone(), None, 0.0);
+    engine.set_limiter_ceiling(None);
+    engine.start();
+
+    let mut samples = Vec::new();
+    let mut buffer = vec![0.0; 512];
+    let mut reshuffled = false;
+    while !engine.is_finished() {
+        if !reshuffled && samples.len() >= FRAMES / 2 {
+            reshuffled = true;
+            // Let the take playing decode ahead, then switch to the other
+            thread::sleep(Duration::from_millis(100));
+            let take = take_at(&samples, 0).unwrap();
+            prot.lock().unwrap().choose_take(0, Some(1 - take)).unwrap();
+            engine.crossfade_group(0, 0.0);
+
+            assert_eq!(engine.fill_buffer(&mut buffer), buffer.len(), "the mix waited for the new take");
+            samples.extend_from_slice(&buffer);
+            continue;
+        }
+
+        // Pulled a few times faster than real time, as a host would, so
+        // the tracks keep up
+        let frames = engine.fill_buffer(&mut buffer);
+        thread::sleep(Duration::from_millis(3));
+        samples.extend_from_slice(&buffer[..frames]);
+    }
+
+    assert_switches_once(&samples);
+}
+
+#[test]
+fn reshuffled_playback_has_no_gap() {
+    let prot_path = write_two_takes("capture_reshuffle");
+    let capture = Capture::new();
+    let backend = OutputBackend::Capture { capture: capture.clone(), speed: 4.0 };
+    let mut player = Player::try_new_with_output(&prot_path, Some(1), backend).unwrap();
+    player.set_limiter_ceiling(None);
+    player.set_reshuffle_crossfade(Some(0.0));
+    player.play();
+
+    while player.get_time() < 1.0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let take = take_at(&capture.get_samples(), 0).unwrap();
+    player.choose_take(0, Some(1 - take)).unwrap();
+    player.sleep_until_end();
+
+    assert_switches_once(&capture.get_samples());
+}
+
+#[test]
+fn captured_playback_matches_a_render() {
+    let dir = temp_dir("capture_render");
+    let take_a = path_str(&dir, "a.wav");
+    let take_b = path_str(&dir, "b.wav");
+    let take_c = path_str(&dir, "c.wav");
+    write_wav(&take_a, SAMPLE_RATE, 1, &ramp(FRAMES, 1));
+    write_wav(&take_b, SAMPLE_RATE, 1, &ramp(FRAMES, -1));
+    // Shorter, and loud enough with the first group for the limiter to work
+    write_wav(&take_c, SAMPLE_RATE, 1, &ramp(FRAMES / 2, 1));
+
+    let prot_path = path_str(&dir, "groups.prot");
+    ProtWriter::new(&[vec![take_a, take_b], vec![take_c]]).write(&prot_path).unwrap();
+
+    let capture = Capture::new();
+    let backend = OutputBackend::Capture { capture: capture.clone(), speed: 8.0 };
+    let mut player = Player::try_new_with_output(&prot_path, Some(3), backend).unwrap();
+    player.play();
+    player.sleep_until_end();
+    assert!(player.is_finished());
+    assert!((player.get_time() - player.get_duration()).abs() < 0.1, "stopped at {}", player.get_time());
+
+    let output = path_str(&dir, "render.wav");
+    render(&prot_path, Some(3), &output, &RenderOptions::new(AudioFileFormat::Wav)).unwrap();
+    let (spec, rendered) = read_wav(&output);
+    assert_eq!(spec.bits_per_sample, 24);
+
+    let captured = capture.get_samples();
+    assert_eq!(captured.len(), rendered.len());
+    let scale = (1 << 23) as f32 - 1.0;
+    for (frame, (captured, rendered)) in captured.iter().zip(&rendered).enumerate() {
+        assert!((captured * scale - *rendered as f32).abs() <= 1.0, "frame {} differs from the render", frame);
+    }
+}
+
+#[test]
+fn playback_goes_on_without_an_audio_device() {
+    let dir = temp_dir("device");
+    let take = path_str(&dir, "take.wav");
+    write_wav(&take, SAMPLE_RATE, 1, &ramp(SAMPLE_RATE as usize / 4, 1));
+    let prot_path = path_str(&dir, "take.prot");
+    ProtWriter::new(&[vec![take]]).write(&prot_path).unwrap();
+
+    // Plays through the device where there is one, and to nothing where
+    // there is not, but finishes either way
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let mut player = Player::try_new_with_output(&prot_path, Some(0), OutputBackend::Device).unwrap();
+        player.set_volume(0.0);
+        player.play();
+        player.sleep_until_end();
+        sender.send(player.get_time()).unwrap();
+    });
+
+    let time = receiver.recv_timeout(Duration::from_secs(30)).expect("the player hung without a device");
+    assert!(time > 0.2, "stopped at {}", time);
+}
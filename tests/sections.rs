@@ -0,0 +1,68 @@
+mod common;
+
+use common::*;
+use proteus_audio::audio_file::AudioFileFormat;
+use proteus_audio::play_settings::SectionSettings;
+use proteus_audio::prot_writer::ProtWriter;
+use proteus_audio::render::{render, RenderOptions};
+
+const SAMPLE_RATE: u32 = 44_100;
+const FRAMES: usize = SAMPLE_RATE as usize * 4;
+
+fn section(start: f64) -> SectionSettings {
+    SectionSettings { start, name: None }
+}
+
+#[test]
+fn takes_switch_on_the_first_frame_of_each_section() {
+    let dir = temp_dir("sections");
+    let take_a = path_str(&dir, "a.wav");
+    let take_b = path_str(&dir, "b.wav");
+    write_wav(&take_a, SAMPLE_RATE, 1, &ramp(FRAMES, 1));
+    write_wav(&take_b, SAMPLE_RATE, 1, &ramp(FRAMES, -1));
+
+    // Neither section starts on a whole millisecond
+    let starts = [0.0, 1.2345, 2.50001];
+    let start_frames: Vec<usize> = starts.iter().map(|start| (start * SAMPLE_RATE as f64).round() as usize).collect();
+
+    let prot_path = path_str(&dir, "sections.prot");
+    let mut writer = ProtWriter::new(&[vec![take_a, take_b]]);
+    writer.set_sections(starts.iter().map(|start| section(*start)).collect());
+    writer.set_crossfade(Some(0.0));
+    writer.write(&prot_path).unwrap();
+
+    let mut options = RenderOptions::new(AudioFileFormat::Wav);
+    options.limiter_ceiling = None;
+
+    let mut switches = 0;
+    for seed in 0..8 {
+        let output = path_str(&dir, &format!("render_{}.wav", seed));
+        render(&prot_path, Some(seed), &output, &options).unwrap();
+
+        let (spec, samples) = read_wav(&output);
+        assert_eq!(spec.channels, 1);
+        assert_eq!(samples.len(), FRAMES, "seed {}", seed);
+
+        let expected = ramp(FRAMES, 1);
+        let mut take = None;
+        for (frame, sample) in samples.iter().enumerate() {
+            // Renders are 24 bit
+            let sample = (*sample as f64 / 256.0).round() as i16;
+            let this_take = if sample == expected[frame] {
+                1
+            } else if sample == -expected[frame] {
+                -1
+            } else {
+                panic!("seed {}: frame {} is {}, from neither take", seed, frame, sample);
+            };
+
+            if take.is_some_and(|take| take != this_take) {
+                assert!(start_frames.contains(&frame), "seed {}: takes switch at frame {}", seed, frame);
+                switches += 1;
+            }
+            take = Some(this_take);
+        }
+    }
+
+    assert!(switches > 0, "no seed switched takes between sections");
+}